@@ -1,15 +1,29 @@
 function load() {
     "use strict";
     var moves = [];
+    var clocks = null;
+    var clockStarted = 0;
     var history = $("#history");
     var formserver = $("#server");
     var formtext = $("#text");
     var connectbtn = $("#connectbtn");
     var disconnectbtn = $("#disconnectbtn");
     var sendbtn = $("#sendbtn");
+    var formgame = $("#gameid");
+    var formnick = $("#nickname");
+    var formuser = $("#username");
+    var formpassword = $("#password");
+    var seatbtns = $("#whitebtn, #blackbtn, #leavebtn, #newgamebtn, #joinbtn, #nickbtn, " +
+                     "#leaderboardbtn, #seekbtn, #unseekbtn, #berserkbtn, #seal, " +
+                     "#unsealbtn, #spectatorsbtn");
     connectbtn.prop("disabled", false);
     disconnectbtn.prop("disabled", true);
     sendbtn.prop("disabled", true);
+    seatbtns.prop("disabled", true);
+    // The game is served from the same origin as the page, over TLS if the page is.
+    var scheme = location.protocol == "https:" ? "wss://" : "ws://";
+    $("#scheme").text(scheme);
+    formserver.val(location.host + "/ws");
 
     function err(value) {
         var elem = $("<div>");
@@ -20,6 +34,71 @@ function load() {
         history.scrollTop(history.prop("scrollHeight"));
     }
 
+    // The session token of the account we are logged in to, if any.
+    var session = localStorage.getItem("session");
+
+    // Tokens go in a header rather than the URL, to stay out of logs and history.
+    function api(method, path, body, token) {
+        return $.ajax({
+            url: "/api/" + path,
+            method: method,
+            contentType: "application/json",
+            headers: token ? { Authorization: "Bearer " + token } : {},
+            data: body ? JSON.stringify(body) : undefined
+        });
+    }
+
+    function failed(xhr) {
+        err(xhr.responseJSON ? xhr.responseJSON.error : "request failed.");
+    }
+
+    function setUser(username) {
+        if (username) {
+            $("#user").text("Logged in as " + username + ".");
+            formnick.prop("disabled", true);
+        } else {
+            session = null;
+            localStorage.removeItem("session");
+            $("#user").text("Playing as a guest.");
+            formnick.prop("disabled", false);
+        }
+    }
+
+    if (session) {
+        api("GET", "sessions", null, session).done(function(user) {
+            setUser(user.username);
+        }).fail(function() {
+            setUser(null);
+        });
+    } else {
+        setUser(null);
+    }
+
+    function login() {
+        var credentials = { username: formuser.val(), password: formpassword.val() };
+        formpassword.val("");
+        api("POST", "sessions", credentials).done(function(user) {
+            session = user.token;
+            localStorage.setItem("session", session);
+            setUser(user.username);
+            err("Logged in. Connect again to play as " + user.username + ".");
+        }).fail(failed);
+    }
+
+    $("#account").submit(login);
+
+    $("#registerbtn").click(function() {
+        var credentials = { username: formuser.val(), password: formpassword.val() };
+        api("POST", "accounts", credentials).done(login).fail(failed);
+    });
+
+    $("#logoutbtn").click(function() {
+        if (!session) { return; }
+        api("DELETE", "sessions", null, session);
+        setUser(null);
+        err("Logged out. Connect again to play as a guest.");
+    });
+
     function write(json) {
         try {
             var msg = JSON.parse(json);
@@ -51,16 +130,58 @@ function load() {
             } else if (msg.variant == "Board") {
                 var state = msg.fields[0];
                 setPosition(chessBoard, state.board);
-                if (msg.fields[2]) {
-                    $("#check").text("Checkmate!");
-                    chessBoard.clear();
+                setCheck(msg.fields[1], msg.fields[2]);
+                if (msg.fields[3]) {
+                    addHistory(msg.fields[3]);
                 }
-                else if (msg.fields[1]) {
-                    $("#check").text("Check!");
+                setClocks(msg.fields[4]);
+            } else if (msg.variant == "State") {
+                var state = msg.fields[0];
+                setPosition(chessBoard, state.board);
+                setSeat(msg.fields[2]);
+                $("#moves").empty();
+                for (var i = 0; i < msg.fields[3].length; i++) {
+                    addHistory(msg.fields[3][i]);
                 }
-                else {
-                    $("#check").text("");
+                setClocks(msg.fields[4]);
+                setCheck(msg.fields[5], msg.fields[6]);
+                $("#game").text("Game #" + msg.fields[7]);
+                setOutcome(msg.fields[8]);
+                return;
+            } else if (msg.variant == "Seat") {
+                setSeat(msg.fields[1]);
+                if (msg.fields[2]) {
+                    // Remember our seat so that we can get it back after reconnecting.
+                    localStorage.setItem("token", msg.fields[2]);
                 }
+                return;
+            } else if (msg.variant == "Nick") {
+                formnick.val(msg.fields[1]);
+                return;
+            } else if (msg.variant == "Presence") {
+                setPresence(msg.fields[0], msg.fields[1], msg.fields[2]);
+                return;
+            } else if (msg.variant == "Standings") {
+                setStandings(msg.fields[0], msg.fields[1]);
+                return;
+            } else if (msg.variant == "Arena") {
+                setArena(msg.fields[1], msg.fields[2], msg.fields[3]);
+                return;
+            } else if (msg.variant == "Seeks") {
+                setSeeks(msg.fields[0]);
+                return;
+            } else if (msg.variant == "Spectators") {
+                var spectating = msg.fields[1];
+                $("#spectatorchat").prop("checked", spectating.chat);
+                $("#delaymoves").val(spectating.delay_moves);
+                $("#delaysecs").val(spectating.delay_secs);
+                return;
+            } else if (msg.variant == "Adjourned") {
+                stopClocks();
+                elem.text("Game adjourned: " + msg.fields[1] + " has sealed a move.");
+            } else if (msg.variant == "Over") {
+                setOutcome(msg.fields[0]);
+                elem.append($("<strong>").text($("#result").text()));
             } else if (msg.variant == "Select") {
             } else if (msg.variant == "Move") {
                 removeGreySquares();
@@ -76,6 +197,128 @@ function load() {
         }
     }
 
+    function setCheck(check, checkmate) {
+        if (checkmate) {
+            $("#check").text("Checkmate!");
+            chessBoard.clear();
+        }
+        else if (check) {
+            $("#check").text("Check!");
+        }
+        else {
+            $("#check").text("");
+        }
+    }
+
+    function setOutcome(outcome) {
+        if (!outcome) {
+            $("#result").text("");
+        } else if (outcome.winner) {
+            $("#result").text(outcome.winner + " wins by " + outcome.reason + ".");
+        } else {
+            $("#result").text("Game over: " + outcome.reason + ".");
+        }
+    }
+
+    function setPresence(white, black, spectators) {
+        $("#presence").text("White: " + (white || "-") + ", Black: " + (black || "-") +
+                            ", Spectators: " + (spectators.join(", ") || "-"));
+    }
+
+    function setStandings(category, standings) {
+        var list = $("#leaderboard");
+        list.empty();
+        for (var i = 0; i < standings.length; i++) {
+            var standing = standings[i];
+            var elem = $("<li>");
+            elem.text(standing.user + " " + standing.rating + (standing.provisional ? "?" : "") +
+                      " (" + standing.games + " games)");
+            list.append(elem);
+        }
+        if (standings.length == 0) {
+            list.append($("<li>").text("No rated " + category + " games yet."));
+        }
+    }
+
+    function setArena(name, remainingMs, standings) {
+        var minutes = Math.ceil(remainingMs / 60000);
+        $("#arenaname").text(name + (minutes > 0 ? " (" + minutes + " min left)" : " (over)"));
+        var list = $("#arena");
+        list.empty();
+        for (var i = 0; i < standings.length; i++) {
+            var standing = standings[i];
+            list.append($("<li>").text(standing.user + " " + standing.score +
+                                       (standing.fire ? " (on fire)" : "") +
+                                       (standing.paused ? " (paused)" : "")));
+        }
+    }
+
+    function setSeeks(seeks) {
+        var list = $("#seeks");
+        list.empty();
+        for (var i = 0; i < seeks.length; i++) {
+            var seek = seeks[i];
+            var terms = seek.terms;
+            var text = seek.nick + " (" + seek.rating + ") " +
+                Math.floor(terms.initial_ms / 60000) + "+" + Math.floor(terms.increment_ms / 1000) +
+                (terms.rated ? " rated" : " casual");
+            if (terms.color) {
+                text += ", plays " + terms.color;
+            }
+            if (terms.min_rating !== null || terms.max_rating !== null) {
+                text += ", wants " + (terms.min_rating === null ? "any" : terms.min_rating) +
+                    "-" + (terms.max_rating === null ? "any" : terms.max_rating);
+            }
+            list.append($("<li>").text(text));
+        }
+    }
+
+    function setSeat(seat) {
+        $("#seat").text("You are " + seat + ".");
+        chessBoard.orientation(seat == "Black" ? "black" : "white");
+    }
+
+    function addHistory(move) {
+        var elem = $("<li>");
+        elem.text(indexToNotation(move.from.row, move.from.col) + "-" +
+                  indexToNotation(move.to.row, move.to.col));
+        $("#moves").append(elem);
+    }
+
+    function setClocks(state) {
+        clocks = state;
+        clockStarted = Date.now();
+        renderClocks();
+    }
+
+    function stopClocks() {
+        if (!clocks) { return; }
+        var elapsed = Date.now() - clockStarted;
+        var white = clocks.white_ms;
+        var black = clocks.black_ms;
+        if (clocks.running == "White") {
+            white = Math.max(0, white - elapsed);
+        } else if (clocks.running == "Black") {
+            black = Math.max(0, black - elapsed);
+        }
+        setClocks({ white_ms: white, black_ms: black, running: null });
+    }
+
+    function renderClocks() {
+        if (!clocks) { return; }
+        var elapsed = Date.now() - clockStarted;
+        var white = clocks.white_ms;
+        var black = clocks.black_ms;
+        if (clocks.running == "White") {
+            white = Math.max(0, white - elapsed);
+        } else if (clocks.running == "Black") {
+            black = Math.max(0, black - elapsed);
+        }
+        $("#whiteclock").text("White " + formatClock(white));
+        $("#blackclock").text("Black " + formatClock(black));
+    }
+    setInterval(renderClocks, 200);
+
     var socket;
     var sockprom;
     $("#connect").submit(function() {
@@ -84,16 +327,30 @@ function load() {
             socket = undefined;
         }
 
-        socket = new WebSocket("ws://" + formserver.val());
+        var url = scheme + formserver.val();
+        // The session token is offered as a subprotocol, so that it is never part
+        // of a URL.
+        if (session) {
+            socket = new WebSocket(url, ["chess.v1", "session." + session]);
+        } else {
+            socket = new WebSocket(url);
+        }
         sockprom = new Promise(function(resolve, reject) {
             socket.onopen = resolve;
             socket.onerror = reject;
             $("#board").show();
         }).catch(function(e) {
-            err("failed to connect.");
+            if (session) {
+                // The server turns away sessions that are no longer open.
+                setUser(null);
+                err("failed to connect. Please log in again.");
+            } else {
+                err("failed to connect.");
+            }
             socket.close();
             socket = undefined;
             sendbtn.prop("disabled", true);
+            seatbtns.prop("disabled", true);
             connectbtn.prop("disabled", false);
             disconnectbtn.prop("disabled", true);
         });
@@ -107,10 +364,20 @@ function load() {
             if (!socket) { return; }
 
             sendbtn.prop("disabled", false);
+            seatbtns.prop("disabled", false);
             connectbtn.prop("disabled", true);
             disconnectbtn.prop("disabled", false);
             err("Connected.");
         });
+
+        var nick = localStorage.getItem("nick");
+        if (nick && !session) {
+            sendAction("Nick", [nick]);
+        }
+        var token = localStorage.getItem("token");
+        if (token) {
+            sendAction("Resume", [token]);
+        }
     });
 
     disconnectbtn.click(function() {
@@ -119,11 +386,76 @@ function load() {
         socket.close();
         socket = undefined;
         sendbtn.prop("disabled", true);
+        seatbtns.prop("disabled", true);
         connectbtn.prop("disabled", false);
         disconnectbtn.prop("disabled", true);
         err("Disconnected.");
     });
 
+    function sendAction(variant, fields) {
+        if (!socket) { return; }
+        sockprom = sockprom.then(function() {
+            var o = { variant: variant, fields: fields };
+            var s = JSON.stringify(o);
+            socket.send(s);
+            console.log("send: " + s);
+        });
+    }
+
+    $("#whitebtn").click(function() {
+        sendAction("TakeSeat", ["White"]);
+    });
+
+    $("#blackbtn").click(function() {
+        sendAction("TakeSeat", ["Black"]);
+    });
+
+    $("#leavebtn").click(function() {
+        sendAction("LeaveSeat", []);
+    });
+
+    $("#newgamebtn").click(function() {
+        sendAction("NewGame", []);
+    });
+
+    $("#joinbtn").click(function() {
+        sendAction("Join", [formgame.val()]);
+    });
+
+    $("#ratings").submit(function() {
+        sendAction("Leaderboard", [$("#category").val()]);
+    });
+
+    $("#seek").submit(function() {
+        sendAction("Seek", [$("#timecontrol").val(), String($("#rated").prop("checked")),
+                            $("#seekcolor").val(), $("#minrating").val(),
+                            $("#maxrating").val()]);
+    });
+
+    $("#berserkbtn").click(function() {
+        sendAction("Berserk", []);
+    });
+
+    $("#spectators").submit(function() {
+        // Only the creator of the game may change these.
+        sendAction("Spectators", [String($("#spectatorchat").prop("checked")),
+                                  $("#delaymoves").val(), $("#delaysecs").val()]);
+    });
+
+    $("#unsealbtn").click(function() {
+        sendAction("Unseal", []);
+    });
+
+    $("#unseekbtn").click(function() {
+        sendAction("Unseek", []);
+    });
+
+    $("#nick").submit(function() {
+        // Remember the nickname we chose, so that we get it back after reconnecting.
+        localStorage.setItem("nick", formnick.val());
+        sendAction("Nick", [formnick.val()]);
+    });
+
     $("#send").submit(function() {
         if (!socket) { return; }
         var txt = formtext.val();
@@ -153,12 +485,19 @@ function load() {
         if (!valid(toRow, toCol)) {
             return 'snapback';
         }
+        // A sealed move stays hidden until the game is resumed.
+        var sealing = $("#seal").prop("checked");
+        $("#seal").prop("checked", false);
         sockprom = sockprom.then(function() {
-            var o = { variant: "Move", fields: ["" + fromRow, "" + fromCol, "" + toRow, "" + toCol] };
+            var o = { variant: sealing ? "Seal" : "Move",
+                      fields: ["" + fromRow, "" + fromCol, "" + toRow, "" + toCol] };
             var s = JSON.stringify(o);
             socket.send(s);
             console.log("send: " + s);
         })
+        if (sealing) {
+            return 'snapback';
+        }
     };
 
     var valid = function(row, col) {
@@ -192,6 +531,18 @@ var indexToNotation = function(row, col) {
     return letter + number; 
 };
 
+var formatClock = function(ms) {
+    var seconds = Math.ceil(ms / 1000);
+    var minutes = Math.floor(seconds / 60);
+    seconds = seconds % 60;
+    if (minutes >= 24 * 60) {
+        // Correspondence games have days per move, so only show days and hours.
+        var hours = Math.floor(minutes / 60);
+        return Math.floor(hours / 24) + "d " + (hours % 24) + "h";
+    }
+    return minutes + ":" + (seconds < 10 ? "0" : "") + seconds;
+};
+
 var removeGreySquares = function() {
   $('#board .square-55d63').css('background', '');
 };
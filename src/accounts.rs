@@ -0,0 +1,214 @@
+//! A module for user accounts, so that players keep one identity across
+//! connections. Accounts are stored in `accounts.json` in the store directory, with
+//! each password salted and hashed with PBKDF2, which is deliberately slow so that
+//! a stolen file is expensive to guess passwords from. Logging in issues a session
+//! token, which WebSocket clients give when connecting, by offering the
+//! `session.<token>` subprotocol, to play as their account instead of as a guest.
+//! Sessions are only kept in memory, so everyone has to log in again after the
+//! server restarts.
+
+use openssl::crypto::pkcs5;
+use openssl::crypto::rand;
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::storage;
+
+/// The name of the file accounts are stored in, in the store directory.
+const FILE_NAME: &'static str = "accounts.json";
+/// How many rounds of PBKDF2 new passwords are hashed with.
+const ITERATIONS: usize = 100_000;
+/// How many random bytes each password is salted with.
+const SALT_LEN: usize = 16;
+/// How many bytes of hash are stored for each password.
+const HASH_LEN: usize = 32;
+/// How many random bytes make up a session token.
+const TOKEN_LEN: usize = 32;
+/// The shortest password that is accepted.
+const PASSWORD_LEN: usize = 8;
+/// The longest name that is accepted, for usernames and nicknames alike.
+const NAME_LEN: usize = 20;
+/// How long a session lasts after logging in.
+const SESSION_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a stored account. The salt and hash are hex encoded, and the
+/// number of iterations is stored so that it can be raised for new passwords.
+struct Account {
+    username: String,
+    salt: String,
+    hash: String,
+    iterations: usize,
+}
+
+/// Represents a logged in session of an account.
+struct Session {
+    username: String,
+    expires: Instant,
+}
+
+/// Every account, keyed by lowercase username, along with the open sessions.
+/// Both are behind their own lock, and passwords are hashed without holding
+/// either, so a slow login never holds up the rest of the server.
+pub struct Accounts {
+    path: PathBuf,
+    accounts: Mutex<BTreeMap<String, Account>>,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+/// Check that a name can be shown to other players: it must be short, and made of
+/// letters, digits, `-` and `_`. Used for both usernames and nicknames.
+pub fn check_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.chars().count() > NAME_LEN {
+        return Err("names must be 1 to 20 characters long")
+    }
+    if !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        return Err("names may only contain letters, digits, - and _")
+    }
+    Ok(())
+}
+
+/// Check if an account is one of the given admins, ignoring case.
+pub fn is_admin(admins: &[String], user: &str) -> bool {
+    let lower = user.to_lowercase();
+    admins.iter().any(|admin| admin.to_lowercase() == lower)
+}
+
+/// Helper function to hash a password with the given salt.
+fn hash(password: &str, salt: &[u8], iterations: usize) -> Vec<u8> {
+    pkcs5::pbkdf2_hmac_sha1(password, salt, iterations, HASH_LEN)
+}
+
+/// Helper function to compare two hashes in constant time, so that the time taken
+/// does not tell how much of a guess was right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl Accounts {
+    /// Open the accounts stored in the given directory, creating the directory if
+    /// it does not exist. Exits if the file cannot be read, rather than start over
+    /// without the accounts.
+    pub fn open(dir: &str) -> Accounts {
+        fs::create_dir_all(dir).unwrap();
+        let path = PathBuf::from(dir).join(FILE_NAME);
+        let mut contents = String::new();
+        let accounts = match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => match json::decode(&contents) {
+                Ok(accounts) => accounts,
+                Err(e) => {
+                    error!("could not read accounts from {}: {}", path.display(), e);
+                    process::exit(1);
+                },
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        info!("loaded {} accounts from {}", accounts.len(), path.display());
+        Accounts {
+            path: path,
+            accounts: Mutex::new(accounts),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check if an account uses the given name, ignoring case.
+    pub fn exists(&self, name: &str) -> bool {
+        self.accounts.lock().unwrap().contains_key(&name.to_lowercase())
+    }
+
+    /// Get the username of the account with the given name, ignoring case, as it
+    /// was registered.
+    pub fn username(&self, name: &str) -> Option<String> {
+        self.accounts.lock().unwrap().get(&name.to_lowercase())
+            .map(|account| account.username.clone())
+    }
+
+    /// Create an account with the given username and password.
+    pub fn register(&self, username: &str, password: &str) -> Result<(), &'static str> {
+        try!(check_name(username));
+        if username.to_lowercase().starts_with("guest") {
+            return Err("usernames starting with \"guest\" are reserved")
+        }
+        if password.chars().count() < PASSWORD_LEN {
+            return Err("passwords must be at least 8 characters long")
+        }
+        if self.exists(username) {
+            return Err("that username is taken")
+        }
+        let salt = rand::rand_bytes(SALT_LEN);
+        let account = Account {
+            username: String::from(username),
+            salt: salt.to_hex(),
+            hash: hash(password, &salt, ITERATIONS).to_hex(),
+            iterations: ITERATIONS,
+        };
+        let mut accounts = self.accounts.lock().unwrap();
+        // Someone else may have taken the name while the password was hashed.
+        if accounts.contains_key(&username.to_lowercase()) {
+            return Err("that username is taken")
+        }
+        accounts.insert(username.to_lowercase(), account);
+        if let Err(e) = self.save(&accounts) {
+            error!("could not save accounts to {}: {}", self.path.display(), e);
+            accounts.remove(&username.to_lowercase());
+            return Err("could not save the account")
+        }
+        info!("registered {}", username);
+        Ok(())
+    }
+
+    /// Log in to an account. Returns the username as registered and a new session
+    /// token.
+    pub fn login(&self, username: &str, password: &str)
+                 -> Result<(String, String), &'static str> {
+        let account = self.accounts.lock().unwrap().get(&username.to_lowercase()).cloned();
+        let account = match account {
+            Some(account) => account,
+            None => {
+                // Take as long as a wrong password, so that usernames cannot be
+                // found by timing.
+                hash(password, &rand::rand_bytes(SALT_LEN), ITERATIONS);
+                return Err("wrong username or password")
+            },
+        };
+        let salt = account.salt.from_hex().unwrap_or(Vec::new());
+        let stored = account.hash.from_hex().unwrap_or(Vec::new());
+        if !same(&hash(password, &salt, account.iterations), &stored) {
+            return Err("wrong username or password")
+        }
+        let token = rand::rand_bytes(TOKEN_LEN).to_hex();
+        let session = Session {
+            username: account.username.clone(),
+            expires: Instant::now() + Duration::from_secs(SESSION_SECS),
+        };
+        self.sessions.lock().unwrap().insert(token.clone(), session);
+        Ok((account.username, token))
+    }
+
+    /// End a session. Returns whether it was open.
+    pub fn logout(&self, token: &str) -> bool {
+        self.sessions.lock().unwrap().remove(token).is_some()
+    }
+
+    /// Get the username of the account a session token belongs to, if the session
+    /// is still open.
+    pub fn user(&self, token: &str) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.get(token).map(|session| session.username.clone())
+    }
+
+    /// Helper function to write every account to disk.
+    fn save(&self, accounts: &BTreeMap<String, Account>) -> io::Result<()> {
+        storage::replace(&self.path, &json::encode(accounts).unwrap())
+    }
+}
@@ -0,0 +1,1145 @@
+//! A module for the JSON HTTP API, mounted on the Iron server next to the html page.
+//! Lets scripts and bots list games, read a game's FEN, PGN and moves, find the
+//! legal moves of a position, and submit moves without opening a WebSocket.
+//! Games are listed and read as spectators see them, so they lag behind while
+//! board updates are held back from spectators.
+//!
+//! * `GET /api/games` lists every game.
+//! * `GET /api/games/<id>` gets a game's FEN, PGN and move list.
+//! * `GET /api/games/<id>/legal` gets the legal moves in a game's position.
+//! * `GET /api/legal?fen=<fen>` gets the legal moves in any position.
+//! * `POST /api/games/<id>/move` with `{"token": ..., "from": "e2", "to": "e4"}`
+//! plays a move for the player whose seat token is given.
+//! * `POST /api/games/<id>/conditional` with `{"token": ..., "lines": [["e7e5",
+//! "g1f3", "b8c6", "f1c4"], ["d7d5", "e4d5"]]}` replaces the conditional moves of
+//! the player whose seat token is given in a correspondence game. Each line starts
+//! with a move of the opponent and alternates with the player's replies.
+//! * `GET /api/games/<id>/conditional` gets the conditional moves of the player
+//! whose seat token is given as `Authorization: Bearer <token>`.
+//! * `POST /api/accounts` with `{"username": ..., "password": ...}` registers an
+//! account.
+//! * `POST /api/sessions` with `{"username": ..., "password": ...}` logs in, and
+//! returns a session token, which WebSocket clients connect with by offering the
+//! `session.<token>` subprotocol.
+//! * `GET /api/sessions` gets the account of the session whose token is given as
+//! `Authorization: Bearer <token>`.
+//! * `DELETE /api/sessions` logs the session whose token is given out.
+//! * `GET /api/leaderboard/<category>` lists the best rated players in a category
+//! of time control: bullet, blitz, rapid, classical or correspondence.
+//! * `GET /api/ratings/<username>` gets a player's rating in every category.
+//! * `GET /api/seeks` lists the open seeks, oldest first.
+//! * `GET /api/tournaments` lists every tournament.
+//! * `POST /api/tournaments` with `{"token": ..., "name": ..., "system": "swiss",
+//! "time_control": "10+5", "rated": true, "rounds": 5}` creates a tournament
+//! organized by the account of the session, where `system` is `round_robin` or
+//! `swiss`, and `rounds` is only given for a Swiss.
+//! * `GET /api/tournaments/<id>` gets a tournament's players, pairings and games,
+//! and standings.
+//! * `POST /api/tournaments/<id>/players` with `{"token": ...}` registers the account
+//! of the session, and with `{"token": ..., "username": ...}` lets the organizer
+//! register any account.
+//! * `POST /api/tournaments/<id>/start` with `{"token": ...}` lets the organizer start
+//! the tournament.
+//! * `POST /api/tournaments/<id>/results` with `{"token": ..., "round": 1, "board": 2,
+//! "result": "1-0"}` lets the organizer set the result of a board, such as for a
+//! game that was aborted.
+//! * `GET /api/tournaments/<id>/crosstable` exports the crosstable as plain text.
+//! * `GET /api/arenas` lists every arena.
+//! * `POST /api/arenas` with `{"token": ..., "name": ..., "time_control": "3+2",
+//! "rated": true, "berserk": true, "minutes": 60, "starts_in": 5}` creates an arena
+//! organized by the account of the session, which starts in `starts_in` minutes, or
+//! right away if it is left out, and lasts for `minutes`.
+//! * `GET /api/arenas/<id>` gets an arena's players, games and standings.
+//! * `POST /api/arenas/<id>/players` with `{"token": ...}` adds the account of the
+//! session to the arena, or lets it be paired again after it was paused.
+//! * `POST /api/arenas/<id>/pause` with `{"token": ...}` stops pairing the account of
+//! the session, keeping its score.
+//! * `POST /api/correspondence` with `{"token": ..., "opponent": ..., "days": 3,
+//! "rated": true, "color": "White"}` starts a correspondence game between the
+//! account of the session and another account, where each side has `days` for every
+//! move, and the color is picked at random if it is left out.
+//! * `GET /api/correspondence` lists the unfinished correspondence games of the
+//! account of the session whose token is given, along with its seat token in each
+//! game, to play moves with through `POST /api/games/<id>/move`.
+//!
+//! Admins, the accounts named in the config, manage the ban list with the token of
+//! one of their sessions. Banning disconnects the clients it applies to, and a
+//! banned address is refused by the API as well.
+//!
+//! * `GET /api/bans` lists the banned addresses and accounts.
+//! * `POST /api/bans` with `{"token": ..., "ip": "203.0.113.7", "user": ...}` bans an
+//! address, an account, or both.
+//! * `DELETE /api/bans/ips/<ip>` and `DELETE /api/bans/users/<username>` lift a ban.
+//!
+//! Tokens are never part of a URL, where they would end up in logs and browser
+//! history: they are sent in the body of a `POST`, and otherwise in an
+//! `Authorization: Bearer <token>` header. Logging in and registering hash the
+//! password at length, so each address may only try a few times in a row.
+
+use rand::{self, Rng};
+use rustc_serialize::json;
+use rustc_serialize::{Decodable, Encodable};
+use std::collections::HashMap;
+use std::io::Read;
+use std::str;
+use std::sync::{Arc, Mutex};
+
+use iron::prelude::*;
+use iron::{Handler, status};
+use iron::method::Method;
+use iron::mime::Mime;
+
+use super::accounts::{self, Accounts};
+use super::arena::{Arena, ArenaStanding, Arenas};
+use super::bans::{self, Bans};
+use super::board::{Board, Cell, Color};
+use super::chess_server::{RateLimit, Relay};
+use super::config;
+use super::conditional;
+use super::front::FORWARDED_FOR;
+use super::game::{Game, Move, Outcome};
+use super::lobby::Lobby;
+use super::ratings::{self, Ratings};
+use super::room::Room;
+use super::tournaments::{Placing, Tournament, Tournaments};
+
+/// How many players are listed on the leaderboard.
+const LEADERBOARD_LEN: usize = 100;
+/// The most days per move a correspondence game may have.
+const MAX_DAYS: u64 = 14;
+/// How long a day is, in milliseconds.
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+/// How many times an address may log in or register in a row.
+const LOGIN_BURST: f64 = 5.0;
+/// How many more times an address may log in or register each second after that.
+const LOGINS_PER_SEC: f64 = 0.1;
+/// How many addresses are remembered before those that could log in again in full
+/// are forgotten.
+const MAX_LOGIN_ADDRS: usize = 10000;
+
+#[derive(RustcEncodable)]
+/// A short description of a game, for listing games.
+struct GameSummary {
+    id: u32,
+    white: bool,
+    black: bool,
+    turn: Color,
+    moves: usize,
+    result: String,
+}
+
+#[derive(RustcEncodable)]
+/// The full description of a game.
+struct GameDetail {
+    id: u32,
+    fen: String,
+    pgn: String,
+    moves: Vec<String>,
+    san: Vec<String>,
+    turn: Color,
+    check: bool,
+    checkmate: bool,
+    result: String,
+    outcome: Option<Outcome>,
+}
+
+#[derive(RustcEncodable)]
+/// The legal moves of a position, as "e2e4"-style strings.
+struct LegalMoves {
+    fen: String,
+    moves: Vec<String>,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to play a move.
+struct MoveRequest {
+    token: String,
+    from: String,
+    to: String,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to set conditional moves.
+struct ConditionalRequest {
+    token: String,
+    lines: Vec<Vec<String>>,
+}
+
+#[derive(RustcEncodable)]
+/// The conditional moves of a player, as lines of "e2e4"-style strings.
+struct ConditionalMoves {
+    lines: Vec<Vec<String>>,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to register or log in.
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+#[derive(RustcEncodable)]
+/// An account, along with a session token right after logging in.
+struct UserInfo {
+    username: String,
+    token: Option<String>,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request made as the account of a session.
+struct SessionRequest {
+    token: String,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to create a tournament.
+struct NewTournament {
+    token: String,
+    name: String,
+    system: String,
+    time_control: String,
+    rated: bool,
+    rounds: Option<u32>,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to register for a tournament, as the account of the
+/// session, or as `username` if the organizer registers someone.
+struct Registration {
+    token: String,
+    username: Option<String>,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to set the result of a board of a tournament.
+struct ResultRequest {
+    token: String,
+    round: usize,
+    board: usize,
+    result: String,
+}
+
+#[derive(RustcEncodable)]
+/// A short description of a tournament, for listing tournaments.
+struct TournamentSummary {
+    id: u32,
+    name: String,
+    system: String,
+    players: usize,
+    round: usize,
+    rounds: u32,
+    finished: bool,
+}
+
+#[derive(RustcEncodable)]
+/// The full description of a tournament, along with its standings.
+struct TournamentDetail {
+    tournament: Tournament,
+    standings: Vec<Placing>,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to create an arena.
+struct NewArena {
+    token: String,
+    name: String,
+    time_control: String,
+    rated: bool,
+    berserk: bool,
+    minutes: u64,
+    starts_in: Option<u64>,
+}
+
+#[derive(RustcEncodable)]
+/// A short description of an arena, for listing arenas.
+struct ArenaSummary {
+    id: u32,
+    name: String,
+    players: usize,
+    starts_ms: u64,
+    ends_ms: u64,
+    finished: bool,
+}
+
+#[derive(RustcEncodable)]
+/// The full description of an arena, along with its standings.
+struct ArenaDetail {
+    arena: Arena,
+    standings: Vec<ArenaStanding>,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to start a correspondence game.
+struct NewCorrespondence {
+    token: String,
+    opponent: String,
+    days: u64,
+    rated: bool,
+    color: Option<Color>,
+}
+
+#[derive(RustcDecodable)]
+/// The body of a request to ban an address, an account, or both.
+struct BanRequest {
+    token: String,
+    ip: Option<String>,
+    user: Option<String>,
+}
+
+#[derive(RustcEncodable)]
+/// A correspondence game, as seen by one of its players, who plays `color` with
+/// the seat token `seat`. `remaining_ms` is the time left for the side to move.
+struct CorrespondenceGame {
+    id: u32,
+    white: String,
+    black: String,
+    color: Color,
+    seat: String,
+    turn: Color,
+    moves: usize,
+    fen: String,
+    days: u64,
+    remaining_ms: u64,
+}
+
+#[derive(RustcEncodable)]
+/// The body of a response to a request that failed.
+struct ApiError {
+    error: String,
+}
+
+/// The Iron handler for the API. Finds games through the lobby, and reads and
+/// plays them through the actor of each game, which also updates the WebSocket
+/// clients in the game about moves played through the API. Accounts and their
+/// sessions are managed through `accounts`, ratings are read from `ratings`, and
+/// tournaments and arenas are run through `tournaments` and `arenas`. `admins`
+/// may change `bans`. Through `relay`, banned clients are disconnected, and the
+/// players of a newly paired tournament round are summoned into their games.
+pub struct Api {
+    lobby: Arc<Mutex<Lobby>>,
+    accounts: Arc<Accounts>,
+    ratings: Arc<Ratings>,
+    tournaments: Arc<Tournaments>,
+    arenas: Arc<Arenas>,
+    bans: Arc<Bans>,
+    admins: Vec<String>,
+    relay: Relay,
+    logins: Mutex<HashMap<String, RateLimit>>,
+}
+
+/// Helper function to build a JSON response.
+fn respond<T: Encodable>(status: status::Status, body: &T) -> IronResult<Response> {
+    let json_type: Mime = "application/json".parse().unwrap();
+    Ok(Response::with((status, json_type, json::encode(body).unwrap())))
+}
+
+/// Helper function to build a JSON error response.
+fn fail(status: status::Status, error: &str) -> IronResult<Response> {
+    respond(status, &ApiError{error: String::from(error)})
+}
+
+/// Helper function to decode the JSON body of a request.
+fn read_body<T: Decodable>(req: &mut Request) -> Option<T> {
+    let mut body = String::new();
+    if req.body.read_to_string(&mut body).is_err() {
+        return None
+    }
+    json::decode(&body).ok()
+}
+
+/// Helper function to get a move as an "e2e4"-style string.
+fn coordinate(from: &Cell, to: &Cell) -> String {
+    format!("{}{}", from.notation(), to.notation())
+}
+
+/// Helper function to parse a move given as an "e2e4"-style string.
+fn parse_move(coordinate: &str) -> Option<Move> {
+    if coordinate.len() != 4 || coordinate.chars().count() != 4 {
+        return None
+    }
+    match (Cell::from_notation(&coordinate[..2]), Cell::from_notation(&coordinate[2..])) {
+        (Some(from), Some(to)) => Some(Move{from: from, to: to}),
+        _ => None,
+    }
+}
+
+/// Helper function to describe the conditional moves of a player.
+fn conditional_moves(game: &Game, color: &Color) -> ConditionalMoves {
+    let lines = conditional::lines(game.conditional(color));
+    ConditionalMoves {
+        lines: lines.iter()
+            .map(|line| line.iter().map(|m| coordinate(&m.from, &m.to)).collect())
+            .collect(),
+    }
+}
+
+/// Helper function to decode a percent-encoded query string value.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                // Parsing alone would also take a sign, as in "%+1".
+                let digits = &bytes[i + 1..i + 3];
+                let hex = if digits.iter().all(|byte| byte.is_ascii_hexdigit()) {
+                    str::from_utf8(digits).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                } else {
+                    None
+                };
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    },
+                    None => decoded.push(b'%'),
+                }
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Helper function to get a parameter from the query string of a request.
+fn query_param(req: &Request, name: &str) -> Option<String> {
+    let query = match req.url.query {
+        Some(ref query) => query,
+        None => return None,
+    };
+    for pair in query.split('&') {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            return Some(percent_decode(parts.next().unwrap_or("")))
+        }
+    }
+    None
+}
+
+/// Helper function to get the token given in the `Authorization: Bearer <token>`
+/// header of a request.
+fn bearer(req: &Request) -> Option<String> {
+    match req.headers.get_raw("Authorization") {
+        Some(values) if values.len() == 1 => bearer_token(&String::from_utf8_lossy(&values[0])),
+        _ => None,
+    }
+}
+
+/// Helper function to get the token of a "Bearer <token>" authorization.
+fn bearer_token(authorization: &str) -> Option<String> {
+    let mut parts = authorization.trim().splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.to_lowercase() == "bearer" &&
+                                       !token.trim().is_empty() => {
+            Some(String::from(token.trim()))
+        },
+        _ => None,
+    }
+}
+
+/// Helper function to get the IP address a request comes from, without its port.
+/// Requests reach the API through the front listener, which gives the address of
+/// the client in a header of its own, in place of any the client sent.
+fn client_ip(req: &Request) -> String {
+    match req.headers.get_raw(FORWARDED_FOR) {
+        Some(values) if values.len() == 1 => {
+            bans::normalize_ip(&String::from_utf8_lossy(&values[0]))
+        },
+        _ => bans::normalize_ip(&req.remote_addr.ip().to_string()),
+    }
+}
+
+/// Helper function to describe a game in full.
+fn detail(id: u32, game: &Game) -> GameDetail {
+    GameDetail {
+        id: id,
+        fen: game.fen(),
+        pgn: game.pgn(id),
+        moves: game.history.iter().map(|m| coordinate(&m.from, &m.to)).collect(),
+        san: game.san_history(),
+        turn: game.board.color(),
+        check: game.check,
+        checkmate: game.checkmate,
+        result: String::from(game.result()),
+        outcome: game.outcome.clone(),
+    }
+}
+
+/// Helper function to describe a correspondence game to one of its players.
+fn correspondence(id: u32, game: &Game, user: &str) -> Option<CorrespondenceGame> {
+    let (color, seat) = match game.seat_of(user) {
+        Some(seat) => seat,
+        None => return None,
+    };
+    let turn = game.board.color();
+    let (move_ms, _) = game.clock.time_control();
+    Some(CorrespondenceGame {
+        id: id,
+        white: String::from(game.user(&Color::White).unwrap_or("")),
+        black: String::from(game.user(&Color::Black).unwrap_or("")),
+        color: color,
+        seat: seat,
+        remaining_ms: game.clock.remaining(&turn),
+        turn: turn,
+        moves: game.history.len(),
+        fen: game.fen(),
+        days: move_ms / DAY_MS,
+    })
+}
+
+/// Helper function to list the legal moves of a position.
+fn legal_moves(board: &Board, fen: String) -> LegalMoves {
+    LegalMoves {
+        fen: fen,
+        moves: board.all_legal_moves().iter().map(|&(ref from, ref to)| coordinate(from, to)).collect(),
+    }
+}
+
+impl Api {
+    pub fn new(lobby: Arc<Mutex<Lobby>>, accounts: Arc<Accounts>, ratings: Arc<Ratings>,
+               tournaments: Arc<Tournaments>, arenas: Arc<Arenas>, bans: Arc<Bans>,
+               admins: Vec<String>, relay: Relay) -> Api {
+        Api {
+            lobby: lobby,
+            accounts: accounts,
+            ratings: ratings,
+            tournaments: tournaments,
+            arenas: arenas,
+            bans: bans,
+            admins: admins,
+            relay: relay,
+            logins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Helper function to check that a session token belongs to an admin.
+    fn is_admin(&self, token: &str) -> bool {
+        self.accounts.user(token).map_or(false, |user| accounts::is_admin(&self.admins, &user))
+    }
+
+    /// Helper function to check that the address a request comes from may try a
+    /// password, since each try takes a while to hash.
+    fn may_try_password(&self, req: &Request) -> bool {
+        let mut logins = self.logins.lock().unwrap();
+        if logins.len() >= MAX_LOGIN_ADDRS {
+            logins.retain(|_, limit| !limit.is_full());
+        }
+        logins.entry(client_ip(req))
+            .or_insert_with(|| RateLimit::new(LOGIN_BURST, LOGINS_PER_SEC))
+            .allow()
+    }
+
+    /// Helper function to get a handle on a game, without holding the lobby.
+    fn room(&self, id: u32) -> Option<Room> {
+        self.lobby.lock().unwrap().get(id)
+    }
+
+    /// Helper function to get a copy of a game.
+    fn game(&self, id: u32) -> Option<Game> {
+        self.room(id).and_then(|room| room.inspect())
+    }
+
+    /// Helper function to get a copy of a game as spectators see it.
+    fn spectated(&self, id: u32) -> Option<Game> {
+        self.room(id).and_then(|room| room.spectate())
+    }
+
+    /// `GET /api/games`
+    fn list_games(&self) -> IronResult<Response> {
+        let rooms = self.lobby.lock().unwrap().rooms();
+        let games: Vec<GameSummary> = rooms.iter().filter_map(|&(id, ref room)| {
+            room.spectate().map(|game| GameSummary {
+                id: id,
+                white: game.white.is_some(),
+                black: game.black.is_some(),
+                turn: game.board.color(),
+                moves: game.history.len(),
+                result: String::from(game.result()),
+            })
+        }).collect();
+        respond(status::Ok, &games)
+    }
+
+    /// `GET /api/games/<id>`
+    fn get_game(&self, id: u32) -> IronResult<Response> {
+        match self.spectated(id) {
+            Some(game) => respond(status::Ok, &detail(id, &game)),
+            None => fail(status::NotFound, "no such game"),
+        }
+    }
+
+    /// `GET /api/games/<id>/legal`
+    fn game_legal_moves(&self, id: u32) -> IronResult<Response> {
+        match self.spectated(id) {
+            Some(ref game) if game.outcome.is_some() => respond(status::Ok, &LegalMoves {
+                fen: game.fen(),
+                moves: Vec::new(),
+            }),
+            Some(ref game) => respond(status::Ok, &legal_moves(&game.board, game.fen())),
+            None => fail(status::NotFound, "no such game"),
+        }
+    }
+
+    /// `GET /api/legal?fen=<fen>`
+    fn fen_legal_moves(&self, req: &Request) -> IronResult<Response> {
+        let fen = match query_param(req, "fen") {
+            Some(fen) => fen,
+            None => return fail(status::BadRequest, "missing fen"),
+        };
+        match Board::from_fen(&fen) {
+            Some(board) => respond(status::Ok, &legal_moves(&board, fen)),
+            None => fail(status::BadRequest, "invalid fen"),
+        }
+    }
+
+    /// `POST /api/games/<id>/move`
+    fn submit_move(&self, id: u32, req: &mut Request) -> IronResult<Response> {
+        let mut body = String::new();
+        if req.body.read_to_string(&mut body).is_err() {
+            return fail(status::BadRequest, "unreadable body")
+        }
+        let request: MoveRequest = match json::decode(&body) {
+            Ok(request) => request,
+            Err(_) => return fail(status::BadRequest, "expected {\"token\", \"from\", \"to\"}"),
+        };
+        let (from, to) = match (Cell::from_notation(&request.from), Cell::from_notation(&request.to)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return fail(status::BadRequest, "invalid cell"),
+        };
+        let room = match self.room(id) {
+            Some(room) => room,
+            None => return fail(status::NotFound, "no such game"),
+        };
+        // The game updates its WebSocket clients itself.
+        if let Err(error) = room.play(&request.token, from, to) {
+            return fail(status::Forbidden, error)
+        }
+        match room.inspect() {
+            Some(game) => respond(status::Ok, &detail(id, &game)),
+            None => fail(status::NotFound, "no such game"),
+        }
+    }
+
+    /// `POST /api/games/<id>/conditional`
+    fn set_conditional(&self, id: u32, req: &mut Request) -> IronResult<Response> {
+        let request: ConditionalRequest = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\", \"lines\"}"),
+        };
+        let mut lines = Vec::new();
+        for line in &request.lines {
+            match line.iter().map(|m| parse_move(m)).collect::<Option<Vec<Move>>>() {
+                Some(line) => lines.push(line),
+                None => return fail(status::BadRequest, "invalid move"),
+            }
+        }
+        let room = match self.room(id) {
+            Some(room) => room,
+            None => return fail(status::NotFound, "no such game"),
+        };
+        if let Err(error) = room.set_conditional(&request.token, lines) {
+            return fail(status::Forbidden, error)
+        }
+        self.get_conditional(id, &request.token)
+    }
+
+    /// `GET /api/games/<id>/conditional`
+    fn get_conditional(&self, id: u32, token: &str) -> IronResult<Response> {
+        let game = match self.game(id) {
+            Some(game) => game,
+            None => return fail(status::NotFound, "no such game"),
+        };
+        match game.color_of_token(token) {
+            Some(color) => respond(status::Ok, &conditional_moves(&game, &color)),
+            None => fail(status::Forbidden, "you are not playing in this game"),
+        }
+    }
+
+    /// `POST /api/accounts`
+    fn register(&self, req: &mut Request) -> IronResult<Response> {
+        if !self.may_try_password(req) {
+            return fail(status::TooManyRequests, "too many attempts, try again later")
+        }
+        let credentials: Credentials = match read_body(req) {
+            Some(credentials) => credentials,
+            None => return fail(status::BadRequest, "expected {\"username\", \"password\"}"),
+        };
+        if self.accounts.exists(&credentials.username) {
+            return fail(status::Conflict, "that username is taken")
+        }
+        match self.accounts.register(&credentials.username, &credentials.password) {
+            Ok(()) => respond(status::Created, &UserInfo{username: credentials.username,
+                                                         token: None}),
+            Err(error) => fail(status::BadRequest, error),
+        }
+    }
+
+    /// `POST /api/sessions`
+    fn login(&self, req: &mut Request) -> IronResult<Response> {
+        if !self.may_try_password(req) {
+            return fail(status::TooManyRequests, "too many attempts, try again later")
+        }
+        let credentials: Credentials = match read_body(req) {
+            Some(credentials) => credentials,
+            None => return fail(status::BadRequest, "expected {\"username\", \"password\"}"),
+        };
+        match self.accounts.login(&credentials.username, &credentials.password) {
+            Ok((username, token)) => respond(status::Created, &UserInfo{username: username,
+                                                                        token: Some(token)}),
+            Err(error) => fail(status::Unauthorized, error),
+        }
+    }
+
+    /// `GET /api/sessions`
+    fn get_session(&self, req: &Request) -> IronResult<Response> {
+        match bearer(req).and_then(|token| self.accounts.user(&token)) {
+            Some(username) => respond(status::Ok, &UserInfo{username: username, token: None}),
+            None => fail(status::NotFound, "no such session"),
+        }
+    }
+
+    /// `GET /api/leaderboard/<category>`
+    fn leaderboard(&self, category: &str) -> IronResult<Response> {
+        if !ratings::CATEGORIES.contains(&category) {
+            return fail(status::NotFound, "no such category")
+        }
+        respond(status::Ok, &self.ratings.leaderboard(category, LEADERBOARD_LEN))
+    }
+
+    /// `GET /api/ratings/<username>`
+    fn user_ratings(&self, username: &str) -> IronResult<Response> {
+        match self.accounts.username(username) {
+            Some(username) => respond(status::Ok, &self.ratings.of(&username)),
+            None => fail(status::NotFound, "no such account"),
+        }
+    }
+
+    /// `GET /api/seeks`
+    fn list_seeks(&self) -> IronResult<Response> {
+        respond(status::Ok, &self.lobby.lock().unwrap().seeks())
+    }
+
+    /// `GET /api/tournaments`
+    fn list_tournaments(&self) -> IronResult<Response> {
+        let tournaments: Vec<TournamentSummary> = self.tournaments.list().into_iter()
+            .map(|tournament| TournamentSummary {
+                id: tournament.id,
+                name: tournament.name,
+                system: tournament.system,
+                players: tournament.players.len(),
+                round: tournament.pairings.len(),
+                rounds: tournament.rounds,
+                finished: tournament.finished,
+            })
+            .collect();
+        respond(status::Ok, &tournaments)
+    }
+
+    /// Helper function to describe a tournament in full.
+    fn tournament(&self, status: status::Status, tournament: Tournament)
+                  -> IronResult<Response> {
+        let standings = tournament.standings();
+        respond(status, &TournamentDetail{tournament: tournament, standings: standings})
+    }
+
+    /// Helper function to check that a tournament exists, and if `organizer` is
+    /// given, that the account is its organizer.
+    fn check_tournament(&self, id: u32, organizer: Option<&str>)
+                        -> Result<(), IronResult<Response>> {
+        let tournament = match self.tournaments.get(id) {
+            Some(tournament) => tournament,
+            None => return Err(fail(status::NotFound, "no such tournament")),
+        };
+        if organizer.map_or(false, |user| user != tournament.organizer) {
+            return Err(fail(status::Forbidden, "only the organizer can do that"))
+        }
+        Ok(())
+    }
+
+    /// `POST /api/tournaments`
+    fn create_tournament(&self, req: &mut Request) -> IronResult<Response> {
+        let request: NewTournament = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\", \"name\", \"system\", \
+                                                     \"time_control\", \"rated\"}"),
+        };
+        let organizer = match self.accounts.user(&request.token) {
+            Some(user) => user,
+            None => return fail(status::Unauthorized, "no such session"),
+        };
+        let (initial_ms, increment_ms) = match config::parse_time_control(&request.time_control) {
+            Some(time_control) => time_control,
+            None => return fail(status::BadRequest, "invalid time control"),
+        };
+        match self.tournaments.create(&organizer, &request.name, &request.system, initial_ms,
+                                      increment_ms, request.rated, request.rounds) {
+            Ok(tournament) => self.tournament(status::Created, tournament),
+            Err(error) => fail(status::BadRequest, error),
+        }
+    }
+
+    /// `POST /api/tournaments/<id>/players`
+    fn register_player(&self, id: u32, req: &mut Request) -> IronResult<Response> {
+        let request: Registration = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\"}"),
+        };
+        let user = match self.accounts.user(&request.token) {
+            Some(user) => user,
+            None => return fail(status::Unauthorized, "no such session"),
+        };
+        let player = match request.username {
+            Some(ref username) => {
+                if let Err(response) = self.check_tournament(id, Some(&user)) {
+                    return response
+                }
+                match self.accounts.username(username) {
+                    Some(username) => username,
+                    None => return fail(status::NotFound, "no such account"),
+                }
+            },
+            None => user,
+        };
+        if let Err(response) = self.check_tournament(id, None) {
+            return response
+        }
+        match self.tournaments.register(id, &player) {
+            Ok(tournament) => self.tournament(status::Ok, tournament),
+            Err(error) => fail(status::Conflict, error),
+        }
+    }
+
+    /// `POST /api/tournaments/<id>/start`
+    fn start_tournament(&self, id: u32, req: &mut Request) -> IronResult<Response> {
+        let request: SessionRequest = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\"}"),
+        };
+        let user = match self.accounts.user(&request.token) {
+            Some(user) => user,
+            None => return fail(status::Unauthorized, "no such session"),
+        };
+        if let Err(response) = self.check_tournament(id, Some(&user)) {
+            return response
+        }
+        match self.tournaments.start(id, &user, &self.ratings, &self.lobby, &self.relay) {
+            Ok(tournament) => self.tournament(status::Ok, tournament),
+            Err(error) => fail(status::Conflict, error),
+        }
+    }
+
+    /// `POST /api/tournaments/<id>/results`
+    fn set_result(&self, id: u32, req: &mut Request) -> IronResult<Response> {
+        let request: ResultRequest = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\", \"round\", \"board\", \
+                                                     \"result\"}"),
+        };
+        let user = match self.accounts.user(&request.token) {
+            Some(user) => user,
+            None => return fail(status::Unauthorized, "no such session"),
+        };
+        if let Err(response) = self.check_tournament(id, Some(&user)) {
+            return response
+        }
+        match self.tournaments.set_result(id, &user, request.round, request.board,
+                                          &request.result, &self.lobby, &self.relay) {
+            Ok(tournament) => self.tournament(status::Ok, tournament),
+            Err(error) => fail(status::BadRequest, error),
+        }
+    }
+
+    /// `GET /api/tournaments/<id>/crosstable`
+    fn crosstable(&self, id: u32) -> IronResult<Response> {
+        let text_type: Mime = "text/plain; charset=utf-8".parse().unwrap();
+        match self.tournaments.get(id) {
+            Some(tournament) => Ok(Response::with((status::Ok, text_type,
+                                                   tournament.crosstable()))),
+            None => fail(status::NotFound, "no such tournament"),
+        }
+    }
+
+    /// `GET /api/arenas`
+    fn list_arenas(&self) -> IronResult<Response> {
+        let arenas: Vec<ArenaSummary> = self.arenas.list().into_iter()
+            .map(|arena| ArenaSummary {
+                id: arena.id,
+                name: arena.name,
+                players: arena.players.len(),
+                starts_ms: arena.starts_ms,
+                ends_ms: arena.ends_ms,
+                finished: arena.finished,
+            })
+            .collect();
+        respond(status::Ok, &arenas)
+    }
+
+    /// Helper function to describe an arena in full.
+    fn arena(&self, status: status::Status, arena: Arena) -> IronResult<Response> {
+        let standings = arena.standings();
+        respond(status, &ArenaDetail{arena: arena, standings: standings})
+    }
+
+    /// `POST /api/arenas`
+    fn create_arena(&self, req: &mut Request) -> IronResult<Response> {
+        let request: NewArena = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\", \"name\", \
+                                                     \"time_control\", \"rated\", \"berserk\", \
+                                                     \"minutes\"}"),
+        };
+        let organizer = match self.accounts.user(&request.token) {
+            Some(user) => user,
+            None => return fail(status::Unauthorized, "no such session"),
+        };
+        let (initial_ms, increment_ms) = match config::parse_time_control(&request.time_control) {
+            Some(time_control) => time_control,
+            None => return fail(status::BadRequest, "invalid time control"),
+        };
+        match self.arenas.create(&organizer, &request.name, initial_ms, increment_ms,
+                                 request.rated, request.berserk,
+                                 request.starts_in.unwrap_or(0), request.minutes) {
+            Ok(arena) => self.arena(status::Created, arena),
+            Err(error) => fail(status::BadRequest, error),
+        }
+    }
+
+    /// `POST /api/arenas/<id>/players` and `POST /api/arenas/<id>/pause`
+    fn arena_player(&self, id: u32, join: bool, req: &mut Request) -> IronResult<Response> {
+        let request: SessionRequest = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\"}"),
+        };
+        let user = match self.accounts.user(&request.token) {
+            Some(user) => user,
+            None => return fail(status::Unauthorized, "no such session"),
+        };
+        if self.arenas.get(id).is_none() {
+            return fail(status::NotFound, "no such arena")
+        }
+        let result = if join { self.arenas.join(id, &user) } else { self.arenas.pause(id, &user) };
+        match result {
+            Ok(arena) => self.arena(status::Ok, arena),
+            Err(error) => fail(status::Conflict, error),
+        }
+    }
+
+    /// `POST /api/correspondence`
+    fn create_correspondence(&self, req: &mut Request) -> IronResult<Response> {
+        let request: NewCorrespondence = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\", \"opponent\", \
+                                                     \"days\", \"rated\"}"),
+        };
+        let user = match self.accounts.user(&request.token) {
+            Some(user) => user,
+            None => return fail(status::Unauthorized, "no such session"),
+        };
+        let opponent = match self.accounts.username(&request.opponent) {
+            Some(opponent) => opponent,
+            None => return fail(status::NotFound, "no such account"),
+        };
+        if opponent == user {
+            return fail(status::BadRequest, "cannot play against yourself")
+        }
+        if request.days < 1 || request.days > MAX_DAYS {
+            return fail(status::BadRequest, "days must be between 1 and 14")
+        }
+        let white = match request.color {
+            Some(color) => color == Color::White,
+            None => rand::thread_rng().gen(),
+        };
+        let (white, black) = if white { (&user, &opponent) } else { (&opponent, &user) };
+        let (id, room) = {
+            let mut lobby = self.lobby.lock().unwrap();
+            let id = lobby.create_correspondence(request.days * DAY_MS, request.rated,
+                                                 white, black, &user);
+            (id, lobby.get(id))
+        };
+        match room.and_then(|room| room.inspect())
+                .and_then(|game| correspondence(id, &game, &user)) {
+            Some(game) => respond(status::Created, &game),
+            None => fail(status::NotFound, "no such game"),
+        }
+    }
+
+    /// `GET /api/correspondence`
+    fn list_correspondence(&self, req: &Request) -> IronResult<Response> {
+        let user = match bearer(req).and_then(|token| self.accounts.user(&token)) {
+            Some(user) => user,
+            None => return fail(status::Unauthorized, "no such session"),
+        };
+        let rooms = self.lobby.lock().unwrap().rooms();
+        let games: Vec<CorrespondenceGame> = rooms.iter()
+            .filter(|&&(_, ref room)| room.is_correspondence() && !room.is_over())
+            .filter_map(|&(id, ref room)| {
+                room.inspect().and_then(|game| correspondence(id, &game, &user))
+            })
+            .collect();
+        respond(status::Ok, &games)
+    }
+
+    /// `GET /api/bans`
+    fn list_bans(&self, req: &Request) -> IronResult<Response> {
+        if !bearer(req).map_or(false, |token| self.is_admin(&token)) {
+            return fail(status::Forbidden, "only admins may see the bans")
+        }
+        respond(status::Ok, &self.bans.list())
+    }
+
+    /// `POST /api/bans`
+    fn ban(&self, req: &mut Request) -> IronResult<Response> {
+        let request: BanRequest = match read_body(req) {
+            Some(request) => request,
+            None => return fail(status::BadRequest, "expected {\"token\", \"ip\", \"user\"}"),
+        };
+        if !self.is_admin(&request.token) {
+            return fail(status::Forbidden, "only admins may ban")
+        }
+        if request.ip.is_none() && request.user.is_none() {
+            return fail(status::BadRequest, "expected an ip or a user to ban")
+        }
+        let ip = request.ip.as_ref().map(|ip| bans::normalize_ip(ip));
+        let ip = ip.as_ref().map(|ip| ip.as_str());
+        let user = request.user.as_ref().map(|user| user.trim());
+        if let Err(error) = self.bans.ban(ip, user) {
+            return fail(status::InternalServerError, error)
+        }
+        info!("banned {:?} {:?}", ip, user);
+        self.relay.expel(ip, user);
+        respond(status::Created, &self.bans.list())
+    }
+
+    /// `DELETE /api/bans/ips/<ip>` and `DELETE /api/bans/users/<username>`
+    fn unban(&self, kind: &str, banned: &str, req: &Request) -> IronResult<Response> {
+        if !bearer(req).map_or(false, |token| self.is_admin(&token)) {
+            return fail(status::Forbidden, "only admins may lift bans")
+        }
+        let banned = percent_decode(banned);
+        let result = match kind {
+            "ips" => self.bans.unban_ip(&banned),
+            "users" => self.bans.unban_user(&banned),
+            _ => return fail(status::NotFound, "not found"),
+        };
+        match result {
+            Ok(true) => Ok(Response::with(status::NoContent)),
+            Ok(false) => fail(status::NotFound, "no such ban"),
+            Err(error) => fail(status::InternalServerError, error),
+        }
+    }
+
+    /// `DELETE /api/sessions`
+    fn logout(&self, req: &Request) -> IronResult<Response> {
+        if bearer(req).map_or(false, |token| self.accounts.logout(&token)) {
+            Ok(Response::with(status::NoContent))
+        } else {
+            fail(status::NotFound, "no such session")
+        }
+    }
+}
+
+impl Handler for Api {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path: Vec<String> = req.url.path.iter().filter(|s| !s.is_empty()).cloned().collect();
+        let id = path.get(1).and_then(|s| s.parse::<u32>().ok());
+        let numbered = path.first()
+            .map_or(false, |s| s == "games" || s == "tournaments" || s == "arenas");
+        if path.is_empty() || (path.len() > 1 && numbered && id.is_none()) {
+            return fail(status::NotFound, "not found")
+        }
+        if self.bans.is_banned(&client_ip(req), None) {
+            return fail(status::Forbidden, "you are banned")
+        }
+        match (req.method.clone(), path.len()) {
+            (Method::Get, 1) if path[0] == "games" => self.list_games(),
+            (Method::Get, 1) if path[0] == "legal" => self.fen_legal_moves(req),
+            (Method::Get, 1) if path[0] == "seeks" => self.list_seeks(),
+            (Method::Get, 1) if path[0] == "tournaments" => self.list_tournaments(),
+            (Method::Post, 1) if path[0] == "tournaments" => self.create_tournament(req),
+            (Method::Get, 2) if path[0] == "tournaments" => {
+                match self.tournaments.get(id.unwrap()) {
+                    Some(tournament) => self.tournament(status::Ok, tournament),
+                    None => fail(status::NotFound, "no such tournament"),
+                }
+            },
+            (Method::Post, 3) if path[0] == "tournaments" && path[2] == "players" => {
+                self.register_player(id.unwrap(), req)
+            },
+            (Method::Post, 3) if path[0] == "tournaments" && path[2] == "start" => {
+                self.start_tournament(id.unwrap(), req)
+            },
+            (Method::Post, 3) if path[0] == "tournaments" && path[2] == "results" => {
+                self.set_result(id.unwrap(), req)
+            },
+            (Method::Get, 3) if path[0] == "tournaments" && path[2] == "crosstable" => {
+                self.crosstable(id.unwrap())
+            },
+            (Method::Get, 1) if path[0] == "arenas" => self.list_arenas(),
+            (Method::Post, 1) if path[0] == "arenas" => self.create_arena(req),
+            (Method::Get, 2) if path[0] == "arenas" => {
+                match self.arenas.get(id.unwrap()) {
+                    Some(arena) => self.arena(status::Ok, arena),
+                    None => fail(status::NotFound, "no such arena"),
+                }
+            },
+            (Method::Post, 3) if path[0] == "arenas" && path[2] == "players" => {
+                self.arena_player(id.unwrap(), true, req)
+            },
+            (Method::Post, 3) if path[0] == "arenas" && path[2] == "pause" => {
+                self.arena_player(id.unwrap(), false, req)
+            },
+            (Method::Post, 1) if path[0] == "correspondence" => self.create_correspondence(req),
+            (Method::Get, 1) if path[0] == "correspondence" => self.list_correspondence(req),
+            (Method::Get, 2) if path[0] == "games" => self.get_game(id.unwrap()),
+            (Method::Get, 3) if path[0] == "games" && path[2] == "legal" => {
+                self.game_legal_moves(id.unwrap())
+            },
+            (Method::Post, 3) if path[0] == "games" && path[2] == "move" => {
+                self.submit_move(id.unwrap(), req)
+            },
+            (Method::Post, 3) if path[0] == "games" && path[2] == "conditional" => {
+                self.set_conditional(id.unwrap(), req)
+            },
+            (Method::Get, 3) if path[0] == "games" && path[2] == "conditional" => {
+                match bearer(req) {
+                    Some(token) => self.get_conditional(id.unwrap(), &token),
+                    None => fail(status::Unauthorized, "missing token"),
+                }
+            },
+            (Method::Get, 1) if path[0] == "bans" => self.list_bans(req),
+            (Method::Post, 1) if path[0] == "bans" => self.ban(req),
+            (Method::Delete, 3) if path[0] == "bans" => self.unban(&path[1], &path[2], req),
+            (Method::Post, 1) if path[0] == "accounts" => self.register(req),
+            (Method::Post, 1) if path[0] == "sessions" => self.login(req),
+            (Method::Get, 1) if path[0] == "sessions" => self.get_session(req),
+            (Method::Delete, 1) if path[0] == "sessions" => self.logout(req),
+            (Method::Get, 2) if path[0] == "leaderboard" => self.leaderboard(&path[1]),
+            (Method::Get, 2) if path[0] == "ratings" => self.user_ratings(&path[1]),
+            _ => fail(status::NotFound, "not found"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bearer_tokens() {
+        assert_eq!(bearer_token("Bearer abc123"), Some(String::from("abc123")));
+        assert_eq!(bearer_token("bearer  abc123 "), Some(String::from("abc123")));
+        assert_eq!(bearer_token("Basic YWxpY2U6c2VjcmV0"), None);
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("abc123"), None);
+    }
+
+    #[test]
+    fn decodes_percent_encoded_values() {
+        assert_eq!(percent_decode("rnbqkbnr%2Fpppppppp+w"), "rnbqkbnr/pppppppp w");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%+1%2g"), "% 1%2g");
+    }
+}
@@ -0,0 +1,557 @@
+//! A module for arenas: time-boxed tournaments where players are paired again as
+//! soon as their game ends, instead of in rounds. An organizer creates an arena,
+//! and players with accounts join it, even after it has started. While the arena
+//! runs, the director thread pairs the waiting players, those with the closest
+//! scores together, creates their games with the seats reserved for them, and
+//! moves their clients into the games. No one is paired once time is up, games in
+//! which no move has been played by then are aborted, so that a player who never
+//! shows up does not hold the arena open, and the arena finishes when its last
+//! game does.
+//!
+//! A win is worth 2 points and a draw 1. A player who wins two games in a row is
+//! on fire, and scores double until they fail to win. If the arena allows it, a
+//! player may go berserk before their first move, halving their clock, for an
+//! extra point if they win after playing at least 7 moves. A player who is not
+//! there for their game is paused until they join again.
+//!
+//! Every time the standings change, they are sent to the clients of every player
+//! in the arena as an `Action::Arena`. Arenas are stored in `arenas.json` in the
+//! store directory.
+
+use rustc_serialize::json;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::board::Color;
+use super::chess_server::{Action, Relay};
+use super::game::{Game, Player};
+use super::lobby::Lobby;
+use super::storage;
+
+/// The name of the file arenas are stored in, in the store directory.
+const FILE_NAME: &'static str = "arenas.json";
+/// How often the director thread collects results and pairs waiting players.
+const DIRECTOR_SECS: u64 = 2;
+/// The longest name of an arena that is accepted.
+const NAME_LEN: usize = 60;
+/// The longest an arena may last, in minutes.
+const MAX_MINUTES: u64 = 24 * 60;
+/// How many moves a berserk player must play for their win to earn the extra point.
+const BERSERK_MOVES: usize = 7;
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a player in an arena. `streak` is how many games they have won in a
+/// row, and a paused player is not paired until they join again.
+pub struct Contender {
+    pub user: String,
+    pub score: u32,
+    pub streak: u32,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a game of an arena. `result` is "1-0", "0-1", "1/2-1/2", or
+/// "aborted" once the game is over, along with the points each player scored.
+pub struct Bout {
+    pub game: u32,
+    pub white: String,
+    pub black: String,
+    pub result: Option<String>,
+    pub white_points: u32,
+    pub black_points: u32,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents an arena, which runs from `starts_ms` until `ends_ms`, in
+/// milliseconds since the epoch.
+pub struct Arena {
+    pub id: u32,
+    pub name: String,
+    pub organizer: String,
+    pub initial_ms: u64,
+    pub increment_ms: u64,
+    pub rated: bool,
+    pub berserk: bool,
+    pub starts_ms: u64,
+    pub ends_ms: u64,
+    pub players: Vec<Contender>,
+    pub games: Vec<Bout>,
+    pub finished: bool,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// A player's place in the standings of an arena, to be shown to clients. A
+/// player on fire scores double.
+pub struct ArenaStanding {
+    pub rank: usize,
+    pub user: String,
+    pub score: u32,
+    pub games: usize,
+    pub fire: bool,
+    pub paused: bool,
+}
+
+/// Every arena, in the order they were created.
+pub struct Arenas {
+    path: PathBuf,
+    arenas: Mutex<Vec<Arena>>,
+}
+
+/// Helper function to score a game for a player, who played the given color,
+/// given the winner, whether they were on fire, and whether they went berserk.
+fn points(game: &Game, color: &Color, winner: Option<&Color>, fire: bool) -> u32 {
+    let base = match winner {
+        Some(winner) if winner == color => 2,
+        Some(_) => 0,
+        None => 1,
+    };
+    let moves = match *color {
+        Color::White => (game.history.len() + 1) / 2,
+        Color::Black => game.history.len() / 2,
+    };
+    let berserk = base == 2 && game.berserked.contains(color) && moves >= BERSERK_MOVES;
+    (if fire { base * 2 } else { base }) + if berserk { 1 } else { 0 }
+}
+
+impl Arena {
+    /// Get the standings, with the highest scores first, and players with the same
+    /// score in the order they joined.
+    pub fn standings(&self) -> Vec<ArenaStanding> {
+        let mut players: Vec<&Contender> = self.players.iter().collect();
+        players.sort_by(|a, b| b.score.cmp(&a.score));
+        players.into_iter().enumerate().map(|(i, player)| {
+            ArenaStanding {
+                rank: i + 1,
+                user: player.user.clone(),
+                score: player.score,
+                games: self.games.iter()
+                    .filter(|bout| bout.white == player.user || bout.black == player.user)
+                    .count(),
+                fire: player.streak >= 2,
+                paused: player.paused,
+            }
+        }).collect()
+    }
+
+    /// Get the standings as an `Action::Arena`, to be sent to the players.
+    pub fn action(&self) -> Action {
+        Action::Arena {
+            arena: self.id,
+            name: self.name.clone(),
+            remaining_ms: self.ends_ms.saturating_sub(storage::now_ms()),
+            standings: self.standings(),
+        }
+    }
+
+    /// Helper function to check if a player is in a game that is not over.
+    fn playing(&self, user: &str) -> bool {
+        self.games.iter().any(|bout| {
+            bout.result.is_none() && (bout.white == user || bout.black == user)
+        })
+    }
+
+    /// Helper function to record the end of a game, scoring it for both players, or
+    /// pausing whoever was not there if it was aborted.
+    fn score(&mut self, index: usize, game: &Game) {
+        let outcome = match game.outcome {
+            Some(ref outcome) => outcome.clone(),
+            None => return,
+        };
+        let (white, black) = (self.games[index].white.clone(), self.games[index].black.clone());
+        if outcome.reason == "aborted" {
+            let absent = |player: &Option<Player>| {
+                player.as_ref().map_or(true, |player| player.addr.is_empty())
+            };
+            for (user, gone) in vec![(white, absent(&game.white)), (black, absent(&game.black))] {
+                if gone {
+                    info!("pausing {} in arena {}, who was not there", user, self.id);
+                    if let Some(player) = self.players.iter_mut().find(|p| p.user == user) {
+                        player.paused = true;
+                    }
+                }
+            }
+            self.games[index].result = Some(String::from("aborted"));
+            return;
+        }
+        let winner = outcome.winner.as_ref();
+        let result = match winner {
+            Some(&Color::White) => "1-0",
+            Some(&Color::Black) => "0-1",
+            None => "1/2-1/2",
+        };
+        for (user, color) in vec![(white, Color::White), (black, Color::Black)] {
+            let scored = match self.players.iter_mut().find(|p| p.user == user) {
+                Some(player) => {
+                    let scored = points(game, &color, winner, player.streak >= 2);
+                    player.score += scored;
+                    player.streak = if winner == Some(&color) { player.streak + 1 } else { 0 };
+                    scored
+                },
+                None => 0,
+            };
+            match color {
+                Color::White => self.games[index].white_points = scored,
+                Color::Black => self.games[index].black_points = scored,
+            }
+        }
+        self.games[index].result = Some(String::from(result));
+    }
+
+    /// Helper function to get who a player last played, if anyone.
+    fn last_opponent(&self, user: &str) -> Option<&str> {
+        self.games.iter().rev()
+            .find(|bout| bout.white == user || bout.black == user)
+            .map(|bout| if bout.white == user { bout.black.as_str() } else { bout.white.as_str() })
+    }
+
+    /// Helper function to count the games a player has played as White, minus
+    /// those they have played as Black.
+    fn balance(&self, user: &str) -> i32 {
+        self.games.iter().fold(0, |balance, bout| {
+            if bout.white == user {
+                balance + 1
+            } else if bout.black == user {
+                balance - 1
+            } else {
+                balance
+            }
+        })
+    }
+
+    /// Helper function to pair the waiting players, those with the closest scores
+    /// together, avoiding an immediate rematch when there is anyone else. Returns
+    /// the pairs as White and Black.
+    fn pair(&self) -> Vec<(String, String)> {
+        let mut waiting: Vec<&Contender> = self.players.iter()
+            .filter(|player| !player.paused && !self.playing(&player.user))
+            .collect();
+        waiting.sort_by(|a, b| b.score.cmp(&a.score));
+        let mut pairs = Vec::new();
+        while waiting.len() >= 2 {
+            let first = waiting.remove(0);
+            let last = self.last_opponent(&first.user);
+            let index = waiting.iter()
+                .position(|player| Some(player.user.as_str()) != last)
+                .unwrap_or(0);
+            let second = waiting.remove(index);
+            let (first, second) = (first.user.clone(), second.user.clone());
+            pairs.push(match self.balance(&first).cmp(&self.balance(&second)) {
+                Ordering::Greater => (second, first),
+                _ => (first, second),
+            });
+        }
+        pairs
+    }
+
+    /// Helper function to collect the results of finished games, pair the waiting
+    /// players while the arena runs, and finish it once time is up and the last
+    /// game is over, aborting the games that have not started once time is up.
+    /// Returns the players to summon into each new game, and whether anything
+    /// changed.
+    fn direct(&mut self, lobby: &Mutex<Lobby>) -> (Vec<(String, u32)>, bool) {
+        let now = storage::now_ms();
+        let mut changed = false;
+        let mut summons = Vec::new();
+        if self.finished || now < self.starts_ms {
+            return (summons, changed)
+        }
+        for index in 0..self.games.len() {
+            if self.games[index].result.is_some() {
+                continue;
+            }
+            let room = lobby.lock().unwrap().get(self.games[index].game);
+            match room.and_then(|room| room.inspect()) {
+                Some(ref game) if game.outcome.is_some() => self.score(index, game),
+                _ => continue,
+            }
+            changed = true;
+        }
+        if now < self.ends_ms {
+            for (white, black) in self.pair() {
+                let game = lobby.lock().unwrap().create_reserved(self.initial_ms,
+                                                                 self.increment_ms, self.rated,
+                                                                 self.berserk, &white, &black);
+                info!("paired {} with {} in arena {}, game {}", white, black, self.id, game);
+                summons.push((white.clone(), game));
+                summons.push((black.clone(), game));
+                self.games.push(Bout{game: game, white: white, black: black, result: None,
+                                     white_points: 0, black_points: 0});
+                changed = true;
+            }
+        } else if self.games.iter().any(|bout| bout.result.is_none()) {
+            for bout in self.games.iter().filter(|bout| bout.result.is_none()) {
+                // Aborted games are collected like any other on the next run.
+                if let Some(room) = lobby.lock().unwrap().get(bout.game) {
+                    room.abort_unstarted();
+                }
+            }
+        } else {
+            info!("arena {} finished", self.id);
+            self.finished = true;
+            changed = true;
+        }
+        (summons, changed)
+    }
+}
+
+/// Spawn the director thread, which runs every arena in progress, moves the
+/// players of each new game into it through the relay, and sends the standings to
+/// the players whenever they change.
+pub fn direct(arenas: Arc<Arenas>, lobby: Arc<Mutex<Lobby>>, relay: Relay) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(DIRECTOR_SECS));
+            for (users, summons, action) in arenas.direct(&lobby) {
+                for (user, game) in summons {
+                    relay.summon(&user, game);
+                }
+                relay.notify(users, action);
+            }
+        }
+    });
+}
+
+impl Arenas {
+    /// Open the arenas stored in the given directory.
+    pub fn open(dir: &str) -> Arenas {
+        let path = PathBuf::from(dir).join(FILE_NAME);
+        let mut contents = String::new();
+        let read = File::open(&path).and_then(|mut f| f.read_to_string(&mut contents));
+        let arenas = match read {
+            Ok(_) => match json::decode(&contents) {
+                Ok(arenas) => arenas,
+                Err(e) => {
+                    error!("could not read arenas from {}: {}", path.display(), e);
+                    process::exit(1);
+                },
+            },
+            Err(_) => Vec::new(),
+        };
+        Arenas {
+            path: path,
+            arenas: Mutex::new(arenas),
+        }
+    }
+
+    /// Get every arena.
+    pub fn list(&self) -> Vec<Arena> {
+        self.arenas.lock().unwrap().clone()
+    }
+
+    /// Get the arena with the given id.
+    pub fn get(&self, id: u32) -> Option<Arena> {
+        self.arenas.lock().unwrap().iter().find(|a| a.id == id).cloned()
+    }
+
+    /// Create an arena, organized by the given account, that starts in
+    /// `starts_in` minutes and lasts for `minutes`.
+    pub fn create(&self, organizer: &str, name: &str, initial_ms: u64, increment_ms: u64,
+                  rated: bool, berserk: bool, starts_in: u64, minutes: u64)
+                  -> Result<Arena, &'static str> {
+        if name.trim().is_empty() || name.chars().count() > NAME_LEN {
+            return Err("names of arenas must be 1 to 60 characters long")
+        }
+        if initial_ms == 0 && increment_ms == 0 {
+            return Err("the time control must give the players some time")
+        }
+        if minutes == 0 || minutes > MAX_MINUTES || starts_in > MAX_MINUTES {
+            return Err("arenas must last between a minute and a day, and start within a day")
+        }
+        let starts_ms = storage::now_ms() + starts_in * 60 * 1000;
+        let mut arenas = self.arenas.lock().unwrap();
+        let arena = Arena {
+            id: arenas.last().map_or(1, |a| a.id + 1),
+            name: String::from(name.trim()),
+            organizer: String::from(organizer),
+            initial_ms: initial_ms,
+            increment_ms: increment_ms,
+            rated: rated,
+            berserk: berserk,
+            starts_ms: starts_ms,
+            ends_ms: starts_ms + minutes * 60 * 1000,
+            players: Vec::new(),
+            games: Vec::new(),
+            finished: false,
+        };
+        arenas.push(arena.clone());
+        self.save(&arenas);
+        info!("{} created arena {}", organizer, arena.id);
+        Ok(arena)
+    }
+
+    /// Add an account to an arena that is not over, or let a paused player be paired
+    /// again.
+    pub fn join(&self, id: u32, user: &str) -> Result<Arena, &'static str> {
+        self.update(id, |arena| {
+            if arena.finished || storage::now_ms() >= arena.ends_ms {
+                return Err("the arena is over")
+            }
+            match arena.players.iter_mut().find(|player| player.user == user) {
+                Some(player) => player.paused = false,
+                None => arena.players.push(Contender{user: String::from(user), score: 0,
+                                                     streak: 0, paused: false}),
+            }
+            Ok(())
+        })
+    }
+
+    /// Pause a player, so that they are not paired again until they join again.
+    /// Their score is kept.
+    pub fn pause(&self, id: u32, user: &str) -> Result<Arena, &'static str> {
+        self.update(id, |arena| {
+            match arena.players.iter_mut().find(|player| player.user == user) {
+                Some(player) => {
+                    player.paused = true;
+                    Ok(())
+                },
+                None => Err("not in the arena"),
+            }
+        })
+    }
+
+    /// Helper function to change an arena and save every arena.
+    fn update<F>(&self, id: u32, change: F) -> Result<Arena, &'static str>
+        where F: FnOnce(&mut Arena) -> Result<(), &'static str> {
+        let mut arenas = self.arenas.lock().unwrap();
+        let arena = {
+            let arena = try!(arenas.iter_mut().find(|a| a.id == id).ok_or("no such arena"));
+            try!(change(arena));
+            arena.clone()
+        };
+        self.save(&arenas);
+        Ok(arena)
+    }
+
+    /// Run every arena in progress. Returns, for each arena that changed, its
+    /// players, who to summon into which new game, and its standings.
+    pub fn direct(&self, lobby: &Mutex<Lobby>) -> Vec<(Vec<String>, Vec<(String, u32)>, Action)> {
+        let mut arenas = self.arenas.lock().unwrap();
+        let mut changes = Vec::new();
+        for arena in arenas.iter_mut() {
+            let (summons, changed) = arena.direct(lobby);
+            if changed {
+                let users = arena.players.iter().map(|player| player.user.clone()).collect();
+                changes.push((users, summons, arena.action()));
+            }
+        }
+        if !changes.is_empty() {
+            self.save(&arenas);
+        }
+        changes
+    }
+
+    /// Helper function to write every arena to disk.
+    fn save(&self, arenas: &[Arena]) {
+        if let Err(e) = storage::replace(&self.path, &json::encode(&arenas).unwrap()) {
+            error!("could not save arenas to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::{Cell, Color};
+    use clock::Clock;
+    use game::{Game, Move, Outcome};
+
+    /// Helper function to get a game in which the given number of moves have been
+    /// played, that went to `winner`, or was drawn if there is none.
+    fn game(moves: usize, winner: Option<Color>) -> Game {
+        let mut game = Game::new(Clock::new(60000, 0));
+        for _ in 0..moves {
+            game.history.push(Move{from: Cell::new(0, 0), to: Cell::new(0, 1)});
+        }
+        game.outcome = Some(Outcome{winner: winner, reason: String::from("checkmate")});
+        game
+    }
+
+    /// Helper function to get an arena between the given players that has started.
+    fn arena(players: &[&str]) -> Arena {
+        Arena {
+            id: 1,
+            name: String::from("test"),
+            organizer: String::from(players[0]),
+            initial_ms: 60000,
+            increment_ms: 0,
+            rated: false,
+            berserk: true,
+            starts_ms: 0,
+            ends_ms: u64::max_value(),
+            players: players.iter().map(|user| Contender{user: String::from(*user), score: 0,
+                                                         streak: 0, paused: false}).collect(),
+            games: Vec::new(),
+            finished: false,
+        }
+    }
+
+    #[test]
+    fn scores_wins_draws_and_losses() {
+        let won = game(20, Some(Color::White));
+        assert_eq!(points(&won, &Color::White, Some(&Color::White), false), 2);
+        assert_eq!(points(&won, &Color::Black, Some(&Color::White), false), 0);
+        let drawn = game(20, None);
+        assert_eq!(points(&drawn, &Color::White, None, false), 1);
+        assert_eq!(points(&drawn, &Color::White, None, true), 2);
+        assert_eq!(points(&won, &Color::White, Some(&Color::White), true), 4);
+        assert_eq!(points(&won, &Color::Black, Some(&Color::White), true), 0);
+    }
+
+    #[test]
+    fn adds_a_point_for_a_berserk_win_of_enough_moves() {
+        // Black has played 7 moves after 14, but only 6 after 13.
+        let mut long = game(14, Some(Color::Black));
+        long.berserked.push(Color::Black);
+        assert_eq!(points(&long, &Color::Black, Some(&Color::Black), false), 3);
+        assert_eq!(points(&long, &Color::Black, Some(&Color::Black), true), 5);
+        let mut short = game(13, Some(Color::Black));
+        short.berserked.push(Color::Black);
+        assert_eq!(points(&short, &Color::Black, Some(&Color::Black), false), 2);
+        let mut lost = game(14, Some(Color::White));
+        lost.berserked.push(Color::Black);
+        assert_eq!(points(&lost, &Color::Black, Some(&Color::White), false), 0);
+    }
+
+    #[test]
+    fn doubles_the_score_of_a_streak() {
+        let mut arena = arena(&["alice", "bob"]);
+        for (i, winner) in vec![Color::White, Color::White, Color::White, Color::Black,
+                                Color::White].into_iter().enumerate() {
+            arena.games.push(Bout{game: i as u32, white: String::from("alice"),
+                                  black: String::from("bob"), result: None, white_points: 0,
+                                  black_points: 0});
+            arena.score(i, &game(20, Some(winner)));
+        }
+        let scored: Vec<u32> = arena.games.iter().map(|bout| bout.white_points).collect();
+        assert_eq!(scored, vec![2, 2, 4, 0, 2]);
+        assert_eq!(arena.players[0].score, 10);
+        assert_eq!(arena.players[0].streak, 1);
+        assert_eq!(arena.players[1].score, 2);
+        assert!(arena.standings()[0].user == "alice" && !arena.standings()[0].fire);
+    }
+
+    #[test]
+    fn pauses_whoever_missed_an_aborted_game() {
+        let mut arena = arena(&["alice", "bob"]);
+        arena.games.push(Bout{game: 1, white: String::from("alice"), black: String::from("bob"),
+                              result: None, white_points: 0, black_points: 0});
+        let mut aborted = Game::new(Clock::new(60000, 0));
+        aborted.reserve(&Color::White, "white");
+        aborted.take_seat("1.2.3.4:5", None, &Color::Black, "black");
+        aborted.outcome = Some(Outcome{winner: None, reason: String::from("aborted")});
+        arena.score(0, &aborted);
+        assert_eq!(arena.games[0].result, Some(String::from("aborted")));
+        assert!(arena.players[0].paused);
+        assert!(!arena.players[1].paused);
+    }
+}
@@ -0,0 +1,168 @@
+//! A module for the ban list, which keeps banned IP addresses and accounts off the
+//! server. Bans are stored in `bans.json` in the store directory, and are checked
+//! before a WebSocket request is accepted, and before a line client is served, so
+//! that a banned client never reaches the relay. Only the admins named in the
+//! config can change the list, through the API.
+
+use rustc_serialize::json;
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+
+use super::storage;
+
+/// The name of the file bans are stored in, in the store directory.
+const FILE_NAME: &'static str = "bans.json";
+
+#[derive(Debug, Clone, Default)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents every ban: IP addresses without their port, with IPv6 addresses in
+/// brackets as in "[::1]", and lowercase usernames.
+pub struct BanList {
+    pub ips: BTreeSet<String>,
+    pub users: BTreeSet<String>,
+}
+
+/// The ban list, behind a lock, written to disk whenever it changes.
+pub struct Bans {
+    path: PathBuf,
+    list: Mutex<BanList>,
+}
+
+/// Get the IP address of a client address, without its port, so that a client is
+/// banned whichever port it connects from.
+pub fn ip_of(addr: &str) -> &str {
+    match addr.rfind(':') {
+        Some(i) if !addr.ends_with(']') => &addr[..i],
+        _ => addr,
+    }
+}
+
+/// Write an IP address the way the list keeps it: without surrounding spaces, in
+/// lowercase, and with IPv6 addresses in brackets, so that "2001:DB8::1" and
+/// "[2001:db8::1]" are the same ban.
+pub fn normalize_ip(ip: &str) -> String {
+    let ip = ip.trim().to_lowercase();
+    if ip.contains(':') && !ip.starts_with('[') {
+        format!("[{}]", ip)
+    } else {
+        ip
+    }
+}
+
+impl Bans {
+    /// Open the bans stored in the given directory, creating the directory if it
+    /// does not exist. Exits if the file cannot be read, rather than lift every ban.
+    pub fn open(dir: &str) -> Bans {
+        fs::create_dir_all(dir).unwrap();
+        let path = PathBuf::from(dir).join(FILE_NAME);
+        let mut contents = String::new();
+        let mut list = match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => match json::decode::<BanList>(&contents) {
+                Ok(list) => list,
+                Err(e) => {
+                    error!("could not read bans from {}: {}", path.display(), e);
+                    process::exit(1);
+                },
+            },
+            Err(_) => BanList::default(),
+        };
+        list.ips = list.ips.iter().map(|ip| normalize_ip(ip)).collect();
+        info!("loaded {} banned addresses and {} banned accounts from {}",
+              list.ips.len(), list.users.len(), path.display());
+        Bans {
+            path: path,
+            list: Mutex::new(list),
+        }
+    }
+
+    /// Check if a client is banned, by the address it connects from or the account
+    /// it logs in to.
+    pub fn is_banned(&self, addr: &str, user: Option<&str>) -> bool {
+        let list = self.list.lock().unwrap();
+        list.ips.contains(ip_of(addr)) ||
+            user.map_or(false, |user| list.users.contains(&user.to_lowercase()))
+    }
+
+    /// Get every ban.
+    pub fn list(&self) -> BanList {
+        self.list.lock().unwrap().clone()
+    }
+
+    /// Ban an IP address, an account, or both.
+    pub fn ban(&self, ip: Option<&str>, user: Option<&str>) -> Result<(), &'static str> {
+        self.change(|list| {
+            if let Some(ip) = ip {
+                list.ips.insert(normalize_ip(ip));
+            }
+            if let Some(user) = user {
+                list.users.insert(user.to_lowercase());
+            }
+        })
+    }
+
+    /// Lift the ban on an IP address. Returns whether it was banned.
+    pub fn unban_ip(&self, ip: &str) -> Result<bool, &'static str> {
+        let mut found = false;
+        try!(self.change(|list| found = list.ips.remove(&normalize_ip(ip))));
+        Ok(found)
+    }
+
+    /// Lift the ban on an account. Returns whether it was banned.
+    pub fn unban_user(&self, user: &str) -> Result<bool, &'static str> {
+        let mut found = false;
+        try!(self.change(|list| found = list.users.remove(&user.to_lowercase())));
+        Ok(found)
+    }
+
+    /// Helper function to change the list and write it to disk, keeping the old
+    /// list if it could not be written.
+    fn change<F: FnOnce(&mut BanList)>(&self, f: F) -> Result<(), &'static str> {
+        let mut list = self.list.lock().unwrap();
+        let mut changed = list.clone();
+        f(&mut changed);
+        if let Err(e) = self.save(&changed) {
+            error!("could not save bans to {}: {}", self.path.display(), e);
+            return Err("could not save the bans")
+        }
+        *list = changed;
+        Ok(())
+    }
+
+    /// Helper function to write every ban to disk.
+    fn save(&self, list: &BanList) -> io::Result<()> {
+        storage::replace(&self.path, &json::encode(list).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::tests::scratch_dir;
+
+    #[test]
+    fn keeps_ipv6_addresses_in_brackets() {
+        assert_eq!(normalize_ip(" 2001:DB8::1 "), "[2001:db8::1]");
+        assert_eq!(normalize_ip("[2001:db8::1]"), "[2001:db8::1]");
+        assert_eq!(normalize_ip("192.0.2.1"), "192.0.2.1");
+    }
+
+    #[test]
+    fn bans_an_address_on_any_port_until_lifted() {
+        let dir = scratch_dir("bans_an_address_on_any_port_until_lifted");
+        let bans = Bans::open(&dir);
+        bans.ban(Some("2001:db8::1"), Some("Mallory")).unwrap();
+        assert!(bans.is_banned("[2001:db8::1]:50123", None));
+        assert!(bans.is_banned("192.0.2.1:50123", Some("mallory")));
+        assert!(!bans.is_banned("192.0.2.1:50123", None));
+
+        let reopened = Bans::open(&dir);
+        assert!(reopened.is_banned("[2001:db8::1]:4000", None));
+        assert_eq!(reopened.unban_ip("2001:DB8::1"), Ok(true));
+        assert_eq!(reopened.unban_ip("2001:db8::1"), Ok(false));
+        assert!(!reopened.is_banned("[2001:db8::1]:4000", None));
+    }
+}
@@ -4,7 +4,9 @@
 //! options. The Piece object contains an enum of PieceType, which is used to
 //! determine the game behavior for each piece.
 
-#[derive(Debug, Clone)]
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[derive(RustcDecodable, RustcEncodable)]
 /// Represents the index of a single cell in the board.
 pub struct Cell {
@@ -37,7 +39,7 @@ enum PieceType {
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[derive(RustcDecodable, RustcEncodable)]
 /// Represents the color of a piece.
-enum Color {
+pub enum Color {
     Black,
     White,
 }
@@ -52,6 +54,35 @@ pub struct Piece {
     cell: Cell,
 }
 
+impl Color {
+    /// Get the opposing color.
+    pub fn other(&self) -> Color {
+        match *self {
+            Color::Black => Color::White,
+            Color::White => Color::Black,
+        }
+    }
+}
+
+impl Piece {
+    /// Get the letter used for the piece in notation: uppercase for white pieces
+    /// and lowercase for black pieces.
+    fn letter(&self) -> char {
+        let letter = match self.piece_type {
+            PieceType::Bishop => 'B',
+            PieceType::King => 'K',
+            PieceType::Knight => 'N',
+            PieceType::Pawn => 'P',
+            PieceType::Rook => 'R',
+            PieceType::Queen => 'Q',
+        };
+        match self.color {
+            Color::White => letter,
+            Color::Black => letter.to_ascii_lowercase(),
+        }
+    }
+}
+
 impl Cell {
     pub fn new(row: i32, col: i32) -> Cell {
         Cell{
@@ -59,6 +90,25 @@ impl Cell {
             col: col
         }
     }
+
+    /// Parse a cell in algebraic notation, e.g. "e4".
+    pub fn from_notation(notation: &str) -> Option<Cell> {
+        let bytes = notation.as_bytes();
+        if bytes.len() != 2 {
+            return None
+        }
+        let cell = Cell::new(b'8' as i32 - bytes[1] as i32, bytes[0] as i32 - b'a' as i32);
+        if cell.row < 8 && cell.row >= 0 && cell.col < 8 && cell.col >= 0 {
+            Some(cell)
+        } else {
+            None
+        }
+    }
+
+    /// Get the cell in algebraic notation, e.g. "e4".
+    pub fn notation(&self) -> String {
+        format!("{}{}", (b'a' + self.col as u8) as char, 8 - self.row)
+    }
 }
 
 impl Board {
@@ -143,6 +193,11 @@ impl Board {
         self.color == Color::White
     }
 
+    /// Get the color of the player whose turn it is.
+    pub fn color(&self) -> Color {
+        self.color.clone()
+    }
+
     /// Get the piece associated with a given cell index.
     fn get_piece(&self, cell: &Cell) -> &Option<Piece> {
         &self.board[cell.row as usize][cell.col as usize]
@@ -291,6 +346,199 @@ impl Board {
         moves
     }
 
+    /// Calculate the legal moves for a given cell index: the potential moves of a
+    /// piece belonging to the current player that do not leave their king in danger.
+    pub fn legal_moves(&self, cell: &Cell) -> Vec<Cell> {
+        if !self.inbounds(cell) || !self.is_friendly_board(cell) {
+            return Vec::new()
+        }
+        let mut cells = self.potential_moves(cell);
+        cells.retain(|m| !self.self_check(cell.clone(), m.clone()));
+        cells
+    }
+
+    /// Calculate every legal move for the current player, as (from, to) pairs.
+    pub fn all_legal_moves(&self) -> Vec<(Cell, Cell)> {
+        let mut moves = Vec::new();
+        for from in self.friendly_pieces() {
+            for to in self.legal_moves(&from) {
+                moves.push((from.clone(), to));
+            }
+        }
+        moves
+    }
+
+    /// Helper function to check if a move is legal for the current player.
+    pub fn is_legal(&self, from: &Cell, to: &Cell) -> bool {
+        self.inbounds(to) &&
+            self.legal_moves(from).iter().any(|m| m.row == to.row && m.col == to.col)
+    }
+
+    /// Get the move in standard algebraic notation, e.g. "Nf3", "exd5" or "Qh5#".
+    /// Must be called before the move is made.
+    pub fn san(&self, from: &Cell, to: &Cell) -> String {
+        let piece = match *self.get_piece(from) {
+            Some(ref piece) => piece.clone(),
+            None => return format!("{}{}", from.notation(), to.notation()),
+        };
+        let capture = !self.is_empty(to);
+        let mut san = String::new();
+        if piece.piece_type == PieceType::Pawn {
+            if capture {
+                san.push_str(&from.notation()[..1]);
+            }
+        } else {
+            san.push(piece.letter().to_ascii_uppercase());
+            // Disambiguate between pieces of the same type that can reach the cell.
+            let others: Vec<Cell> = self.friendly_pieces().into_iter().filter(|cell| {
+                (cell.row != from.row || cell.col != from.col) &&
+                    self.get_piece(cell).as_ref().map_or(false, |p| p.piece_type == piece.piece_type) &&
+                    self.is_legal(cell, to)
+            }).collect();
+            if !others.is_empty() {
+                if others.iter().all(|cell| cell.col != from.col) {
+                    san.push_str(&from.notation()[..1]);
+                } else if others.iter().all(|cell| cell.row != from.row) {
+                    san.push_str(&from.notation()[1..]);
+                } else {
+                    san.push_str(&from.notation());
+                }
+            }
+        }
+        if capture {
+            san.push('x');
+        }
+        san.push_str(&to.notation());
+        if self.is_promotion(from, to) {
+            san.push_str("=Q");
+        }
+        let mut after = self.clone();
+        after.move_piece(from.clone(), to.clone());
+        if after.check() {
+            san.push(if after.checkmate() { '#' } else { '+' });
+        }
+        san
+    }
+
+    /// Check if a move is a pawn move or a capture, which resets the halfmove
+    /// clock. Must be called before the move is made.
+    pub fn is_irreversible(&self, from: &Cell, to: &Cell) -> bool {
+        !self.is_empty(to) ||
+            self.get_piece(from).as_ref().map_or(false, |p| p.piece_type == PieceType::Pawn)
+    }
+
+    /// Check if a move takes a pawn to the last rank. The pawn is always promoted
+    /// to a queen, since underpromotion is not implemented.
+    pub fn is_promotion(&self, from: &Cell, to: &Cell) -> bool {
+        (to.row == 0 || to.row == 7) &&
+            self.get_piece(from).as_ref().map_or(false, |p| p.piece_type == PieceType::Pawn)
+    }
+
+    /// Draw the board as text from White's side, or from Black's side if `flipped`
+    /// is set, with empty cells as dots and ranks separated by " / ", to fit on one
+    /// line of chat.
+    pub fn diagram(&self, flipped: bool) -> String {
+        let mut rows: Vec<usize> = (0..8).collect();
+        let mut files: Vec<usize> = (0..8).collect();
+        if flipped {
+            rows.reverse();
+            files.reverse();
+        }
+        let ranks: Vec<String> = rows.iter().map(|&row| {
+            let cells: String = files.iter().map(|&col| match self.board[row][col] {
+                Some(ref piece) => piece.letter(),
+                None => '.',
+            }).collect();
+            format!("{} {}", 8 - row, cells)
+        }).collect();
+        let letters: String = files.iter().map(|&col| (b'a' + col as u8) as char).collect();
+        format!("{} / {}", ranks.join(" / "), letters)
+    }
+
+    /// Get the piece placement and side to move in Forsyth-Edwards Notation.
+    /// Castling and en passant are not implemented, so those fields are always
+    /// empty, and the move counters are left to the caller.
+    pub fn fen(&self) -> String {
+        let mut fen = String::new();
+        for (i, row) in self.board.iter().enumerate() {
+            if i > 0 {
+                fen.push('/');
+            }
+            let mut empty = 0;
+            for piece_option in row {
+                match *piece_option {
+                    Some(ref piece) => {
+                        if empty > 0 {
+                            fen.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        fen.push(piece.letter());
+                    },
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+            }
+        }
+        fen.push_str(if self.color == Color::White { " w - -" } else { " b - -" });
+        fen
+    }
+
+    /// Build a board from the piece placement and side to move of a position in
+    /// Forsyth-Edwards Notation. The remaining fields are ignored.
+    pub fn from_fen(fen: &str) -> Option<Board> {
+        let mut fields = fen.split_whitespace();
+        let placement = match fields.next() {
+            Some(placement) => placement,
+            None => return None,
+        };
+        let color = match fields.next() {
+            Some("w") | None => Color::White,
+            Some("b") => Color::Black,
+            Some(_) => return None,
+        };
+        let rows: Vec<&str> = placement.split('/').collect();
+        if rows.len() != 8 {
+            return None
+        }
+        let mut board = Vec::new();
+        for (i, fen_row) in rows.iter().enumerate() {
+            let mut row = Vec::new();
+            for c in fen_row.chars() {
+                if let Some(empty) = c.to_digit(10) {
+                    for _ in 0..empty {
+                        row.push(None);
+                    }
+                    continue;
+                }
+                let piece_type = match c.to_ascii_uppercase() {
+                    'B' => PieceType::Bishop,
+                    'K' => PieceType::King,
+                    'N' => PieceType::Knight,
+                    'P' => PieceType::Pawn,
+                    'R' => PieceType::Rook,
+                    'Q' => PieceType::Queen,
+                    _ => return None,
+                };
+                let col = row.len() as i32;
+                row.push(Some(Piece{
+                    piece_type: piece_type,
+                    color: if c.is_uppercase() { Color::White } else { Color::Black },
+                    cell: Cell::new(i as i32, col)
+                }));
+            }
+            if row.len() != 8 {
+                return None
+            }
+            board.push(row);
+        }
+        Some(Board {
+            color: color,
+            board: board
+        })
+    }
+
     /// Helper function to check if a move would place the current player's king in danger.
     /// Clones the board and makes the move, before running check() on the clone.
     pub fn self_check(&self, from: Cell, to: Cell) -> bool {
@@ -352,7 +600,8 @@ impl Board {
 
     /// Helper function that checks for checkmate.
     /// Iterates through all enemy pieces and checks if any of their potential moves
-    /// can bring them out of check.
+    /// can bring them out of check. The enemy is only mated if they are in check,
+    /// and stalemated otherwise.
     pub fn checkmate(&self) -> bool {
         for cell in self.enemy_pieces() {
             for potential_move in self.potential_moves(&cell) {
@@ -366,10 +615,15 @@ impl Board {
         true
     }
 
-    /// Helper function that moves a piece from a cell to the target cell
+    /// Helper function that moves a piece from a cell to the target cell, and
+    /// promotes a pawn that reaches the last rank to a queen.
     pub fn move_piece(&mut self, from: Cell, to: Cell) -> bool {
+        let promotion = self.is_promotion(&from, &to);
         if let Some(ref mut piece) = self.get_piece(&from).clone() {
             piece.cell = to.clone();
+            if promotion {
+                piece.piece_type = PieceType::Queen;
+            }
             self.board[from.row as usize][from.col as usize] = None;
             self.board[to.row as usize][to.col as usize] = Some(piece.clone());
             return true
@@ -389,4 +643,18 @@ impl Board {
         }
         false
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for Board {
+    /// Draws the board as text from White's side, with empty cells as dots, as in
+    /// `diagram` but with one rank per line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let diagram = self.diagram(false);
+        let lines: Vec<&str> = diagram.split(" / ").collect();
+        let (files, ranks) = lines.split_last().unwrap();
+        for rank in ranks {
+            try!(writeln!(f, "{}", rank));
+        }
+        write!(f, "  {}", files)
+    }
+}
@@ -1,254 +1,969 @@
-//! A module for the server logic of Chess. Handles all WebSocket clients using
-//! a relay MPSC channel. Sends information about game logic through Actions.
+//! A module for the server logic of Chess. Handles all clients, over whichever
+//! transport they connect with, using a relay MPSC channel, which hands each
+//! client's Actions to the actor of their game. Sends information about game logic
+//! through Actions.
+//!
+//! A client is served by a `Session`, which checks and decodes the messages it
+//! sends and hands them to the relay, and an `Outbox`, which queues the messages
+//! sent to it. Neither ever waits on the client's connection, so the event loop in
+//! `front` can drive thousands of them from one thread.
 
-use rustc_serialize::json;
-use std::str;
 use std::thread;
 use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::TrySendError;
 use std::collections::HashMap;
-use websocket::{Receiver, Sender, Server, Message, WebSocketStream};
-use websocket::sender;
-use websocket::receiver;
-use websocket::message::Type;
+use std::time::{Duration, Instant};
 
-use super::board::{Board, Cell};
+use super::accounts::{self, Accounts};
+use super::bans;
+use super::board::{Board, Cell, Color};
+use super::clock::ClockState;
+use super::config::Config;
+use super::game::{Move, Outcome, Spectating};
+use super::lobby::Lobby;
+use super::protocol::{self, Encoded, Version};
+use super::ratings::{self, Ratings, Standing};
+use super::room::{Abandonment, Command};
+use super::arena::ArenaStanding;
+use super::seeks::{self, Seek, SeekInfo, Terms};
+use super::transport::{Closer, Waker};
+#[cfg(test)]
+use super::transport::{Incoming, Outgoing};
 
-const WS_ADDR: &'static str = "0.0.0.0:1981";
+/// How many Actions may be waiting for the relay thread before clients are told
+/// that the server is busy.
+const RELAY_LEN: usize = 1024;
+/// How many messages may be waiting to be sent to a client before it is
+/// disconnected for being too slow.
+const OUTBOX_LEN: usize = 64;
+/// How many players are listed in an `Action::Standings`.
+const LEADERBOARD_LEN: usize = 50;
+/// The longest message a client may send, in bytes. Longer ones are refused
+/// without being decoded.
+const MAX_MESSAGE_LEN: usize = 4096;
+/// The longest chat message a client may send, in characters.
+const MAX_CHAT_LEN: usize = 500;
+/// How many messages a client may send in a burst.
+const MESSAGE_BURST: f64 = 30.0;
+/// How many more messages a client may send each second after a burst.
+const MESSAGES_PER_SEC: f64 = 10.0;
+/// How many chat messages a client may send in a burst.
+const CHAT_BURST: f64 = 5.0;
+/// How many more chat messages a client may send each second after a burst.
+const CHATS_PER_SEC: f64 = 0.5;
 
 #[derive(Debug, Clone)]
 #[derive(RustcDecodable, RustcEncodable)]
-/// Represents a single, atomic action taken by a client.
+/// Represents a single, atomic action taken by a client, or sent to one.
 ///
-/// DO NOT MODIFY: the JavaScript relies on this!
-enum Action {
-    Connect { addr: String },
-    Disconnect { addr: String },
+/// Version 1 of the protocol sends these to clients exactly as rustc_serialize
+/// encodes them, as the variant's name and its fields in order, and the JavaScript
+/// client reads them that way. So renaming a variant, or adding, removing or
+/// reordering its fields, breaks it, while new variants are safe to add. What
+/// version 1 clients send is decoded by hand in `protocol::decode_v1`, so the
+/// fields of a variant may change freely as far as decoding is concerned.
+pub enum Action {
+    Connect { user: String },
+    Disconnect { user: String },
     Select { addr: String, cell: Cell },
-    Board { board: Board, check: bool, checkmate: bool},
+    Board { board: Board, check: bool, checkmate: bool, last: Option<Move>, clocks: ClockState },
     Msg { user: String, text: String},
     Moves { cells: Vec<Cell>},
-    Move { from: Cell, to: Cell },
+    Move { addr: String, from: Cell, to: Cell },
+    State { board: Board, turn: Color, seat: String, history: Vec<Move>,
+            clocks: ClockState, check: bool, checkmate: bool, game: u32,
+            outcome: Option<Outcome> },
+    TakeSeat { addr: String, color: Color },
+    LeaveSeat { addr: String },
+    Seat { game: u32, seat: String, token: String },
+    Say { addr: String, text: String },
+    Resume { addr: String, token: String },
+    NewGame { addr: String },
+    Join { addr: String, game: u32 },
+    Over { outcome: Outcome },
+    Nick { addr: String, nick: String },
+    Presence { white: Option<String>, black: Option<String>, spectators: Vec<String> },
+    Leaderboard { addr: String, category: String },
+    Standings { category: String, standings: Vec<Standing> },
+    Seek { addr: String, terms: Terms },
+    Unseek { addr: String },
+    Seeks { seeks: Vec<SeekInfo> },
+    Berserk { addr: String },
+    Arena { arena: u32, name: String, remaining_ms: u64, standings: Vec<ArenaStanding> },
+    Seal { addr: String, from: Cell, to: Cell },
+    Unseal { addr: String },
+    Adjourned { game: u32, sealed_by: Color },
+    Spectators { addr: String, spectating: Spectating },
 }
 
-#[derive(RustcDecodable, RustcEncodable)]
-struct Payload {
-    variant: String,
-    fields: Vec<String>
+/// Represents a message to the relay thread: a newly connected client along with
+/// their account, if they are logged in, and their outbox, a client that has
+/// disconnected, an `Action` from a connected client, a request to move the
+/// clients of an account into a game, an `Action` for the clients of some
+/// accounts, a request to disconnect the clients that were just banned, by
+/// address or account, or a client who got their seat back in a game.
+enum Relayed {
+    Connect(String, Option<String>, Outbox),
+    Disconnect(String),
+    Action(Action),
+    Summon(String, u32),
+    Notify(Vec<String>, Action),
+    Expel(Option<String>, Option<String>),
+    Resumed(String, u32),
+}
+
+/// A token bucket limiting how often a client may do something: it holds up to
+/// `burst` tokens, gains `per_sec` tokens each second, and each use takes one.
+pub struct RateLimit {
+    burst: f64,
+    per_sec: f64,
+    tokens: f64,
+    last: Instant,
 }
 
-/// Spawn a WebSocket listener thread.
-pub fn start() {
-    thread::spawn(listen);
+impl RateLimit {
+    /// Create a full bucket.
+    pub fn new(burst: f64, per_sec: f64) -> RateLimit {
+        RateLimit{burst: burst, per_sec: per_sec, tokens: burst, last: Instant::now()}
+    }
+
+    /// Helper function to get how many tokens the bucket holds by now.
+    fn available(&self) -> f64 {
+        let elapsed = self.last.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        (self.tokens + secs * self.per_sec).min(self.burst)
+    }
+
+    /// Check if the bucket has filled up again, so that forgetting it changes
+    /// nothing.
+    pub fn is_full(&self) -> bool {
+        self.available() >= self.burst
+    }
+
+    /// Take a token if there is one. Returns whether there was.
+    pub fn allow(&mut self) -> bool {
+        self.tokens = self.available();
+        self.last = Instant::now();
+        if self.tokens < 1.0 {
+            return false
+        }
+        self.tokens -= 1.0;
+        true
+    }
 }
 
-/// Create the relay MPSC (multi-producer/single-consumer) channel, spawn the
-/// relay thread, then listen for WebSocket clients and spawn their threads.
-fn listen() {
-    let server = Server::bind(WS_ADDR).unwrap();
+#[derive(Clone)]
+/// The sending half of a client's bounded queue of outgoing messages, which their
+/// connection is woken to drain. Sending never waits: a client whose queue is
+/// full has fallen too far behind, and is disconnected.
+pub struct Outbox {
+    addr: String,
+    version: Version,
+    tx: mpsc::SyncSender<String>,
+    closer: Closer,
+    waker: Waker,
+}
+
+impl Outbox {
+    /// Queue an `Action` to be sent to the client, in the client's version of the
+    /// protocol.
+    pub fn send(&self, action: &Action) {
+        self.send_text(protocol::encode(self.version, action));
+    }
+
+    /// Queue an `Action` that has already been encoded to be sent to the client.
+    pub fn send_encoded(&self, encoded: &Encoded) {
+        self.send_text(String::from(encoded.get(self.version)));
+    }
+
+    /// Queue a message telling the client that their message was refused, if
+    /// their version of the protocol has such a message.
+    pub fn send_error(&self, error: &str) {
+        if let Some(text) = protocol::error(self.version, error) {
+            self.send_text(text);
+        }
+    }
+
+    /// Disconnect the client. Reading from them fails from now on, so they are
+    /// cleaned up like any other disconnect.
+    pub fn close(&self) {
+        (*self.closer)();
+    }
+
+    /// Queue a message to be sent to the client.
+    fn send_text(&self, text: String) {
+        match self.tx.try_send(text) {
+            Ok(()) => (*self.waker)(),
+            Err(TrySendError::Full(_)) => {
+                warn!("disconnecting {}, which is too slow to keep up", self.addr);
+                // Reading from the client fails from now on, which disconnects them.
+                (*self.closer)();
+            },
+            Err(TrySendError::Disconnected(_)) => {},
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A handle on the relay thread, used to add clients over any transport, along
+/// with how many Actions are waiting for the relay thread.
+pub struct Relay {
+    tx: Arc<Mutex<mpsc::Sender<Relayed>>>,
+    pending: Arc<AtomicUsize>,
+    heartbeat: Duration,
+    idle_timeout: Duration,
+}
+
+/// Open the lobby, create the relay MPSC (multi-producer/single-consumer) channel,
+/// and spawn the relay thread, which keeps the names of accounts for guests and
+/// answers for the ratings. Returns the lobby and a handle on the relay.
+///
+/// Sending to the relay thread never waits. Only the Actions of clients are
+/// counted against `RELAY_LEN`, so a flood of them is turned away, while clients
+/// connecting and disconnecting are never held up or lost.
+pub fn start(config: &Config, accounts: Arc<Accounts>, ratings: Arc<Ratings>)
+             -> (Arc<Mutex<Lobby>>, Relay) {
     let (tx, rx) = mpsc::channel();
-    let clients = Arc::new(Mutex::new(HashMap::new()));
-    let clients_clone = clients.clone();
-    let board = Arc::new(Mutex::new(Board::new()));
-    let board_clone = board.clone();
-    let black_ip = Arc::new(Mutex::new(String::new()));
-    let white_ip = Arc::new(Mutex::new(String::new()));
-    thread::spawn(move || relay_thread(board_clone, clients_clone, rx));
+    let pending = Arc::new(AtomicUsize::new(0));
+    let abandonment = Abandonment {
+        grace: config.abandon_grace_secs.map(Duration::from_secs),
+        first_move: config.first_move_secs.map(Duration::from_secs),
+        forfeit: config.forfeit,
+    };
+    let lobby = Arc::new(Mutex::new(Lobby::open(&config.store_dir, config.initial_ms,
+                                                config.increment_ms, ratings.clone(),
+                                                abandonment,
+                                                Arc::new(config.admins.clone()))));
+    let (lobby_clone, tx_clone, pending_clone) = (lobby.clone(), tx.clone(), pending.clone());
+    thread::spawn(move || {
+        relay_thread(lobby_clone, accounts, ratings, tx_clone, pending_clone, rx)
+    });
+    (lobby, Relay {
+        tx: Arc::new(Mutex::new(tx)),
+        pending: pending,
+        heartbeat: Duration::from_secs(config.heartbeat_secs),
+        idle_timeout: Duration::from_secs(config.idle_timeout_secs),
+    })
+}
 
-    for connection in server {
-        let tx = tx.clone();
-        let request = connection.unwrap().read_request().unwrap(); // Get the request
+/// The receiving end of a client's outbox: the messages queued for them, already
+/// encoded in their version of the protocol.
+pub type Queued = mpsc::Receiver<String>;
 
-        request.validate().unwrap(); // Validate the request
+/// Serves a connected client: checks the size and rate of the messages they send,
+/// decodes them, and hands them to the relay thread.
+pub struct Session {
+    addr: String,
+    version: Version,
+    relay: mpsc::Sender<Relayed>,
+    pending: Arc<AtomicUsize>,
+    outbox: Outbox,
+    messages: RateLimit,
+    chats: RateLimit,
+}
+
+impl Relay {
+    /// Connect a client speaking the given version of the protocol, and logged in
+    /// to the given account, if any: hand them to the relay thread, after queuing
+    /// the welcome. `waker` is called whenever a message is queued for them, and
+    /// `closer` to disconnect them. Returns the session to hand the client's
+    /// messages to, and the queue of messages to send them.
+    pub fn connect(&self, addr: String, version: Version, user: Option<String>, waker: Waker,
+                   closer: Closer) -> (Session, Queued) {
+        let (tx, rx) = mpsc::sync_channel(OUTBOX_LEN);
+        let outbox = Outbox{addr: addr.clone(), version: version, tx: tx, closer: closer,
+                            waker: waker};
+        outbox.send_text(protocol::welcome(version));
+        self.relay(Relayed::Connect(addr.clone(), user, outbox.clone()));
+        let session = Session {
+            addr: addr,
+            version: version,
+            relay: self.tx.lock().unwrap().clone(),
+            pending: self.pending.clone(),
+            outbox: outbox,
+            messages: RateLimit::new(MESSAGE_BURST, MESSAGES_PER_SEC),
+            chats: RateLimit::new(CHAT_BURST, CHATS_PER_SEC),
+        };
+        (session, rx)
+    }
+
+    #[cfg(test)]
+    /// Serve a client over a blocking transport, such as the in-memory one, on the
+    /// current thread until they disconnect or go idle, with a writer thread that
+    /// sends them what is queued for them and pings them every heartbeat.
+    pub fn serve(&self, addr: String, version: Version, user: Option<String>,
+                 mut incoming: Box<Incoming>, outgoing: Box<Outgoing>) {
+        incoming.set_idle_timeout(self.idle_timeout);
+        let closer = outgoing.closer();
+        // The writer thread waits on the queue itself, so it needs no waking.
+        let (mut session, queued) = self.connect(addr, version, user, Arc::new(|| {}),
+                                                 closer.clone());
+        let heartbeat = self.heartbeat;
+        thread::spawn(move || writer_thread(outgoing, queued, closer, heartbeat));
+        // Stops once the client disconnects, or is disconnected for being too slow
+        // or for sending nothing within the idle timeout.
+        while let Some(message) = incoming.recv() {
+            session.receive(message);
+        }
+        session.disconnect();
+    }
 
-        let response = request.accept(); // Form a response
+    /// How often clients are pinged.
+    pub fn heartbeat(&self) -> Duration {
+        self.heartbeat
+    }
 
-        let mut client = response.send().unwrap(); // Send the response
+    /// How long a client may send nothing before they are disconnected.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Move every client logged in to the given account into a game.
+    pub fn summon(&self, user: &str, game: u32) {
+        self.relay(Relayed::Summon(String::from(user), game));
+    }
 
-        let ip = client.get_mut_sender()
-                .get_mut()
-                .peer_addr()
-                .unwrap();
+    /// Send an `Action` to every client logged in to one of the given accounts.
+    pub fn notify(&self, users: Vec<String>, action: Action) {
+        self.relay(Relayed::Notify(users, action));
+    }
 
-        let ip_string = format!("{}", ip);
+    /// Disconnect every client connected from the given IP address, or logged in to
+    /// the given account, once they have been banned.
+    pub fn expel(&self, ip: Option<&str>, user: Option<&str>) {
+        self.relay(Relayed::Expel(ip.map(String::from), user.map(String::from)));
+    }
 
-        let ref mut black_ip_mut = *black_ip.lock().unwrap();
-        let ref mut white_ip_mut = *white_ip.lock().unwrap();
+    /// Helper function to hand something to the relay thread, without waiting.
+    fn relay(&self, relayed: Relayed) {
+        self.tx.lock().unwrap().send(relayed).unwrap();
+    }
+}
 
-        // Keep track of the IP of both players.
-        if white_ip_mut.is_empty() {
-            white_ip_mut.push_str(ip_string.as_str());
+#[cfg(test)]
+/// The writer thread of a client over a blocking transport sends the messages
+/// queued in their outbox to their connection, so that only this thread waits on
+/// a slow client, and pings the client every heartbeat. It stops once the client
+/// is gone from every game, or the client stops accepting messages, and then
+/// closes the connection, so the client is cleaned up like any other disconnect.
+fn writer_thread(mut outgoing: Box<Outgoing>, outbox: mpsc::Receiver<String>, closer: Closer,
+                 heartbeat: Duration) {
+    let mut pinged = Instant::now();
+    loop {
+        let wait = heartbeat.checked_sub(pinged.elapsed()).unwrap_or(Duration::from_secs(0));
+        match outbox.recv_timeout(wait) {
+            Ok(text) => {
+                if !outgoing.send(&text) {
+                    break;
+                }
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
         }
-        else if black_ip_mut.is_empty() {
-            black_ip_mut.push_str(ip_string.as_str());
+        if pinged.elapsed() >= heartbeat {
+            if !outgoing.ping() {
+                break;
+            }
+            pinged = Instant::now();
         }
+    }
+    (*closer)();
+}
 
-        let (mut sender, receiver) = client.split();
-        sender.send_message(&Message::text(String::from("Welcome!"))).unwrap();
+/// Helper function to check that a guest may use a nickname: it must be a valid
+/// name, not be the name of an account, and not be used by another client, ignoring
+/// case.
+fn check_nick(nicks: &HashMap<String, String>, accounts: &Accounts, addr: &str, nick: &str)
+              -> Result<(), &'static str> {
+    try!(accounts::check_name(nick));
+    if accounts.exists(nick) {
+        return Err("that nickname belongs to an account")
+    }
+    let lower = nick.to_lowercase();
+    if nicks.iter().any(|(other, taken)| other != addr && taken.to_lowercase() == lower) {
+        return Err("that nickname is taken")
+    }
+    Ok(())
+}
 
-        // Add new client sender to list of clients.
-        let ref mut clients_vec = *clients.lock().unwrap();
-        clients_vec.insert(ip_string.clone(), sender);
+/// Helper function to describe an `Action` from a client for the log, without the
+/// token of an `Action::Resume`.
+fn redacted(action: &Action) -> String {
+    match *action {
+        Action::Resume{ref addr, ..} => format!("Resume {{ addr: {:?} }}", addr),
+        ref action => format!("{:?}", action),
+    }
+}
+
+/// Helper function to pick the next unused guest nickname for a new client.
+fn guest_nick(nicks: &HashMap<String, String>, accounts: &Accounts, next_guest: &mut u32)
+              -> String {
+    loop {
+        let nick = format!("Guest{}", *next_guest);
+        *next_guest += 1;
+        if check_nick(nicks, accounts, "", &nick).is_ok() {
+            return nick
+        }
+    }
+}
 
-        let black_ip_clone = black_ip.clone();
-        let white_ip_clone = white_ip.clone();
-        let board_clone = board.clone();
-        thread::spawn(move || client_thread(board_clone, black_ip_clone, white_ip_clone,
-                                            ip_string.clone(), tx, receiver));
+/// Helper function to tell a client and the game they are in about their new
+/// nickname.
+fn rename(lobby: &Arc<Mutex<Lobby>>, members: &HashMap<String, u32>, outbox: &Outbox,
+          addr: &str, nick: &str) {
+    outbox.send(&Action::Nick{addr: String::new(), nick: String::from(nick)});
+    let room = members.get(addr).and_then(|id| lobby.lock().unwrap().get(*id));
+    if let Some(room) = room {
+        room.push(Command::Rename{addr: String::from(addr), nick: String::from(nick)});
     }
 }
 
-/// The relay thread handles all `Action`s received on its MPSC channel. It contains an `Arc<Mutex<Board>>`
-/// in order to both access board state and modify it if necessary. Since the client threads also require
-/// references to the board state, this must be locked in a Mutex.
+/// Helper function to move a client into a game, leaving the game they were in.
+fn move_to(lobby: &Arc<Mutex<Lobby>>, members: &mut HashMap<String, u32>, addr: &str,
+           nick: &str, user: Option<&String>, outbox: Outbox, id: u32) {
+    let (old, room) = {
+        let lobby = lobby.lock().unwrap();
+        let room = match lobby.get(id) {
+            Some(room) => room,
+            None => return,
+        };
+        (members.get(addr).and_then(|old| lobby.get(*old)), room)
+    };
+    if members.insert(String::from(addr), id) == Some(id) {
+        return;
+    }
+    if let Some(old) = old {
+        old.push(Command::Leave{addr: String::from(addr), release: false});
+    }
+    room.push(Command::Enter{addr: String::from(addr), nick: String::from(nick),
+                             user: user.cloned(), outbox: outbox, connected: false});
+}
+
+/// Helper function to send the open seeks to every client.
+fn broadcast_seeks(lobby: &Arc<Mutex<Lobby>>, outboxes: &HashMap<String, Outbox>) {
+    let action = Action::Seeks{seeks: lobby.lock().unwrap().seeks()};
+    let encoded = Encoded::new(&action);
+    for outbox in outboxes.values() {
+        outbox.send_encoded(&encoded);
+    }
+}
+
+/// The relay thread receives every `Action` from the clients on its bounded MPSC
+/// channel, keeps track of which game each client is in, and hands the action to
+/// the `Room` that owns that game, which sends the results to the clients itself.
+/// It only locks the lobby to find or create a game, and never waits on a client or
+/// a game, so one slow client or one busy game cannot hold up the others: an
+/// `Action` for a game whose queue is full is turned away, and the client is told
+/// that the game is busy.
 ///
-/// * If it receives an `Action::Select`, it will calculate the potential_moves
-/// that can be made by that cell, and then relay an `Action::Moves` to the
-/// sender only. 
-/// * If it receives an `Action::Move`, it will adjust the board state, check for
-/// check and checkmate, switch turns, and then send an `Action::Board` with all
-/// the state to all clients.
-/// * If it receives any other `Action`, it will relay the `Action` verbatim to all clients.
-fn relay_thread(mutex_board: Arc<Mutex<Board>>, clients: Arc<Mutex<HashMap<String, sender::Sender<WebSocketStream>>>>,
-                mpsc_receiver: mpsc::Receiver<String>) {
-    for action_string in mpsc_receiver {
-        println!("{}", action_string);
-        let action: Action = json::decode(action_string.as_str()).unwrap();
-        let new_action;
-        match action {
-            Action::Select{ref addr, ref cell} => {
-                let ref board = *mutex_board.lock().unwrap();
-                let mut cells = board.potential_moves(cell);
-                if !board.is_friendly_board(cell) {
-                    continue;
+/// Other clients only ever see a client's nickname, never their address. Clients
+/// logged in to an account go by their username, and every other client starts out
+/// with a guest nickname, which is sent to them in an `Action::Nick`. A guest who
+/// was using the username of an account that logs in is given a new guest nickname.
+///
+/// * If it receives a new client, it will put the client in the newest unfinished
+/// game, where they take a seat if one is open and are sent an `Action::State`
+/// snapshot of the game, and the rest of the game is sent an `Action::Connect`.
+/// * If a client disconnects, it will take the client out of their game, where
+/// their seat stays reserved for them, and the rest of the game is sent an
+/// `Action::Disconnect`.
+/// * If it is asked to summon an account into a game, it will move every client
+/// logged in to it into that game, and if it is asked to notify some accounts, it
+/// will send the `Action` to every client logged in to one of them.
+/// * If it is asked to expel an address or an account that was just banned, it will
+/// disconnect every client connected from that address or logged in to that account.
+/// * If it receives an `Action::Seek` with valid terms, it will pair it with the
+/// oldest open seek it is compatible with, creating a new game for the pair and
+/// seating both players in it, or leave it open otherwise. Every client is sent
+/// the open seeks in an `Action::Seeks` when they connect and whenever the seeks
+/// change, and a client's seek is closed by an `Action::Unseek` or when they
+/// disconnect.
+/// * If it receives an `Action::Leaderboard` for a category of time control, it
+/// will answer with an `Action::Standings` listing the best players in it.
+/// * If it receives an `Action::Nick` from a guest with a valid nickname that no one
+/// else uses, it will rename the client, confirm the nickname with an `Action::Nick`,
+/// and send the client's game an updated `Action::Presence`.
+/// * If it receives an `Action::Resume` with a token that holds a seat in an unfinished
+/// game, it will move the client into that game and rebind them to their seat. The
+/// games are asked one at a time on a thread of their own, which tells the relay
+/// thread which game the client is now in.
+/// * If it receives an `Action::NewGame` or `Action::Join`, it will move the client
+/// into a new game or the given game as a spectator. A client logged in to an account
+/// is the creator of the games they create.
+/// * Any other `Action` is handed to the client's game.
+fn relay_thread(lobby: Arc<Mutex<Lobby>>, accounts: Arc<Accounts>, ratings: Arc<Ratings>,
+                mpsc_sender: mpsc::Sender<Relayed>, pending: Arc<AtomicUsize>,
+                mpsc_receiver: mpsc::Receiver<Relayed>) {
+    // Which game each client is currently in, the outbox and nickname of each
+    // client, and the account of each client that is logged in.
+    let mut members: HashMap<String, u32> = HashMap::new();
+    let mut outboxes: HashMap<String, Outbox> = HashMap::new();
+    let mut nicks: HashMap<String, String> = HashMap::new();
+    let mut users: HashMap<String, String> = HashMap::new();
+    let mut next_guest = 1;
+    for relayed in mpsc_receiver {
+        let action = match relayed {
+            Relayed::Connect(addr, user, outbox) => {
+                debug!("{} connected as {:?}", addr, user);
+                let nick = match user {
+                    Some(user) => {
+                        let lower = user.to_lowercase();
+                        let guests: Vec<String> = nicks.iter()
+                            .filter(|&(other, nick)| {
+                                !users.contains_key(other) && nick.to_lowercase() == lower
+                            })
+                            .map(|(other, _)| other.clone())
+                            .collect();
+                        for guest in guests {
+                            let nick = guest_nick(&nicks, &accounts, &mut next_guest);
+                            nicks.insert(guest.clone(), nick.clone());
+                            if let Some(outbox) = outboxes.get(&guest) {
+                                rename(&lobby, &members, outbox, &guest, &nick);
+                            }
+                        }
+                        users.insert(addr.clone(), user.clone());
+                        user
+                    },
+                    None => guest_nick(&nicks, &accounts, &mut next_guest),
+                };
+                outbox.send(&Action::Nick{addr: String::new(), nick: nick.clone()});
+                outbox.send(&Action::Seeks{seeks: lobby.lock().unwrap().seeks()});
+                let (id, room) = {
+                    let mut lobby = lobby.lock().unwrap();
+                    let id = lobby.default_game();
+                    (id, lobby.get(id).unwrap())
+                };
+                room.push(Command::Enter{addr: addr.clone(), nick: nick.clone(),
+                                         user: users.get(&addr).cloned(),
+                                         outbox: outbox.clone(), connected: true});
+                members.insert(addr.clone(), id);
+                outboxes.insert(addr.clone(), outbox);
+                nicks.insert(addr, nick);
+                continue;
+            },
+            Relayed::Disconnect(addr) => {
+                debug!("{} disconnected", addr);
+                outboxes.remove(&addr);
+                nicks.remove(&addr);
+                users.remove(&addr);
+                let room = members.remove(&addr).and_then(|id| lobby.lock().unwrap().get(id));
+                if let Some(room) = room {
+                    room.push(Command::Disconnect{addr: addr.clone()});
+                }
+                if lobby.lock().unwrap().cancel(&addr) {
+                    broadcast_seeks(&lobby, &outboxes);
+                }
+                continue;
+            },
+            Relayed::Summon(user, id) => {
+                let addrs: Vec<String> = users.iter()
+                    .filter(|&(_, other)| *other == user)
+                    .map(|(addr, _)| addr.clone())
+                    .collect();
+                for addr in addrs {
+                    if let (Some(outbox), Some(nick)) = (outboxes.get(&addr), nicks.get(&addr)) {
+                        move_to(&lobby, &mut members, &addr, nick, Some(&user), outbox.clone(),
+                                id);
+                    }
                 }
-                cells.retain(|m| !board.self_check(cell.clone(), m.clone()));
-                new_action = Action::Moves{cells: cells};
-                let mut clients_map = clients.lock().unwrap();
-                let message = Message::text(json::encode(&new_action).unwrap());
-                // Relay message to only this client.
-                for (client_addr, client_sender) in &mut *clients_map {
-                    if addr == client_addr {
-                        client_sender.send_message(&message).unwrap();
+                continue;
+            },
+            Relayed::Expel(ip, user) => {
+                let user = user.map(|user| user.to_lowercase());
+                for (addr, outbox) in &outboxes {
+                    let from_ip = ip.as_ref().map_or(false, |ip| *ip == bans::ip_of(addr));
+                    let logged_in = users.get(addr).map_or(false, |other| {
+                        user.as_ref() == Some(&other.to_lowercase())
+                    });
+                    if from_ip || logged_in {
+                        info!("disconnecting {}, who was banned", addr);
+                        outbox.close();
                     }
                 }
                 continue;
             },
-            Action::Move{ref from, ref to} => {
-                // Modify board state and check for check, checkmate.
-                let ref mut board = *mutex_board.lock().unwrap();
-                board.move_piece(from.clone(), to.clone());
-                let check = board.check();
-                let checkmate = board.checkmate();
-                board.switch_color();
-                new_action = Action::Board{board: board.clone(), check: check,
-                                           checkmate: checkmate};
-            },
-            _ => new_action = action,
-        }
-        let mut clients_map = clients.lock().unwrap();
-        let message = Message::text(json::encode(&new_action).unwrap());
-        // relay message to all of the clients
-        for (addr, client_sender) in &mut *clients_map {
-            client_sender.send_message(&message).unwrap();
-        }
-    }
-}
-
-/// Each client thread waits for input (or disconnects) from its respective clients, checks
-/// if the command is a valid given the game state, and if so, relays the appropriate messages
-/// via the relay MPSC channel.
-///
-/// The messages received-from and sent-to the client should be JSON objects with the same
-/// form as rustc_serialize's serialization of the `Action` type.
-///
-/// * If the client connects, a `Action::Connect` will be relayed with their IP address.
-///
-/// * If the client disconnects, a `Action::Disconnect` will be relayed with their IP address.
-///
-/// * If the client sends a `Action::Msg`, the message will be relayed and the user will be adjusted
-/// to Black, White, or Spectator accordingly.
-///
-/// * If the client sends a `Action::Select`, if it is not their turn then this message will be discarded.
-/// Otherwise, the `Action::Select` will be relayed with the selected cell and the client's IP address.
-///
-/// * If the client sends a `Action::Move`, if it is not their turn then this message will be discarded.
-/// Otherwise, the `Action::Move` will be relayed with the from cell and to cell.
-fn client_thread(mutex_board: Arc<Mutex<Board>>, black_ip: Arc<Mutex<String>>,
-                 white_ip: Arc<Mutex<String>>, ip: String, mpsc_sender: mpsc::Sender<String>,
-                 mut client_receiver: receiver::Receiver<WebSocketStream>) {
-
-    // Send connect message to MPSC channel
-    let ca = Action::Connect{addr: ip.clone()};
-    let encoded_ca = json::encode(&ca).unwrap();
-    mpsc_sender.send(encoded_ca).unwrap();
-
-    for message in client_receiver.incoming_messages() {
-        let message: Message = message.unwrap();
-        match message.opcode {
-            // disconnect
-            Type::Close => {
-                let ca = Action::Disconnect{addr: ip.clone()};
-                let encoded_ca = json::encode(&ca).unwrap();
-                mpsc_sender.send(encoded_ca).unwrap();
-            },
-            _ => {
-                // json object with username and message
-                let payload: Payload = json::decode(str::from_utf8(&message.payload).unwrap()).unwrap();
-                let ref board = *mutex_board.lock().unwrap();
-                match payload.variant.as_ref() {
-                    "Select" => {
-                        if !((board.white_turn() && ip == *white_ip.lock().unwrap()) ||
-                                (!board.white_turn() && ip == *black_ip.lock().unwrap())) {
-                            continue;
+            Relayed::Notify(notified, action) => {
+                let encoded = Encoded::new(&action);
+                for (addr, user) in &users {
+                    if notified.contains(user) {
+                        if let Some(outbox) = outboxes.get(addr) {
+                            outbox.send_encoded(&encoded);
                         }
-                        let action = Action::Select{
-                            addr: ip.clone(),
-                            cell: Cell::new(
-                                payload.fields[0].clone().parse::<i32>().unwrap(),
-                                payload.fields[1].clone().parse::<i32>().unwrap()
-                            )
-                        };
-                        let encoded_action = json::encode(&action).unwrap();
-                        mpsc_sender.send(encoded_action).unwrap();
-                    },
-                    "Move" => {
-                        if !((board.white_turn() && ip == *white_ip.lock().unwrap()) ||
-                                (!board.white_turn() && ip == *black_ip.lock().unwrap())) {
-                            continue;
+                    }
+                }
+                continue;
+            },
+            Relayed::Resumed(addr, id) => {
+                let room = lobby.lock().unwrap().get(id);
+                let room = match room {
+                    Some(room) => room,
+                    None => continue,
+                };
+                // The client may have disconnected while their seat was looked up.
+                if !outboxes.contains_key(&addr) {
+                    room.push(Command::Disconnect{addr: addr});
+                    continue;
+                }
+                if let Some(old) = members.insert(addr.clone(), id) {
+                    if old != id {
+                        // Give up the seat handed out on connect.
+                        let old_room = lobby.lock().unwrap().get(old);
+                        if let Some(old_room) = old_room {
+                            old_room.push(Command::Leave{addr: addr, release: true});
                         }
-                        let action = Action::Move{
-                            from: Cell::new(
-                                payload.fields[0].clone().parse::<i32>().unwrap(),
-                                payload.fields[1].clone().parse::<i32>().unwrap()
-                            ),
-                            to: Cell::new(
-                                payload.fields[2].clone().parse::<i32>().unwrap(),
-                                payload.fields[3].clone().parse::<i32>().unwrap()
-                            )
+                    }
+                }
+                continue;
+            },
+            Relayed::Action(action) => {
+                pending.fetch_sub(1, Ordering::SeqCst);
+                action
+            },
+        };
+        debug!("{}", redacted(&action));
+        let nick = match action {
+            Action::Select{ref addr, ..} | Action::Move{ref addr, ..} |
+            Action::Say{ref addr, ..} | Action::TakeSeat{ref addr, ..} |
+            Action::LeaveSeat{ref addr} | Action::Resume{ref addr, ..} |
+            Action::NewGame{ref addr} | Action::Join{ref addr, ..} |
+            Action::Nick{ref addr, ..} | Action::Leaderboard{ref addr, ..} |
+            Action::Seek{ref addr, ..} | Action::Unseek{ref addr} |
+            Action::Berserk{ref addr} | Action::Seal{ref addr, ..} |
+            Action::Unseal{ref addr} | Action::Spectators{ref addr, ..} => {
+                match nicks.get(addr) {
+                    Some(nick) => nick.clone(),
+                    None => continue,
+                }
+            },
+            _ => continue,
+        };
+        match action {
+            Action::Seek{addr, terms} => {
+                let outbox = match outboxes.get(&addr) {
+                    Some(outbox) => outbox.clone(),
+                    None => continue,
+                };
+                let user = users.get(&addr).cloned();
+                if let Err(error) = seeks::check(&terms, user.as_ref().map(|u| u.as_str())) {
+                    outbox.send_error(error);
+                    continue;
+                }
+                let category = ratings::category(terms.initial_ms, terms.increment_ms);
+                let rating = ratings.rating(user.as_ref().map(|u| u.as_str()), category);
+                let seek = Seek{id: 0, addr: addr, nick: nick, user: user, rating: rating,
+                                terms: terms};
+                let paired = {
+                    let mut lobby = lobby.lock().unwrap();
+                    lobby.post(seek).map(|(white, black)| {
+                        let terms = &white.terms;
+                        let id = lobby.create_game(terms.initial_ms, terms.increment_ms,
+                                                   terms.rated);
+                        (id, lobby.get(id).unwrap(), white, black)
+                    })
+                };
+                if let Some((id, room, white, black)) = paired {
+                    info!("paired {} with {} in game {}", white.nick, black.nick, id);
+                    for (seek, color) in vec![(white, Color::White), (black, Color::Black)] {
+                        let outbox = match outboxes.get(&seek.addr) {
+                            Some(outbox) => outbox.clone(),
+                            None => continue,
                         };
-                        let encoded_action = json::encode(&action).unwrap();
-                        mpsc_sender.send(encoded_action).unwrap();
-                    },
-                    "Msg" => {
-                        let user;
-                        if ip == *white_ip.lock().unwrap() {
-                            user = "White";
+                        move_to(&lobby, &mut members, &seek.addr, &seek.nick,
+                                users.get(&seek.addr), outbox, id);
+                        room.push(Command::Client(Action::TakeSeat{addr: seek.addr,
+                                                                   color: color}));
+                    }
+                }
+                broadcast_seeks(&lobby, &outboxes);
+            },
+            Action::Unseek{addr} => {
+                if lobby.lock().unwrap().cancel(&addr) {
+                    broadcast_seeks(&lobby, &outboxes);
+                }
+            },
+            Action::Leaderboard{addr, category} => {
+                let outbox = match outboxes.get(&addr) {
+                    Some(outbox) => outbox,
+                    None => continue,
+                };
+                if !ratings::CATEGORIES.contains(&category.as_str()) {
+                    outbox.send_error(&format!("unknown category {:?}", category));
+                    continue;
+                }
+                let standings = ratings.leaderboard(&category, LEADERBOARD_LEN);
+                outbox.send(&Action::Standings{category: category, standings: standings});
+            },
+            Action::Nick{addr, nick: new} => {
+                let outbox = match outboxes.get(&addr) {
+                    Some(outbox) => outbox.clone(),
+                    None => continue,
+                };
+                let checked = if users.contains_key(&addr) {
+                    Err("players logged in to an account go by their username")
+                } else {
+                    check_nick(&nicks, &accounts, &addr, &new)
+                };
+                if let Err(error) = checked {
+                    outbox.send_error(error);
+                    outbox.send(&Action::Nick{addr: String::new(), nick: nick});
+                    continue;
+                }
+                nicks.insert(addr.clone(), new.clone());
+                rename(&lobby, &members, &outbox, &addr, &new);
+            },
+            Action::Resume{addr, token} => {
+                let outbox = match outboxes.get(&addr) {
+                    Some(outbox) => outbox.clone(),
+                    None => continue,
+                };
+                let rooms = lobby.lock().unwrap().rooms();
+                let user = users.get(&addr).cloned();
+                let relay = mpsc_sender.clone();
+                thread::spawn(move || {
+                    let user = user.as_ref().map(|user| user.as_str());
+                    for (id, room) in rooms {
+                        if !room.is_over() &&
+                            room.resume(&addr, &nick, user, &token, outbox.clone()) {
+                            let _ = relay.send(Relayed::Resumed(addr, id));
+                            return;
                         }
-                        else if ip == *black_ip.lock().unwrap() {
-                            user = "Black";
-                        } else {
-                            user = "Spectator";
+                    }
+                });
+            },
+            Action::NewGame{addr} => {
+                let outbox = match outboxes.get(&addr) {
+                    Some(outbox) => outbox.clone(),
+                    None => continue,
+                };
+                let creator = users.get(&addr).map(|user| user.as_str());
+                let id = lobby.lock().unwrap().create_by(creator);
+                move_to(&lobby, &mut members, &addr, &nick, users.get(&addr), outbox, id);
+            },
+            Action::Join{addr, game} => {
+                let outbox = match outboxes.get(&addr) {
+                    Some(outbox) => outbox.clone(),
+                    None => continue,
+                };
+                move_to(&lobby, &mut members, &addr, &nick, users.get(&addr), outbox, game);
+            },
+            action => {
+                let addr = match action {
+                    Action::Select{ref addr, ..} | Action::Move{ref addr, ..} |
+                    Action::Say{ref addr, ..} | Action::TakeSeat{ref addr, ..} |
+                    Action::LeaveSeat{ref addr} | Action::Berserk{ref addr} |
+                    Action::Seal{ref addr, ..} | Action::Unseal{ref addr} |
+                    Action::Spectators{ref addr, ..} => addr.clone(),
+                    _ => continue,
+                };
+                let room = members.get(&addr).and_then(|id| lobby.lock().unwrap().get(*id));
+                if let Some(room) = room {
+                    if room.try_send(Command::Client(action)).is_err() {
+                        warn!("dropped an action from {}, since their game is busy", addr);
+                        if let Some(outbox) = outboxes.get(&addr) {
+                            outbox.send_error("the game is busy, try again");
                         }
-                        let action = Action::Msg{
-                            user: String::from(user), 
-                            text: payload.fields[1].clone()
-                        };
-                        let encoded_action = json::encode(&action).unwrap();
-                        mpsc_sender.send(encoded_action).unwrap();
-                    },
-                    _ => println!("should not happen"),
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Each session is handed the messages of its client as they arrive, parses the command,
+/// and relays the appropriate messages via the relay MPSC channel tagged with the client's
+/// address. Checking whether a command is valid given the game state is
+/// left to the actor that owns the client's game.
+///
+/// The messages received-from and sent-to the client are decoded and encoded by the
+/// `protocol` module, in the version of the protocol the client chose when connecting.
+/// Messages that cannot be decoded are skipped, and answered with an error in version 2.
+/// So are messages that are too long, and messages beyond the client's rate limits,
+/// which allow a burst of messages and then a steady rate, with a much lower rate
+/// for chat messages.
+///
+/// * If the client disconnects, the relay thread will be told their address.
+///
+/// * If the client sends a `Action::Nick`, it will be relayed so they can be renamed.
+///
+/// * If the client sends a `Action::Seek` or `Action::Unseek`, it will be relayed so
+/// their seek can be posted or closed.
+///
+/// * If the client sends a `Action::Resume` with the token they were given when seated,
+/// it will be relayed so they can get their seat back.
+///
+/// * If the client sends a `Action::NewGame`, or a `Action::Join` with a game id, it will
+/// be relayed so they can move to that game.
+///
+/// * If the client sends a `Action::TakeSeat` with a color, or a `Action::LeaveSeat`, it
+/// will be relayed with the client's IP address.
+///
+/// * If the client sends a `Action::Msg` (`say` in version 2), the message will be relayed
+/// as an `Action::Say`,
+/// so that it can be labeled with the client's nickname, or carried out by the client's
+/// game if it is a chat command such as `/move Nf3`.
+///
+/// * If the client sends a `Action::Berserk`, it will be relayed so the client can halve
+/// their clock.
+///
+/// * If the client sends a `Action::Seal` with a move, or a `Action::Unseal`, it will be
+/// relayed so the game can be adjourned or resumed.
+///
+/// * If the client sends a `Action::Spectators`, it will be relayed so the creator of the
+/// game can change what spectators may do and see.
+///
+/// * If the client sends a `Action::Select`, it will be relayed with the selected cell.
+///
+/// * If the client sends a `Action::Move`, it will be relayed with the from cell and to cell.
+impl Session {
+    /// Handle a message from the client.
+    pub fn receive(&mut self, message: String) {
+        let (addr, outbox) = (&self.addr, &self.outbox);
+        if message.len() > MAX_MESSAGE_LEN {
+            warn!("refused a message of {} bytes from {}", message.len(), addr);
+            outbox.send_error("message too long");
+            return;
+        }
+        if !self.messages.allow() {
+            debug!("dropped a message from {}, which is sending too fast", addr);
+            outbox.send_error("too many messages, slow down");
+            return;
+        }
+        let action = match protocol::decode(self.version, addr, &message) {
+            Ok(Some(Action::Say{addr, text})) => {
+                // Commands are answered only to the client who sent them, so they
+                // are not held to the chat limit, only to the limit on messages.
+                let command = text.trim().starts_with('/');
+                if text.chars().count() > MAX_CHAT_LEN {
+                    outbox.send_error(&format!("chat messages may be at most {} characters long",
+                                               MAX_CHAT_LEN));
+                    return;
+                } else if !command && !self.chats.allow() {
+                    outbox.send_error("too many chat messages, slow down");
+                    return;
+                }
+                Action::Say{addr: addr, text: text}
+            },
+            Ok(Some(action)) => action,
+            // Answers to heartbeats only keep the connection alive.
+            Ok(None) => return,
+            Err(error) => {
+                warn!("bad message {:?} from {}: {}", message, addr, error);
+                outbox.send_error(&error);
+                return;
+            },
+        };
+        // The relay thread only falls behind under a flood, which letting its
+        // queue grow would only make worse.
+        if self.pending.load(Ordering::SeqCst) >= RELAY_LEN {
+            warn!("dropped a message from {}, since the server is busy", addr);
+            outbox.send_error("the server is busy, try again");
+            return;
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.relay.send(Relayed::Action(action)).unwrap();
+    }
+
+    /// Tell the relay thread that the client has disconnected, without waiting.
+    pub fn disconnect(self) {
+        self.relay.send(Relayed::Disconnect(self.addr)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use accounts::Accounts;
+    use config::Config;
+    use protocol::Version;
+    use ratings::Ratings;
+    use rustc_serialize::json::Json;
+    use std::sync::{Arc, mpsc};
+    use std::thread;
+    use std::time::Duration;
+    use storage::tests::scratch_dir;
+
+    /// A client connected to the relay in memory: the channel it sends messages on,
+    /// and the one it is sent messages on.
+    struct FakeClient {
+        to_server: mpsc::Sender<String>,
+        from_server: mpsc::Receiver<String>,
+    }
+
+    impl FakeClient {
+        /// Connect a guest speaking version 2 of the protocol from the given address,
+        /// served on a thread of its own.
+        fn connect(relay: &Relay, addr: &str) -> FakeClient {
+            let (to_server, incoming) = mpsc::channel();
+            let (outgoing, from_server) = mpsc::channel();
+            let (relay, addr) = (relay.clone(), String::from(addr));
+            thread::spawn(move || {
+                relay.serve(addr, Version::V2, None, Box::new(incoming), Box::new(outgoing))
+            });
+            FakeClient{to_server: to_server, from_server: from_server}
+        }
+
+        fn send(&self, text: &str) {
+            self.to_server.send(String::from(text)).unwrap();
+        }
+
+        /// Wait for the next message of the given type, skipping any others.
+        fn expect(&self, kind: &str) -> Json {
+            loop {
+                let text = self.from_server.recv_timeout(Duration::from_secs(5)).unwrap();
+                let message = Json::from_str(&text).unwrap();
+                if message.find("type").and_then(|kind| kind.as_string()) == Some(kind) {
+                    return message
                 }
             }
         }
     }
+
+    /// Helper function to get a string field of a message.
+    fn text<'a>(message: &'a Json, key: &str) -> &'a str {
+        message.find(key).and_then(|value| value.as_string()).unwrap()
+    }
+
+    #[test]
+    fn plays_moves_between_two_clients_in_memory() {
+        let mut config = Config::new();
+        config.store_dir = scratch_dir("plays_moves_between_two_clients_in_memory");
+        let accounts = Arc::new(Accounts::open(&config.store_dir));
+        let ratings = Arc::new(Ratings::open(&config.store_dir));
+        let (_, relay) = start(&config, accounts, ratings);
+
+        let white = FakeClient::connect(&relay, "192.0.2.1:1000");
+        white.expect("welcome");
+        assert_eq!(text(&white.expect("state"), "seat"), "white");
+        let black = FakeClient::connect(&relay, "192.0.2.2:1000");
+        assert_eq!(text(&black.expect("state"), "seat"), "black");
+
+        white.send(r#"{"type": "move", "from": "e2", "to": "e4"}"#);
+        let after_e4 = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b";
+        for client in &[&white, &black] {
+            let board = client.expect("board");
+            assert!(text(&board, "fen").starts_with(after_e4), "{}", board);
+        }
+        // Whether or not it arrives before Black's move, White cannot move a black
+        // pawn, so only Black's move is played.
+        white.send(r#"{"type": "move", "from": "e7", "to": "e5"}"#);
+        black.send(r#"{"type": "move", "from": "e7", "to": "e5"}"#);
+        let after_e5 = "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w";
+        for client in &[&white, &black] {
+            let board = client.expect("board");
+            assert!(text(&board, "fen").starts_with(after_e5), "{}", board);
+        }
+    }
+
+    #[test]
+    fn limits_bursts_until_the_bucket_fills_again() {
+        let mut limit = RateLimit::new(2.0, 10.0);
+        assert!(limit.allow());
+        assert!(limit.allow());
+        assert!(!limit.allow());
+        assert!(!limit.is_full());
+        thread::sleep(Duration::from_millis(250));
+        assert!(limit.is_full());
+        assert!(limit.allow());
+    }
 }
@@ -0,0 +1,211 @@
+//! A module for the chess clock. Each side has a budget of milliseconds that
+//! only drains while it is that side's turn, plus an optional increment that is
+//! added back after every move. In correspondence games, each side instead has
+//! the same time for every move, and their clock is reset once they have moved.
+
+use std::time::{Duration, Instant};
+
+use super::board::Color;
+
+#[derive(Debug, Clone)]
+/// Represents the clocks of both players.
+/// `running` is the side whose time is currently draining, and `since` is the
+/// instant their clock was started. If `per_move` is set, `initial_ms` is the time
+/// each side has for every move.
+pub struct Clock {
+    initial_ms: u64,
+    per_move: bool,
+    white_ms: u64,
+    black_ms: u64,
+    increment_ms: u64,
+    running: Option<Color>,
+    since: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// A snapshot of the clocks that can be sent to clients.
+pub struct ClockState {
+    pub white_ms: u64,
+    pub black_ms: u64,
+    pub running: Option<Color>,
+}
+
+/// Helper function to convert an elapsed time into milliseconds.
+fn millis(instant: &Instant) -> u64 {
+    let elapsed = instant.elapsed();
+    elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+}
+
+impl Clock {
+    /// Initialize both clocks with the same budget. Neither clock runs until the
+    /// first move has been made.
+    pub fn new(initial_ms: u64, increment_ms: u64) -> Clock {
+        Clock {
+            initial_ms: initial_ms,
+            per_move: false,
+            white_ms: initial_ms,
+            black_ms: initial_ms,
+            increment_ms: increment_ms,
+            running: None,
+            since: None,
+        }
+    }
+
+    /// Initialize the clocks of a correspondence game, where each side has `move_ms`
+    /// for every move.
+    pub fn per_move(move_ms: u64) -> Clock {
+        Clock {
+            per_move: true,
+            ..Clock::new(move_ms, 0)
+        }
+    }
+
+    /// Check if this is the clock of a correspondence game.
+    pub fn is_per_move(&self) -> bool {
+        self.per_move
+    }
+
+    /// Get when the running clock runs out, if one is running.
+    pub fn expires(&self) -> Option<Instant> {
+        match (&self.running, &self.since) {
+            (&Some(ref running), &Some(ref since)) => {
+                let budget = match *running {
+                    Color::White => self.white_ms,
+                    Color::Black => self.black_ms,
+                };
+                Some(*since + Duration::from_millis(budget))
+            },
+            _ => None,
+        }
+    }
+
+    /// Get the time control: the initial time and the increment, in milliseconds.
+    pub fn time_control(&self) -> (u64, u64) {
+        (self.initial_ms, self.increment_ms)
+    }
+
+    /// Get the time left for the given side, including the time spent on the
+    /// current move if their clock is running.
+    pub fn remaining(&self, color: &Color) -> u64 {
+        let budget = match *color {
+            Color::White => self.white_ms,
+            Color::Black => self.black_ms,
+        };
+        match (&self.running, &self.since) {
+            (&Some(ref running), &Some(ref since)) if running == color => {
+                budget.saturating_sub(millis(since))
+            },
+            _ => budget,
+        }
+    }
+
+    /// Called after `color` has made a move. Charges them for the time spent,
+    /// adds the increment, and starts the opponent's clock. In correspondence
+    /// games, their clock is reset to the time for a move instead.
+    pub fn press(&mut self, color: &Color) {
+        let left = if self.per_move {
+            self.initial_ms
+        } else {
+            self.remaining(color) + self.increment_ms
+        };
+        let opponent = match *color {
+            Color::White => {
+                self.white_ms = left;
+                Color::Black
+            },
+            Color::Black => {
+                self.black_ms = left;
+                Color::White
+            },
+        };
+        self.running = Some(opponent);
+        self.since = Some(Instant::now());
+    }
+
+    /// Overwrite the time left for the given side, e.g. when restoring a game
+    /// from storage.
+    pub fn set_remaining(&mut self, color: &Color, ms: u64) {
+        match *color {
+            Color::White => self.white_ms = ms,
+            Color::Black => self.black_ms = ms,
+        }
+    }
+
+    /// Halve the time the given side started with, for a player who goes berserk.
+    pub fn halve(&mut self, color: &Color) {
+        let ms = self.initial_ms / 2;
+        self.set_remaining(color, ms);
+    }
+
+    /// Start the clock of the given side, e.g. when an adjourned game is resumed.
+    pub fn start(&mut self, color: &Color) {
+        self.running = Some(color.clone());
+        self.since = Some(Instant::now());
+    }
+
+    /// Stop both clocks, e.g. when the game is over.
+    pub fn stop(&mut self) {
+        self.white_ms = self.remaining(&Color::White);
+        self.black_ms = self.remaining(&Color::Black);
+        self.running = None;
+        self.since = None;
+    }
+
+    /// Take a snapshot of the clocks to send to clients.
+    pub fn state(&self) -> ClockState {
+        ClockState {
+            white_ms: self.remaining(&Color::White),
+            black_ms: self.remaining(&Color::Black),
+            running: self.running.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::Color;
+
+    #[test]
+    fn runs_only_after_the_first_move() {
+        let mut clock = Clock::new(60_000, 2_000);
+        assert!(clock.expires().is_none());
+        clock.press(&Color::White);
+        assert!(clock.expires().is_some());
+        assert_eq!(clock.state().running, Some(Color::Black));
+        // White is given the increment, and Black's time is draining.
+        assert!(clock.remaining(&Color::White) >= 61_900);
+        assert!(clock.remaining(&Color::Black) <= 60_000);
+    }
+
+    #[test]
+    fn expires_when_the_running_side_is_out_of_time() {
+        let mut clock = Clock::new(60_000, 0);
+        clock.press(&Color::White);
+        clock.set_remaining(&Color::Black, 0);
+        assert!(clock.expires().unwrap() <= Instant::now());
+        assert_eq!(clock.remaining(&Color::Black), 0);
+    }
+
+    #[test]
+    fn resets_per_move_clocks() {
+        let day = 24 * 60 * 60 * 1000;
+        let mut clock = Clock::per_move(3 * day);
+        assert!(clock.is_per_move());
+        clock.press(&Color::White);
+        clock.set_remaining(&Color::Black, day);
+        clock.press(&Color::Black);
+        assert_eq!(clock.remaining(&Color::Black), 3 * day);
+    }
+
+    #[test]
+    fn stops_and_restarts() {
+        let mut clock = Clock::new(60_000, 0);
+        clock.press(&Color::White);
+        clock.stop();
+        assert!(clock.expires().is_none());
+        clock.start(&Color::Black);
+        assert_eq!(clock.state().running, Some(Color::Black));
+    }
+}
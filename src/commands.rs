@@ -0,0 +1,270 @@
+//! A module for the commands that can be typed in the chat, so that a game can be
+//! played entirely from the keyboard, or with a screen reader. A chat message that
+//! starts with a slash is a command, and is answered only to the client who sent
+//! it instead of being relayed to the game:
+//!
+//! * `/move e2e4` or `/move Nf3` plays a move, given as a coordinate move or in
+//! Standard Algebraic Notation, in either case. Pawns are always promoted to a
+//! queen, and castling is not supported.
+//! * `/resign` resigns the game.
+//! * `/draw` offers a draw, or accepts the opponent's offer.
+//! * `/flip` turns the board around, and shows it as text.
+//! * `/fen` and `/pgn` show the position and the game.
+//! * `/who` shows who is playing and watching, and whose turn it is.
+//! * `/help` lists the commands.
+//!
+//! The creator of a game, and the admins of the server, may also moderate it:
+//!
+//! * `/mute nick` stops a client from chatting in the game, and `/unmute nick` lets
+//! them chat again.
+//! * `/kick nick` disconnects a client and keeps them out of the game.
+
+use super::board::{Board, Cell};
+
+/// The ways of writing castling, which is not implemented.
+const CASTLING: [&'static str; 2] = ["O-O", "O-O-O"];
+
+/// The answer to `/help`.
+pub const HELP: &'static str = "/move e2e4 or /move Nf3 plays a move, /resign resigns, \
+                                /draw offers or accepts a draw, /flip turns the board around, \
+                                /fen and /pgn show the game, /who shows who is here; \
+                                the creator of the game may /mute, /unmute or /kick a nick";
+
+/// Represents a command typed in the chat.
+pub enum Slash {
+    Move(String),
+    Resign,
+    Draw,
+    Flip,
+    Fen,
+    Pgn,
+    Who,
+    Help,
+    Mute(String),
+    Unmute(String),
+    Kick(String),
+}
+
+/// Parse a chat message. Returns None if it is not a command, and the command or
+/// why it could not be understood otherwise.
+pub fn parse(text: &str) -> Option<Result<Slash, String>> {
+    let text = text.trim();
+    if !text.starts_with('/') {
+        return None
+    }
+    let mut words = text[1..].split_whitespace();
+    let name = words.next().unwrap_or("").to_lowercase();
+    let argument = words.next();
+    let command = match (name.as_ref(), argument) {
+        ("move", Some(san)) | ("m", Some(san)) => Ok(Slash::Move(String::from(san))),
+        ("move", None) | ("m", None) => Err(String::from("usage: /move e2e4 or /move Nf3")),
+        ("resign", _) => Ok(Slash::Resign),
+        ("draw", _) => Ok(Slash::Draw),
+        ("flip", _) => Ok(Slash::Flip),
+        ("fen", _) => Ok(Slash::Fen),
+        ("pgn", _) => Ok(Slash::Pgn),
+        ("who", _) => Ok(Slash::Who),
+        ("help", _) => Ok(Slash::Help),
+        ("mute", Some(nick)) => Ok(Slash::Mute(String::from(nick))),
+        ("unmute", Some(nick)) => Ok(Slash::Unmute(String::from(nick))),
+        ("kick", Some(nick)) => Ok(Slash::Kick(String::from(nick))),
+        ("mute", None) | ("unmute", None) | ("kick", None) => {
+            Err(format!("usage: /{} nick", name))
+        },
+        _ => Err(format!("unknown command /{}, try /help", name)),
+    };
+    Some(command)
+}
+
+/// Helper function to strip the annotations that may follow a move in SAN: check,
+/// checkmate, and comments such as "!?".
+fn strip(san: &str) -> &str {
+    san.trim_right_matches(|c| c == '+' || c == '#' || c == '!' || c == '?')
+}
+
+/// Helper function to split the choice of promotion off a move, such as the "=Q"
+/// of "e8=Q", or the piece letter after the last square of "e8Q" or "e7e8q". The
+/// piece is returned in uppercase.
+fn split_promotion(text: &str) -> (&str, Option<String>) {
+    if let Some(i) = text.find('=') {
+        return (&text[..i], Some(text[i + 1..].to_uppercase()))
+    }
+    let mut chars = text.chars().rev();
+    match (chars.next(), chars.next()) {
+        (Some(piece), Some(rank)) if rank.is_digit(10) && "QRBNqrbn".contains(piece) => {
+            (&text[..text.len() - 1], Some(piece.to_uppercase().collect()))
+        },
+        _ => (text, None),
+    }
+}
+
+/// Helper function to get the piece and the square a piece move in SAN goes to,
+/// in either case, leaving out which piece it is and whether it captures, e.g.
+/// ('N', "d2") for "Nbd2". Returns None for anything else, such as a pawn move:
+/// a lowercase b is a pawn on the b-file rather than a bishop.
+fn piece_and_square(san: &str) -> Option<(char, String)> {
+    let chars: Vec<char> = san.to_lowercase().chars().collect();
+    if chars.len() < 3 {
+        return None
+    }
+    let piece = match san.chars().next() {
+        Some('K') | Some('k') => 'K',
+        Some('Q') | Some('q') => 'Q',
+        Some('R') | Some('r') => 'R',
+        Some('B') => 'B',
+        Some('N') | Some('n') => 'N',
+        _ => return None,
+    };
+    let square: String = chars[chars.len() - 2..].iter().cloned().collect();
+    Cell::from_notation(&square).map(|_| (piece, square))
+}
+
+/// Find the legal move of the side to move that a coordinate move, such as
+/// "e2e4" or "e7e8q", or a move in Standard Algebraic Notation, such as "Nf3" or
+/// "e8=Q", stands for, in either case. Returns why it could not be played
+/// otherwise. Castling is not implemented, and pawns are always promoted to a
+/// queen, so the other pieces are refused rather than ignored.
+pub fn find_move(board: &Board, text: &str) -> Result<(Cell, Cell), String> {
+    let entered = text.trim();
+    let text = strip(entered);
+    if CASTLING.contains(&text.to_uppercase().replace('0', "O").as_str()) {
+        return Err(String::from("castling is not supported"))
+    }
+    let (text, promotion) = split_promotion(text);
+    match promotion.as_ref().map(|piece| piece.as_ref()) {
+        None | Some("Q") => {},
+        Some(_) => return Err(String::from("pawns can only be promoted to a queen")),
+    }
+    // A promotion must be asked for on a move that promotes, but may be left out.
+    let promotes = |from: &Cell, to: &Cell| promotion.is_none() || board.is_promotion(from, to);
+    let lowercase = text.to_lowercase();
+    if lowercase.len() == 4 && lowercase.chars().count() == 4 {
+        if let (Some(from), Some(to)) = (Cell::from_notation(&lowercase[..2]),
+                                         Cell::from_notation(&lowercase[2..])) {
+            if board.is_legal(&from, &to) && promotes(&from, &to) {
+                return Ok((from, to))
+            }
+        }
+    }
+    let moves: Vec<(Cell, Cell, String)> = board.all_legal_moves().into_iter()
+        .filter(|&(ref from, ref to)| promotes(from, to))
+        .map(|(from, to)| {
+            let san = board.san(&from, &to);
+            let san = String::from(split_promotion(strip(&san)).0);
+            (from, to, san)
+        }).collect();
+    // The case matters when it tells a pawn on the b-file from a bishop, as in
+    // "bxc3" and "Bxc3", so an exact match is preferred.
+    if let Some(&(ref from, ref to, _)) = moves.iter().find(|m| m.2 == text) {
+        return Ok((from.clone(), to.clone()))
+    }
+    let mut matches = moves.iter().filter(|m| m.2.to_lowercase() == lowercase);
+    match (matches.next(), matches.next()) {
+        (Some(&(ref from, ref to, _)), None) => return Ok((from.clone(), to.clone())),
+        (Some(_), Some(_)) => return Err(format!("{} is ambiguous", entered)),
+        (None, _) => {},
+    }
+    // A piece move may leave out which of two pieces moves, as in "Nd2" when
+    // either knight can go there, or say so when it need not, as in "Ngf3".
+    let wanted = match piece_and_square(text) {
+        Some(wanted) => wanted,
+        None => return Err(format!("{} is not a legal move", entered)),
+    };
+    let mut matches = moves.iter().filter(|m| piece_and_square(&m.2).as_ref() == Some(&wanted));
+    match (matches.next(), matches.next()) {
+        (Some(&(ref from, ref to, _)), None) => Ok((from.clone(), to.clone())),
+        (Some(_), Some(_)) => Err(format!("{} is ambiguous", entered)),
+        (None, _) => Err(format!("{} is not a legal move", entered)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::{Board, Cell};
+
+    const START: &'static str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w";
+
+    /// Helper function to find a move in a position, as a coordinate move.
+    fn found(fen: &str, text: &str) -> Result<String, String> {
+        let board = Board::from_fen(fen).unwrap();
+        find_move(&board, text).map(|(from, to)| from.notation() + &to.notation())
+    }
+
+    #[test]
+    fn finds_moves_in_either_notation_and_case() {
+        let knights = "k7/8/8/8/8/5N2/8/1N5K w";
+        let rooks = "k7/8/8/8/8/4R3/8/4R2K w";
+        let captures = "rnbqkbnr/ppp1pppp/8/3p4/4P3/8/PPPP1PPP/RNBQKBNR w";
+        let pawn_or_bishop = "k7/8/8/8/8/2n5/1P6/4B2K w";
+        let mate = "rnbqkbnr/pppp1ppp/8/4p3/6P1/5P2/PPPPP2P/RNBQKBNR b";
+        let promotion = "k7/4P3/8/8/8/8/8/7K w";
+        let table = [
+            (START, "e2e4", "e2e4"),
+            (START, "E2E4", "e2e4"),
+            (START, "Nf3", "g1f3"),
+            (START, "nf3", "g1f3"),
+            (START, "NF3", "g1f3"),
+            (knights, "Nbd2", "b1d2"),
+            (knights, "Nfd2", "f3d2"),
+            (knights, "nbd2", "b1d2"),
+            (START, "Ngf3", "g1f3"),
+            (START, "nxf3", "g1f3"),
+            (rooks, "R1e2", "e1e2"),
+            (rooks, "R3e2", "e3e2"),
+            (rooks, "r1e2", "e1e2"),
+            (captures, "exd5", "e4d5"),
+            (captures, "EXD5", "e4d5"),
+            (pawn_or_bishop, "bxc3", "b2c3"),
+            (pawn_or_bishop, "Bxc3", "e1c3"),
+            (mate, "Qh4#", "d8h4"),
+            (mate, "Qh4+", "d8h4"),
+            (mate, "Qh4", "d8h4"),
+            (mate, "qh4#", "d8h4"),
+            (mate, "Qh4!?", "d8h4"),
+            (promotion, "e8", "e7e8"),
+            (promotion, "e8=Q", "e7e8"),
+            (promotion, "e8=q+", "e7e8"),
+            (promotion, "e8Q", "e7e8"),
+            (promotion, "e7e8", "e7e8"),
+            (promotion, "e7e8q", "e7e8"),
+            (promotion, "E7E8Q", "e7e8"),
+        ];
+        for &(fen, text, expected) in table.iter() {
+            assert_eq!(found(fen, text), Ok(String::from(expected)), "{} in {}", text, fen);
+        }
+    }
+
+    #[test]
+    fn explains_the_moves_it_refuses() {
+        let knights = "k7/8/8/8/8/5N2/8/1N5K w";
+        let pawn_or_bishop = "k7/8/8/8/8/2n5/1P6/4B2K w";
+        let promotion = "k7/4P3/8/8/8/8/8/7K w";
+        let table = [
+            (START, "e2e5", "e2e5 is not a legal move"),
+            (START, "Nf6", "Nf6 is not a legal move"),
+            (START, "e4=Q", "e4=Q is not a legal move"),
+            (START, "O-O", "castling is not supported"),
+            (START, "0-0-0", "castling is not supported"),
+            (START, "o-o+", "castling is not supported"),
+            (knights, "Nd2", "Nd2 is ambiguous"),
+            (knights, "nd2", "nd2 is ambiguous"),
+            (knights, "Nd3", "Nd3 is not a legal move"),
+            (pawn_or_bishop, "BXC3", "BXC3 is ambiguous"),
+            (promotion, "e8=N", "pawns can only be promoted to a queen"),
+            (promotion, "e7e8n", "pawns can only be promoted to a queen"),
+        ];
+        for &(fen, text, expected) in table.iter() {
+            assert_eq!(found(fen, text), Err(String::from(expected)), "{} in {}", text, fen);
+        }
+    }
+
+    #[test]
+    fn promotes_pawns_to_a_queen() {
+        let mut board = Board::from_fen("k7/4P3/8/8/8/8/8/7K w").unwrap();
+        let (from, to) = (Cell::from_notation("e7").unwrap(), Cell::from_notation("e8").unwrap());
+        assert_eq!(board.san(&from, &to), "e8=Q+");
+        board.move_piece(from, to);
+        assert!(board.fen().starts_with("k3Q3/8/"));
+    }
+}
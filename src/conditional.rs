@@ -0,0 +1,172 @@
+//! A module for conditional moves in correspondence games. A player registers
+//! lines of moves, each starting with a move they expect their opponent to play
+//! and alternating with their replies. The lines are merged into a tree, and
+//! whenever the opponent plays a move, the tree is followed: if the move was
+//! foreseen, the reply is played for the player and the rest of the tree is kept,
+//! and otherwise the whole tree is discarded.
+
+use super::board::Board;
+use super::game::Move;
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a reply to play if the opponent plays `opponent`, along with the
+/// conditional moves that follow it.
+pub struct Branch {
+    pub opponent: Move,
+    pub reply: Move,
+    pub then: Vec<Branch>,
+}
+
+/// Helper function to check if two moves are the same.
+fn same(a: &Move, b: &Move) -> bool {
+    a.from == b.from && a.to == b.to
+}
+
+/// Helper function to add a line of moves to a tree. A line may share the start
+/// of another line, but may not reply differently to the same move.
+fn insert(tree: &mut Vec<Branch>, line: &[Move]) -> Result<(), &'static str> {
+    if line.is_empty() {
+        return Ok(())
+    }
+    let (opponent, reply) = (&line[0], &line[1]);
+    if let Some(branch) = tree.iter_mut().find(|branch| same(&branch.opponent, opponent)) {
+        if !same(&branch.reply, reply) {
+            return Err("lines reply differently to the same move")
+        }
+        return insert(&mut branch.then, &line[2..])
+    }
+    let mut branch = Branch{opponent: opponent.clone(), reply: reply.clone(), then: Vec::new()};
+    try!(insert(&mut branch.then, &line[2..]));
+    tree.push(branch);
+    Ok(())
+}
+
+/// Build a tree of conditional moves from lines of moves, for the player who is
+/// waiting for their opponent to move in the given position. Every line must have
+/// a reply to each of its opponent's moves, and be legal from the position.
+pub fn build(board: &Board, lines: &[Vec<Move>]) -> Result<Vec<Branch>, &'static str> {
+    let mut tree = Vec::new();
+    for line in lines {
+        if line.is_empty() || line.len() % 2 != 0 {
+            return Err("every move in a line needs a reply")
+        }
+        let mut board = board.clone();
+        for m in line {
+            if !board.is_legal(&m.from, &m.to) {
+                return Err("illegal move in a line")
+            }
+            board.move_piece(m.from.clone(), m.to.clone());
+            board.switch_color();
+        }
+        try!(insert(&mut tree, line));
+    }
+    Ok(tree)
+}
+
+/// Follow a tree after the opponent has played a move. Returns the reply to play
+/// and the rest of the tree, or None if the move was not foreseen.
+pub fn follow(tree: Vec<Branch>, played: &Move) -> Option<(Move, Vec<Branch>)> {
+    tree.into_iter()
+        .find(|branch| same(&branch.opponent, played))
+        .map(|branch| (branch.reply, branch.then))
+}
+
+/// Get the lines of a tree back, one for each of its leaves.
+pub fn lines(tree: &[Branch]) -> Vec<Vec<Move>> {
+    let mut all = Vec::new();
+    for branch in tree {
+        let start = vec![branch.opponent.clone(), branch.reply.clone()];
+        if branch.then.is_empty() {
+            all.push(start);
+            continue;
+        }
+        for rest in lines(&branch.then) {
+            let mut line = start.clone();
+            line.extend(rest);
+            all.push(line);
+        }
+    }
+    all
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::{Board, Cell};
+    use game::Move;
+
+    /// Helper function to parse a coordinate move such as "e2e4".
+    fn mv(coordinate: &str) -> Move {
+        Move {
+            from: Cell::from_notation(&coordinate[..2]).unwrap(),
+            to: Cell::from_notation(&coordinate[2..]).unwrap(),
+        }
+    }
+
+    /// Helper function to parse lines of coordinate moves.
+    fn parse(lines: &[&[&str]]) -> Vec<Vec<Move>> {
+        lines.iter().map(|line| line.iter().map(|m| mv(m)).collect()).collect()
+    }
+
+    #[test]
+    fn rejects_odd_and_empty_lines() {
+        let board = Board::new();
+        assert!(build(&board, &parse(&[&["e2e4"]])).is_err());
+        assert!(build(&board, &parse(&[&["e2e4", "e7e5", "g1f3"]])).is_err());
+        assert!(build(&board, &[Vec::new()]).is_err());
+    }
+
+    #[test]
+    fn rejects_illegal_moves() {
+        let board = Board::new();
+        // The opponent's move is illegal.
+        assert!(build(&board, &parse(&[&["e2e5", "e7e5"]])).is_err());
+        // The reply is illegal.
+        assert!(build(&board, &parse(&[&["e2e4", "e7e4"]])).is_err());
+        // A later move is only illegal after the earlier ones.
+        assert!(build(&board, &parse(&[&["e2e4", "e7e5", "e4e5", "d7d6"]])).is_err());
+    }
+
+    #[test]
+    fn merges_lines_with_a_common_start() {
+        let board = Board::new();
+        let given = parse(&[&["e2e4", "e7e5", "g1f3", "b8c6"],
+                             &["e2e4", "e7e5", "f1c4", "g8f6"],
+                             &["d2d4", "d7d5"]]);
+        let tree = build(&board, &given).unwrap();
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[0].then.len(), 2);
+        assert!(tree[1].then.is_empty());
+        assert_eq!(lines(&tree).len(), 3);
+    }
+
+    #[test]
+    fn rejects_different_replies_to_the_same_move() {
+        let board = Board::new();
+        let given = parse(&[&["e2e4", "e7e5"], &["e2e4", "c7c5"]]);
+        assert!(build(&board, &given).is_err());
+    }
+
+    #[test]
+    fn follows_a_foreseen_move() {
+        let board = Board::new();
+        let given = parse(&[&["e2e4", "e7e5", "g1f3", "b8c6"], &["d2d4", "d7d5"]]);
+        let tree = build(&board, &given).unwrap();
+        let (reply, rest) = follow(tree, &mv("e2e4")).unwrap();
+        assert!(same(&reply, &mv("e7e5")));
+        assert_eq!(rest.len(), 1);
+        let (reply, rest) = follow(rest, &mv("g1f3")).unwrap();
+        assert!(same(&reply, &mv("b8c6")));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn discards_the_tree_after_an_unexpected_move() {
+        let board = Board::new();
+        let tree = build(&board, &parse(&[&["e2e4", "e7e5", "g1f3", "b8c6"]])).unwrap();
+        assert!(follow(tree.clone(), &mv("c2c4")).is_none());
+        let (_, rest) = follow(tree, &mv("e2e4")).unwrap();
+        assert!(follow(rest, &mv("f1c4")).is_none());
+    }
+}
@@ -0,0 +1,379 @@
+//! A module for the configuration of the server. Every setting has a default,
+//! which can be overridden by an optional TOML config file, which can in turn be
+//! overridden by command-line flags.
+//!
+//! An example config file, with every setting at its default:
+//!
+//! ```toml
+//! addr = "0.0.0.0:1980"
+//! static_root = "html/"
+//! time_control = "10+0"
+//! store_dir = "games"
+//! log_level = "info"
+//! heartbeat = 20
+//! idle_timeout = 60
+//! abandon_grace = 60
+//! first_move_timeout = 60
+//! abandon = "forfeit"
+//! ```
+//!
+//! The server pings every client each `heartbeat` seconds, and drops a client that
+//! has sent nothing, not even an answer to a ping, for `idle_timeout` seconds.
+//!
+//! A game is abandoned once a seated player has been disconnected for
+//! `abandon_grace` seconds, or a player has not played their first move within
+//! `first_move_timeout` seconds of both seats being taken; either can be set to 0
+//! for no limit. Abandoned games are aborted before both players have moved. After
+//! that, `abandon = "forfeit"` awards the game to the opponent, and
+//! `abandon = "abort"` aborts it.
+//!
+//! To also accept clients speaking version 2 of the protocol over plain TCP, one
+//! message per line, give an address for them:
+//!
+//! ```toml
+//! line_addr = "0.0.0.0:1982"
+//! ```
+//!
+//! To serve over https and wss, also give a PEM certificate and key. A
+//! self-signed pair for local testing can be made with
+//! `openssl req -x509 -newkey rsa:2048 -nodes -subj /CN=localhost -keyout key.pem -out cert.pem`.
+//!
+//! ```toml
+//! tls_cert = "cert.pem"
+//! tls_key = "key.pem"
+//! ```
+//!
+//! Admins are accounts that may ban addresses and accounts through the API, and
+//! mute or kick anyone in any game, as the creator of a game can in their own:
+//!
+//! ```toml
+//! admins = ["alice", "bob"]
+//! ```
+
+use getopts::{Matches, Options};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+use toml;
+
+#[derive(Debug, Clone)]
+/// Represents the settings of the server.
+/// The time control is stored in milliseconds, and is given as "minutes+seconds",
+/// e.g. "5+3" for five minutes with a three second increment.
+pub struct Config {
+    pub addr: String,
+    pub line_addr: Option<String>,
+    pub static_root: String,
+    pub initial_ms: u64,
+    pub increment_ms: u64,
+    pub store_dir: String,
+    pub log_level: String,
+    pub heartbeat_secs: u64,
+    pub idle_timeout_secs: u64,
+    pub abandon_grace_secs: Option<u64>,
+    pub first_move_secs: Option<u64>,
+    pub forfeit: bool,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub replay: Option<String>,
+    pub admins: Vec<String>,
+}
+
+#[derive(Debug, RustcDecodable)]
+/// Represents the settings found in a config file, all of which are optional.
+struct FileConfig {
+    addr: Option<String>,
+    line_addr: Option<String>,
+    static_root: Option<String>,
+    time_control: Option<String>,
+    store_dir: Option<String>,
+    log_level: Option<String>,
+    heartbeat: Option<u64>,
+    idle_timeout: Option<u64>,
+    abandon_grace: Option<u64>,
+    first_move_timeout: Option<u64>,
+    abandon: Option<String>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    admins: Option<Vec<String>>,
+}
+
+/// Helper function to parse a time control such as "10+5" into the initial time
+/// and the increment, in milliseconds.
+pub fn parse_time_control(time_control: &str) -> Option<(u64, u64)> {
+    let mut parts = time_control.splitn(2, '+');
+    let minutes = parts.next().and_then(|m| m.trim().parse::<u64>().ok());
+    let seconds = parts.next().unwrap_or("0").trim().parse::<u64>().ok();
+    match (minutes, seconds) {
+        (Some(minutes), Some(seconds)) => Some((minutes * 60 * 1000, seconds * 1000)),
+        _ => None,
+    }
+}
+
+/// Helper function to turn a number of seconds into a limit, where 0 means none.
+fn limit(secs: u64) -> Option<u64> {
+    if secs == 0 { None } else { Some(secs) }
+}
+
+/// Helper function to parse an abandonment policy into whether abandoned games
+/// are forfeited.
+fn parse_abandon(abandon: &str) -> Option<bool> {
+    match abandon {
+        "forfeit" => Some(true),
+        "abort" => Some(false),
+        _ => None,
+    }
+}
+
+/// Helper function to get the usage.
+fn usage(opts: &Options) -> String {
+    opts.usage("Usage: chess [options]\n       chess [options] replay <log>")
+}
+
+/// Helper function to print an error along with the usage to stderr, and exit
+/// with a failure.
+fn exit_with(error: &str, opts: &Options) -> ! {
+    let _ = writeln!(io::stderr(), "{}\n{}", error, usage(opts));
+    process::exit(1);
+}
+
+/// Helper function to read a flag given in whole seconds, exiting if it is not
+/// a number.
+fn secs_flag(matches: &Matches, name: &str, opts: &Options) -> Option<u64> {
+    matches.opt_str(name).map(|secs| match secs.parse() {
+        Ok(secs) => secs,
+        Err(_) => exit_with(&format!("invalid {} {:?}", name, secs), opts),
+    })
+}
+
+impl Config {
+    /// Get the default settings.
+    pub fn new() -> Config {
+        Config {
+            addr: String::from("0.0.0.0:1980"),
+            line_addr: None,
+            static_root: String::from("html/"),
+            initial_ms: 10 * 60 * 1000,
+            increment_ms: 0,
+            store_dir: String::from("games"),
+            log_level: String::from("info"),
+            heartbeat_secs: 20,
+            idle_timeout_secs: 60,
+            abandon_grace_secs: Some(60),
+            first_move_secs: Some(60),
+            forfeit: true,
+            tls_cert: None,
+            tls_key: None,
+            replay: None,
+            admins: Vec::new(),
+        }
+    }
+
+    /// Helper function to set the time control from a "minutes+seconds" string.
+    fn set_time_control(&mut self, time_control: &str) -> bool {
+        match parse_time_control(time_control) {
+            Some((initial_ms, increment_ms)) => {
+                self.initial_ms = initial_ms;
+                self.increment_ms = increment_ms;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Helper function to apply the settings found in a config file.
+    fn apply_file(&mut self, path: &str) -> Result<(), String> {
+        let mut contents = String::new();
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            return Err(format!("could not read config {}: {}", path, e))
+        }
+        let file: FileConfig = match toml::decode_str(&contents) {
+            Some(file) => file,
+            None => return Err(format!("could not parse config {}", path)),
+        };
+        if let Some(addr) = file.addr {
+            self.addr = addr;
+        }
+        if file.line_addr.is_some() {
+            self.line_addr = file.line_addr;
+        }
+        if let Some(static_root) = file.static_root {
+            self.static_root = static_root;
+        }
+        if let Some(time_control) = file.time_control {
+            if !self.set_time_control(&time_control) {
+                return Err(format!("invalid time control {:?}", time_control))
+            }
+        }
+        if let Some(store_dir) = file.store_dir {
+            self.store_dir = store_dir;
+        }
+        if let Some(log_level) = file.log_level {
+            self.log_level = log_level;
+        }
+        if let Some(heartbeat) = file.heartbeat {
+            self.heartbeat_secs = heartbeat;
+        }
+        if let Some(idle_timeout) = file.idle_timeout {
+            self.idle_timeout_secs = idle_timeout;
+        }
+        if let Some(abandon_grace) = file.abandon_grace {
+            self.abandon_grace_secs = limit(abandon_grace);
+        }
+        if let Some(first_move_timeout) = file.first_move_timeout {
+            self.first_move_secs = limit(first_move_timeout);
+        }
+        if let Some(abandon) = file.abandon {
+            match parse_abandon(&abandon) {
+                Some(forfeit) => self.forfeit = forfeit,
+                None => return Err(format!("invalid abandonment policy {:?}", abandon)),
+            }
+        }
+        if file.tls_cert.is_some() {
+            self.tls_cert = file.tls_cert;
+        }
+        if file.tls_key.is_some() {
+            self.tls_key = file.tls_key;
+        }
+        if let Some(admins) = file.admins {
+            self.admins = admins;
+        }
+        Ok(())
+    }
+
+    /// Build the settings from the command-line arguments, reading the config file
+    /// given by `--config` if there is one. Prints the usage and exits if `--help`
+    /// is given, or prints it to stderr along with the error and exits with a
+    /// failure if the arguments are invalid.
+    pub fn from_args(args: &[String]) -> Config {
+        let mut opts = Options::new();
+        opts.optopt("c", "config", "read settings from a TOML file", "FILE");
+        opts.optopt("a", "addr", "address to serve the page, API and WebSocket on", "ADDR");
+        opts.optopt("", "line-addr", "address to accept line protocol clients on", "ADDR");
+        opts.optopt("", "static-root", "directory of the page to serve", "DIR");
+        opts.optopt("t", "time-control", "time control of new games, e.g. 5+3", "MIN+SEC");
+        opts.optopt("", "store-dir", "directory to store game logs and accounts in", "DIR");
+        opts.optopt("l", "log-level", "one of error, warn, info, debug, trace", "LEVEL");
+        opts.optopt("", "heartbeat", "seconds between pings to each client", "SECS");
+        opts.optopt("", "idle-timeout", "seconds of silence before a client is dropped", "SECS");
+        opts.optopt("", "abandon-grace", "seconds a player may be disconnected, 0 for no limit",
+                    "SECS");
+        opts.optopt("", "first-move-timeout", "seconds to play a first move, 0 for no limit",
+                    "SECS");
+        opts.optopt("", "abandon", "what abandoned games become: forfeit or abort", "POLICY");
+        opts.optopt("", "tls-cert", "PEM certificate to serve https and wss with", "FILE");
+        opts.optopt("", "tls-key", "PEM private key of the certificate", "FILE");
+        opts.optmulti("", "admin", "account that may ban, mute and kick; may be repeated",
+                      "NAME");
+        opts.optflag("h", "help", "print this help");
+
+        let matches = match opts.parse(&args[1..]) {
+            Ok(matches) => matches,
+            Err(e) => exit_with(&e.to_string(), &opts),
+        };
+        if matches.opt_present("h") {
+            println!("{}", usage(&opts));
+            process::exit(0);
+        }
+
+        let mut config = Config::new();
+        if let Some(path) = matches.opt_str("config") {
+            if let Err(e) = config.apply_file(&path) {
+                exit_with(&e, &opts);
+            }
+        }
+        if let Some(addr) = matches.opt_str("addr") {
+            config.addr = addr;
+        }
+        if let Some(line_addr) = matches.opt_str("line-addr") {
+            config.line_addr = Some(line_addr);
+        }
+        if let Some(static_root) = matches.opt_str("static-root") {
+            config.static_root = static_root;
+        }
+        if let Some(time_control) = matches.opt_str("time-control") {
+            if !config.set_time_control(&time_control) {
+                exit_with(&format!("invalid time control {:?}", time_control), &opts);
+            }
+        }
+        if let Some(store_dir) = matches.opt_str("store-dir") {
+            config.store_dir = store_dir;
+        }
+        if let Some(log_level) = matches.opt_str("log-level") {
+            config.log_level = log_level;
+        }
+        if let Some(heartbeat) = secs_flag(&matches, "heartbeat", &opts) {
+            config.heartbeat_secs = heartbeat;
+        }
+        if let Some(idle_timeout) = secs_flag(&matches, "idle-timeout", &opts) {
+            config.idle_timeout_secs = idle_timeout;
+        }
+        if let Some(abandon_grace) = secs_flag(&matches, "abandon-grace", &opts) {
+            config.abandon_grace_secs = limit(abandon_grace);
+        }
+        if let Some(first_move_timeout) = secs_flag(&matches, "first-move-timeout", &opts) {
+            config.first_move_secs = limit(first_move_timeout);
+        }
+        if let Some(abandon) = matches.opt_str("abandon") {
+            match parse_abandon(&abandon) {
+                Some(forfeit) => config.forfeit = forfeit,
+                None => exit_with(&format!("invalid abandonment policy {:?}", abandon), &opts),
+            }
+        }
+        if config.heartbeat_secs == 0 || config.idle_timeout_secs <= config.heartbeat_secs {
+            exit_with("the idle timeout must be longer than a non-zero heartbeat", &opts);
+        }
+        if let Some(tls_cert) = matches.opt_str("tls-cert") {
+            config.tls_cert = Some(tls_cert);
+        }
+        if let Some(tls_key) = matches.opt_str("tls-key") {
+            config.tls_key = Some(tls_key);
+        }
+        let admins = matches.opt_strs("admin");
+        if !admins.is_empty() {
+            config.admins = admins;
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            exit_with("a TLS certificate and key must be given together", &opts);
+        }
+
+        match (matches.free.get(0).map(|s| s.as_str()), matches.free.get(1)) {
+            (None, _) => {},
+            (Some("replay"), Some(path)) if matches.free.len() == 2 => {
+                config.replay = Some(path.clone());
+            },
+            _ => exit_with("unexpected arguments", &opts),
+        }
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function to build the settings from arguments given after the name
+    /// of the program.
+    fn from(args: &[&str]) -> Config {
+        let mut all = vec![String::from("chess")];
+        all.extend(args.iter().map(|arg| String::from(*arg)));
+        Config::from_args(&all)
+    }
+
+    #[test]
+    fn parses_time_controls() {
+        assert_eq!(parse_time_control("10+5"), Some((600000, 5000)));
+        assert_eq!(parse_time_control("3"), Some((180000, 0)));
+        assert_eq!(parse_time_control("3+"), None);
+        assert_eq!(parse_time_control("fast"), None);
+    }
+
+    #[test]
+    fn reads_flags_over_the_defaults() {
+        let config = from(&["--time-control", "5+3", "--admin", "alice", "--admin", "bob"]);
+        assert_eq!((config.initial_ms, config.increment_ms), (300000, 3000));
+        assert_eq!(config.admins, vec![String::from("alice"), String::from("bob")]);
+        assert_eq!(config.addr, Config::new().addr);
+        assert!(config.replay.is_none());
+    }
+}
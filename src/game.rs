@@ -0,0 +1,529 @@
+//! A module for the state of a single game. Wraps the `Board` together with the
+//! state that is not part of the position itself: who holds each seat, the moves
+//! played so far, the clocks, the check status after the last move, the
+//! conditional moves of each player, the sealed move of an adjourned game, what
+//! spectators may do and see, and the outcome once the game is over.
+
+use std::mem;
+
+use super::board::{Board, Cell, Color};
+use super::clock::Clock;
+use super::conditional::{self, Branch};
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a single move that has been played.
+pub struct Move {
+    pub from: Cell,
+    pub to: Cell,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents how a game ended. `winner` is None for a draw or an aborted game.
+pub struct Outcome {
+    pub winner: Option<Color>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents what the spectators of a game may do and see. Spectators may chat
+/// among themselves if `chat` is set, and each board update reaches them once
+/// `delay_moves` more moves have been played and `delay_secs` seconds have passed.
+pub struct Spectating {
+    pub chat: bool,
+    pub delay_moves: u32,
+    pub delay_secs: u32,
+}
+
+impl Spectating {
+    /// Check if board updates are held back from spectators.
+    pub fn is_delayed(&self) -> bool {
+        self.delay_moves > 0 || self.delay_secs > 0
+    }
+}
+
+impl Default for Spectating {
+    fn default() -> Spectating {
+        Spectating {
+            chat: true,
+            delay_moves: 0,
+            delay_secs: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Represents the player holding a seat.
+/// The token identifies the player across connections, so the seat stays
+/// reserved for them while `addr`, the client they are connected from, is empty.
+/// `user` is the account they were logged in to when they took the seat, if any.
+pub struct Player {
+    pub token: String,
+    pub addr: String,
+    pub user: Option<String>,
+}
+
+#[derive(Clone)]
+/// Represents the full state of a game.
+/// `white` and `black` are None while the seat is open. Games are rated unless
+/// `rated` is cleared, when both players are logged in to accounts. If `berserk`
+/// is set, each player may halve their own clock before their first move, and
+/// `berserked` lists the colors that have. `white_conditional` and
+/// `black_conditional` are the trees of conditional moves of each player, and
+/// `reply` is the conditional reply to the last move, until it is played. While
+/// the game is adjourned, `sealed` is the move sealed by the side to move, which
+/// is kept from the clients until the game is resumed. `creator` is the account
+/// that created the game, if any, which may change what spectators may do and see.
+/// `draw_offer` is the color that has offered a draw since the last move, if any.
+/// `paired` is set for a game created for a pairing, such as a seek, a tournament
+/// or an arena, which new clients are never put in.
+pub struct Game {
+    pub board: Board,
+    pub history: Vec<Move>,
+    pub clock: Clock,
+    pub white: Option<Player>,
+    pub black: Option<Player>,
+    pub check: bool,
+    pub checkmate: bool,
+    pub outcome: Option<Outcome>,
+    pub rated: bool,
+    pub berserk: bool,
+    pub berserked: Vec<Color>,
+    pub white_conditional: Vec<Branch>,
+    pub black_conditional: Vec<Branch>,
+    pub reply: Option<Move>,
+    pub sealed: Option<Move>,
+    pub creator: Option<String>,
+    pub spectating: Spectating,
+    pub draw_offer: Option<Color>,
+    pub paired: bool,
+}
+
+/// Helper function to check if a seat is held by the given client.
+fn seated(player: &Option<Player>, addr: &str) -> bool {
+    match *player {
+        Some(ref player) => !player.addr.is_empty() && player.addr == addr,
+        None => false,
+    }
+}
+
+/// Helper function to bind a client to a seat if the seat belongs to `token`.
+fn rebind(player: &mut Option<Player>, addr: &str, token: &str) -> bool {
+    match *player {
+        Some(ref mut player) if player.token == token => {
+            player.addr = String::from(addr);
+            true
+        },
+        _ => false,
+    }
+}
+
+impl Game {
+    /// Initialize a game with the starting position and the given clock.
+    pub fn new(clock: Clock) -> Game {
+        Game {
+            board: Board::new(),
+            history: Vec::new(),
+            clock: clock,
+            white: None,
+            black: None,
+            check: false,
+            checkmate: false,
+            outcome: None,
+            rated: true,
+            berserk: false,
+            berserked: Vec::new(),
+            white_conditional: Vec::new(),
+            black_conditional: Vec::new(),
+            reply: None,
+            sealed: None,
+            creator: None,
+            spectating: Spectating::default(),
+            draw_offer: None,
+            paired: false,
+        }
+    }
+
+    /// Get the color of the seat held by a client, if any.
+    pub fn color_of(&self, addr: &str) -> Option<Color> {
+        if seated(&self.white, addr) {
+            Some(Color::White)
+        } else if seated(&self.black, addr) {
+            Some(Color::Black)
+        } else {
+            None
+        }
+    }
+
+    /// Get the name of the seat held by a client: White, Black, or Spectator.
+    pub fn seat(&self, addr: &str) -> &'static str {
+        match self.color_of(addr) {
+            Some(Color::White) => "White",
+            Some(Color::Black) => "Black",
+            None => "Spectator",
+        }
+    }
+
+    /// Seat a client, logged in to `user` if any, as the given color, identified by
+    /// `token` from now on. Fails if the seat is held or reserved by someone else,
+    /// or if the client wants to switch sides after the first move has been played.
+    pub fn take_seat(&mut self, addr: &str, user: Option<&str>, color: &Color, token: &str)
+                     -> bool {
+        let current = self.color_of(addr);
+        if current.as_ref() == Some(color) {
+            return true
+        }
+        let started = !self.history.is_empty();
+        let (seat, other) = match *color {
+            Color::White => (&mut self.white, &mut self.black),
+            Color::Black => (&mut self.black, &mut self.white),
+        };
+        if seat.is_some() || (current.is_some() && started) {
+            return false
+        }
+        if current.is_some() {
+            *other = None;
+        }
+        *seat = Some(Player{token: String::from(token), addr: String::from(addr),
+                            user: user.map(String::from)});
+        true
+    }
+
+    /// Reserve the seat of the given color for a token, without binding it to a
+    /// client. Used when restoring a game from storage.
+    pub fn reserve(&mut self, color: &Color, token: &str) {
+        let player = Some(Player{token: String::from(token), addr: String::new(), user: None});
+        match *color {
+            Color::White => self.white = player,
+            Color::Black => self.black = player,
+        }
+    }
+
+    /// Record the account of the player holding the seat of the given color. Used
+    /// when restoring a game from storage.
+    pub fn claim(&mut self, color: &Color, user: &str) {
+        let player = match *color {
+            Color::White => &mut self.white,
+            Color::Black => &mut self.black,
+        };
+        if let Some(ref mut player) = *player {
+            player.user = Some(String::from(user));
+        }
+    }
+
+    /// Get the account of the player holding the seat of the given color, if any.
+    pub fn user(&self, color: &Color) -> Option<&str> {
+        let player = match *color {
+            Color::White => &self.white,
+            Color::Black => &self.black,
+        };
+        player.as_ref().and_then(|player| player.user.as_ref()).map(|user| user.as_str())
+    }
+
+    /// Get the color and token of the seat an account plays in, if any.
+    pub fn seat_of(&self, user: &str) -> Option<(Color, String)> {
+        let seat = |player: &Option<Player>| match *player {
+            Some(ref player) if player.user.as_ref().map(|u| u.as_str()) == Some(user) => {
+                Some(player.token.clone())
+            },
+            _ => None,
+        };
+        seat(&self.white).map(|token| (Color::White, token))
+            .or_else(|| seat(&self.black).map(|token| (Color::Black, token)))
+    }
+
+    /// Get the color and token of a seat that is reserved for an account, but has no
+    /// client, if any.
+    pub fn reserved_for(&self, user: &str) -> Option<(Color, String)> {
+        let free = |player: &Option<Player>| match *player {
+            Some(ref player) if player.addr.is_empty() &&
+                player.user.as_ref().map(|u| u.as_str()) == Some(user) => {
+                Some(player.token.clone())
+            },
+            _ => None,
+        };
+        free(&self.white).map(|token| (Color::White, token))
+            .or_else(|| free(&self.black).map(|token| (Color::Black, token)))
+    }
+
+    /// Open the seat of the given color.
+    pub fn release(&mut self, color: &Color) {
+        match *color {
+            Color::White => self.white = None,
+            Color::Black => self.black = None,
+        }
+    }
+
+    /// Release whichever seat is held by a client, so that it can be taken by
+    /// someone else. Returns the color of the released seat.
+    pub fn leave_seat(&mut self, addr: &str) -> Option<Color> {
+        let color = self.color_of(addr);
+        if let Some(ref color) = color {
+            self.release(color);
+        }
+        color
+    }
+
+    /// Get the color of the seat reserved for a token, if any.
+    pub fn color_of_token(&self, token: &str) -> Option<Color> {
+        match (&self.white, &self.black) {
+            (&Some(ref player), _) if player.token == token => Some(Color::White),
+            (_, &Some(ref player)) if player.token == token => Some(Color::Black),
+            _ => None,
+        }
+    }
+
+    /// Bind a reconnecting client to the seat reserved for their token.
+    /// Returns the color of the seat, if the token holds one.
+    pub fn resume(&mut self, addr: &str, token: &str) -> Option<Color> {
+        if rebind(&mut self.white, addr, token) {
+            Some(Color::White)
+        } else if rebind(&mut self.black, addr, token) {
+            Some(Color::Black)
+        } else {
+            None
+        }
+    }
+
+    /// Unbind a client that has disconnected. Their seat stays reserved for
+    /// their token so that they can resume it later.
+    pub fn disconnect(&mut self, addr: &str) {
+        for player in self.white.iter_mut().chain(self.black.iter_mut()) {
+            if player.addr == addr {
+                player.addr.clear();
+            }
+        }
+    }
+
+    /// Helper function that checks if it is the given client's turn to move.
+    pub fn is_turn(&self, addr: &str) -> bool {
+        self.outcome.is_none() && self.sealed.is_none() &&
+            self.color_of(addr) == Some(self.board.color())
+    }
+
+    /// Adjourn the game with a move sealed by the side to move, stopping the clocks.
+    pub fn seal(&mut self, from: Cell, to: Cell) {
+        self.sealed = Some(Move{from: from, to: to});
+        self.clock.stop();
+    }
+
+    /// Resume an adjourned game, restarting the clock of the side to move. Returns
+    /// their sealed move, which is yet to be played, or None if the game was not
+    /// adjourned.
+    pub fn unseal(&mut self) -> Option<Move> {
+        let sealed = self.sealed.take();
+        if sealed.is_some() {
+            self.clock.start(&self.board.color());
+        }
+        sealed
+    }
+
+    /// Play a move for the side to move. Updates the board, the history and the
+    /// clocks, checks for check, checkmate and stalemate, and then switches turns.
+    /// If the side to move has already run out of time, they lose on time instead.
+    /// Once the move is played, the opponent's conditional moves are followed, and
+    /// their reply, if any, is left in `reply`.
+    pub fn play(&mut self, from: Cell, to: Cell) {
+        let color = self.board.color();
+        self.reply = None;
+        self.draw_offer = None;
+        if self.clock.remaining(&color) == 0 {
+            self.finish(Some(color.other()), "timeout");
+            return;
+        }
+        self.board.move_piece(from.clone(), to.clone());
+        self.history.push(Move{from: from, to: to});
+        self.check = self.board.check();
+        // A side left without a move out of check is mated, and one left without a
+        // move while not in check is stalemated.
+        let stuck = self.board.checkmate();
+        self.checkmate = self.check && stuck;
+        self.clock.press(&color);
+        if self.checkmate {
+            self.finish(Some(color.clone()), "checkmate");
+        } else if stuck {
+            self.finish(None, "stalemate");
+        }
+        self.board.switch_color();
+        let opponent = color.other();
+        let played = self.history.last().unwrap().clone();
+        let tree = mem::replace(self.conditional_mut(&opponent), Vec::new());
+        if let Some((reply, rest)) = conditional::follow(tree, &played) {
+            *self.conditional_mut(&opponent) = rest;
+            self.reply = Some(reply);
+        }
+    }
+
+    /// Get a copy of the game as it was after its first `moves` moves, e.g. to show
+    /// spectators a delayed view of the game.
+    pub fn rewound(&self, moves: usize) -> Game {
+        let mut game = self.clone();
+        game.board = Board::new();
+        game.history = Vec::new();
+        game.check = false;
+        game.checkmate = false;
+        for m in self.history.iter().take(moves) {
+            game.board.move_piece(m.from.clone(), m.to.clone());
+            game.history.push(m.clone());
+            game.check = game.board.check();
+            game.checkmate = game.check && game.board.checkmate();
+            game.board.switch_color();
+        }
+        game
+    }
+
+    /// Get the tree of conditional moves of the given color.
+    pub fn conditional(&self, color: &Color) -> &Vec<Branch> {
+        match *color {
+            Color::White => &self.white_conditional,
+            Color::Black => &self.black_conditional,
+        }
+    }
+
+    /// Get the tree of conditional moves of the given color, to replace it.
+    pub fn conditional_mut(&mut self, color: &Color) -> &mut Vec<Branch> {
+        match *color {
+            Color::White => &mut self.white_conditional,
+            Color::Black => &mut self.black_conditional,
+        }
+    }
+
+    /// Halve the clock of the given color, if berserk is allowed, the game is not
+    /// over, and they have neither moved nor gone berserk yet. Returns whether they
+    /// went berserk.
+    pub fn go_berserk(&mut self, color: &Color) -> bool {
+        let moved = match *color {
+            Color::White => !self.history.is_empty(),
+            Color::Black => self.history.len() > 1,
+        };
+        if !self.berserk || self.outcome.is_some() || moved || self.berserked.contains(color) {
+            return false
+        }
+        self.clock.halve(color);
+        self.berserked.push(color.clone());
+        true
+    }
+
+    /// Get the current position in Forsyth-Edwards Notation. The halfmove clock is
+    /// counted by replaying the moves played so far.
+    pub fn fen(&self) -> String {
+        let mut board = Board::new();
+        let mut halfmoves = 0;
+        for m in &self.history {
+            halfmoves = if board.is_irreversible(&m.from, &m.to) { 0 } else { halfmoves + 1 };
+            board.move_piece(m.from.clone(), m.to.clone());
+            board.switch_color();
+        }
+        format!("{} {} {}", self.board.fen(), halfmoves, self.history.len() / 2 + 1)
+    }
+
+    /// Get the moves played so far in standard algebraic notation.
+    pub fn san_history(&self) -> Vec<String> {
+        let mut board = Board::new();
+        let mut sans = Vec::new();
+        for m in &self.history {
+            sans.push(board.san(&m.from, &m.to));
+            board.move_piece(m.from.clone(), m.to.clone());
+            board.switch_color();
+        }
+        sans
+    }
+
+    /// Get the result of the game as written in PGN: "1-0", "0-1", "1/2-1/2", or
+    /// "*" while the game is still going or if it was aborted.
+    pub fn result(&self) -> &'static str {
+        match self.outcome {
+            None => "*",
+            Some(Outcome{winner: Some(Color::White), ..}) => "1-0",
+            Some(Outcome{winner: Some(Color::Black), ..}) => "0-1",
+            Some(ref outcome) if outcome.reason == "aborted" => "*",
+            Some(_) => "1/2-1/2",
+        }
+    }
+
+    /// Get the game in Portable Game Notation, under the given game id. Players
+    /// are named by their account, or "?" for guests.
+    pub fn pgn(&self, id: u32) -> String {
+        let name = |color| String::from(self.user(&color).unwrap_or("?"));
+        let tags = [("Event", format!("Rust Chess game {}", id)),
+                    ("Site", String::from("?")),
+                    ("Date", String::from("????.??.??")),
+                    ("Round", String::from("-")),
+                    ("White", name(Color::White)),
+                    ("Black", name(Color::Black)),
+                    ("Result", String::from(self.result()))];
+        let mut pgn = String::new();
+        for &(tag, ref value) in tags.iter() {
+            pgn.push_str(&format!("[{} \"{}\"]\n", tag, value));
+        }
+        pgn.push('\n');
+        for (i, san) in self.san_history().iter().enumerate() {
+            if i % 2 == 0 {
+                pgn.push_str(&format!("{}. ", i / 2 + 1));
+            }
+            pgn.push_str(san);
+            pgn.push(' ');
+        }
+        pgn.push_str(self.result());
+        pgn
+    }
+
+    /// End the game with the given winner, and stop the clocks.
+    pub fn finish(&mut self, winner: Option<Color>, reason: &str) {
+        self.clock.stop();
+        self.outcome = Some(Outcome{winner: winner, reason: String::from(reason)});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::{Board, Cell, Color};
+    use clock::Clock;
+
+    /// Helper function to play coordinate moves such as "e2e4" in a game.
+    fn play(game: &mut Game, moves: &[&str]) {
+        for m in moves {
+            game.play(Cell::from_notation(&m[..2]).unwrap(),
+                      Cell::from_notation(&m[2..]).unwrap());
+        }
+    }
+
+    #[test]
+    fn counts_halfmoves_since_a_pawn_move_or_capture() {
+        let mut game = Game::new(Clock::new(60000, 0));
+        assert!(game.fen().ends_with(" 0 1"));
+        play(&mut game, &["g1f3", "g8f6", "b1c3"]);
+        assert!(game.fen().ends_with(" 3 2"));
+        play(&mut game, &["e7e5"]);
+        assert!(game.fen().ends_with(" 0 3"));
+        play(&mut game, &["f3e5", "b8c6"]);
+        assert!(game.fen().ends_with(" 1 4"));
+    }
+
+    #[test]
+    fn names_the_players_by_account() {
+        let mut game = Game::new(Clock::new(60000, 0));
+        game.reserve(&Color::White, "w");
+        game.claim(&Color::White, "alice");
+        game.reserve(&Color::Black, "b");
+        play(&mut game, &["f2f3", "e7e5", "g2g4", "d8h4"]);
+        let pgn = game.pgn(7);
+        assert!(pgn.contains("[White \"alice\"]\n"));
+        assert!(pgn.contains("[Black \"?\"]\n"));
+        assert!(pgn.contains("[Result \"0-1\"]\n"));
+        assert!(pgn.ends_with("1. f3 e5 2. g4 Qh4# 0-1"));
+    }
+
+    #[test]
+    fn draws_a_stalemate() {
+        let mut game = Game::new(Clock::new(60000, 0));
+        game.board = Board::from_fen("7k/4Q3/6K1/8/8/8/8/8 w").unwrap();
+        play(&mut game, &["e7f7"]);
+        assert!(!game.check && !game.checkmate);
+        let outcome = game.outcome.unwrap();
+        assert_eq!((outcome.winner, outcome.reason.as_str()), (None, "stalemate"));
+    }
+}
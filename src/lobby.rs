@@ -0,0 +1,389 @@
+//! A module for the collection of games hosted by the server. Each game is owned
+//! by its own actor, a `Room`, run as a task on a pool of `GAME_THREADS` workers,
+//! and the lobby only keeps a handle on each room, so it is locked just long
+//! enough to find or create a game. Every change made to a game is appended to the
+//! game's log in `Storage`, and the lobby is rebuilt by replaying the stored logs
+//! on startup, so unfinished games survive a restart and their players can
+//! reconnect to them.
+//!
+//! The lobby also holds the open seeks, so that posting a seek and pairing it
+//! happen under the same lock.
+//!
+//! Correspondence games are restored like any other game, but the side to move
+//! is charged for the time the server was down since the last move, so that
+//! their deadline does not move because of a restart.
+
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use super::board::Color;
+use super::clock::Clock;
+use super::game::Game;
+use super::ratings::Ratings;
+use super::room::{self, Abandonment, Room};
+use super::seeks::{Seek, SeekInfo};
+use super::storage::{self, Record, Storage};
+use super::tasks::Executor;
+
+/// How many worker threads the games run on, however many games there are.
+const GAME_THREADS: usize = 4;
+
+/// Holds a handle on every game by id, along with the workers the games run on,
+/// the time control, the ratings and the abandonment policy of the games, the
+/// admins who may moderate any game, and the open seeks, oldest first.
+pub struct Lobby {
+    rooms: BTreeMap<u32, Room>,
+    executor: Executor,
+    next_id: u32,
+    initial_ms: u64,
+    increment_ms: u64,
+    ratings: Arc<Ratings>,
+    abandonment: Abandonment,
+    admins: Arc<Vec<String>>,
+    dir: String,
+    seeks: Vec<Seek>,
+    next_seek: u32,
+}
+
+impl Lobby {
+    /// Open the lobby stored in the given directory, replaying every stored game.
+    /// New games will use the given time control, and every game is rated in
+    /// `ratings`, ended according to the given abandonment policy, and may be
+    /// moderated by `admins`.
+    pub fn open(dir: &str, initial_ms: u64, increment_ms: u64, ratings: Arc<Ratings>,
+                abandonment: Abandonment, admins: Arc<Vec<String>>) -> Lobby {
+        let storage = Storage::open(dir);
+        let mut games = BTreeMap::new();
+        let mut next_id = 1;
+        let mut moved_at = HashMap::new();
+        for events in storage.load_all() {
+            for event in events {
+                if let Record::Moved{game, ..} = event.record {
+                    moved_at.insert(game, event.at);
+                }
+                next_id = cmp::max(next_id, Lobby::replay(&mut games, event.record) + 1);
+            }
+        }
+        let now = storage::now_ms();
+        for (id, game) in games.iter_mut() {
+            if game.clock.is_per_move() && game.outcome.is_none() && game.sealed.is_none() {
+                if let Some(at) = moved_at.get(id) {
+                    let color = game.board.color();
+                    let left = game.clock.remaining(&color).saturating_sub(now.saturating_sub(*at));
+                    game.clock.set_remaining(&color, left);
+                }
+            }
+        }
+        info!("restored {} games from {}", games.len(), dir);
+        let executor = Executor::new(GAME_THREADS);
+        let rooms = games.into_iter()
+            .map(|(id, game)| {
+                (id, Room::spawn(&executor, id, game, Storage::open(dir), ratings.clone(),
+                                 abandonment, admins.clone()))
+            })
+            .collect();
+        Lobby {
+            rooms: rooms,
+            executor: executor,
+            next_id: next_id,
+            initial_ms: initial_ms,
+            increment_ms: increment_ms,
+            ratings: ratings,
+            abandonment: abandonment,
+            admins: admins,
+            dir: String::from(dir),
+            seeks: Vec::new(),
+            next_seek: 1,
+        }
+    }
+
+    /// Apply a stored record to the games. Records that do not change the state
+    /// of a game are ignored. Returns the id of the game the record belongs to.
+    pub fn replay(games: &mut BTreeMap<u32, Game>, record: Record) -> u32 {
+        match record {
+            Record::Created{game, initial_ms, increment_ms} => {
+                games.insert(game, Game::new(Clock::new(initial_ms, increment_ms)));
+                game
+            },
+            Record::Seated{game, color, token} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.reserve(&color, &token);
+                }
+                game
+            },
+            Record::Claimed{game, color, user} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.claim(&color, &user);
+                }
+                game
+            },
+            Record::Correspondence{game} => {
+                if let Some(state) = games.get_mut(&game) {
+                    let (move_ms, _) = state.clock.time_control();
+                    state.clock = Clock::per_move(move_ms);
+                }
+                game
+            },
+            Record::Conditional{game, color, tree} => {
+                if let Some(state) = games.get_mut(&game) {
+                    *state.conditional_mut(&color) = tree;
+                }
+                game
+            },
+            Record::Sealed{game, from, to, white_ms, black_ms} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.seal(from, to);
+                    state.clock.set_remaining(&Color::White, white_ms);
+                    state.clock.set_remaining(&Color::Black, black_ms);
+                }
+                game
+            },
+            Record::Unsealed{game} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.unseal();
+                }
+                game
+            },
+            Record::Paired{game} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.paired = true;
+                }
+                game
+            },
+            Record::CreatedBy{game, user} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.creator = Some(user);
+                }
+                game
+            },
+            Record::Spectating{game, spectating} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.spectating = spectating;
+                }
+                game
+            },
+            Record::Casual{game} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.rated = false;
+                }
+                game
+            },
+            Record::BerserkAllowed{game} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.berserk = true;
+                }
+                game
+            },
+            Record::Berserked{game, color} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.go_berserk(&color);
+                }
+                game
+            },
+            Record::Unseated{game, color} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.release(&color);
+                }
+                game
+            },
+            Record::Moved{game, from, to, clock_ms} => {
+                if let Some(state) = games.get_mut(&game) {
+                    let color = state.board.color();
+                    state.play(from, to);
+                    state.clock.set_remaining(&color, clock_ms);
+                }
+                game
+            },
+            Record::Finished{game, outcome} => {
+                if let Some(state) = games.get_mut(&game) {
+                    state.finish(outcome.winner, &outcome.reason);
+                }
+                game
+            },
+            Record::Joined{game, ..} | Record::Left{game, ..} |
+            Record::Resumed{game, ..} | Record::Said{game, ..} => game,
+        }
+    }
+
+    /// Create a new game with the lobby's time control. Returns its id.
+    pub fn create(&mut self) -> u32 {
+        self.create_by(None)
+    }
+
+    /// Create a new game with the lobby's time control, created by the given
+    /// account, if any. Returns its id.
+    pub fn create_by(&mut self, creator: Option<&str>) -> u32 {
+        let clock = Clock::new(self.initial_ms, self.increment_ms);
+        self.spawn_game(clock, true, false, false, None, creator)
+    }
+
+    /// Create a new game for a pairing with the given time control, which is rated
+    /// if `rated` is set. Returns its id.
+    pub fn create_game(&mut self, initial_ms: u64, increment_ms: u64, rated: bool) -> u32 {
+        self.spawn_game(Clock::new(initial_ms, increment_ms), rated, false, true, None, None)
+    }
+
+    /// Create a new game with the given time control, which is rated if `rated`
+    /// is set and lets players go berserk if `berserk` is set, with its seats
+    /// reserved for the given accounts. Each player takes their seat when they
+    /// enter the game logged in. Returns its id.
+    pub fn create_reserved(&mut self, initial_ms: u64, increment_ms: u64, rated: bool,
+                           berserk: bool, white: &str, black: &str) -> u32 {
+        let clock = Clock::new(initial_ms, increment_ms);
+        self.spawn_game(clock, rated, berserk, true, Some((white, black)), None)
+    }
+
+    /// Create a new correspondence game, where each side has `move_ms` for every
+    /// move, which is rated if `rated` is set, with its seats reserved for the
+    /// given accounts, created by the given account. Returns its id.
+    pub fn create_correspondence(&mut self, move_ms: u64, rated: bool, white: &str,
+                                 black: &str, creator: &str) -> u32 {
+        let players = Some((white, black));
+        self.spawn_game(Clock::per_move(move_ms), rated, false, true, players, Some(creator))
+    }
+
+    /// Helper function to create a new game with the given clock, for a pairing if
+    /// `paired` is set, with its seats reserved for the accounts in `players`, if
+    /// any, and created by the account `creator`, if any.
+    fn spawn_game(&mut self, clock: Clock, rated: bool, berserk: bool, paired: bool,
+                  players: Option<(&str, &str)>, creator: Option<&str>) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut storage = Storage::open(&self.dir);
+        let (initial_ms, increment_ms) = clock.time_control();
+        let mut records = vec![Record::Created{game: id, initial_ms: initial_ms,
+                                               increment_ms: increment_ms}];
+        if clock.is_per_move() {
+            records.push(Record::Correspondence{game: id});
+        }
+        let mut game = Game::new(clock);
+        if let Some(creator) = creator {
+            records.push(Record::CreatedBy{game: id, user: String::from(creator)});
+            game.creator = Some(String::from(creator));
+        }
+        if !rated {
+            records.push(Record::Casual{game: id});
+            game.rated = false;
+        }
+        if berserk {
+            records.push(Record::BerserkAllowed{game: id});
+            game.berserk = true;
+        }
+        if paired {
+            records.push(Record::Paired{game: id});
+            game.paired = true;
+        }
+        if let Some((white, black)) = players {
+            for (color, user) in vec![(Color::White, white), (Color::Black, black)] {
+                let token = room::new_token();
+                records.push(Record::Seated{game: id, color: color.clone(),
+                                            token: token.clone()});
+                records.push(Record::Claimed{game: id, color: color.clone(),
+                                             user: String::from(user)});
+                game.reserve(&color, &token);
+                game.claim(&color, user);
+            }
+        }
+        // No client is in the game yet to be told if it cannot be logged.
+        for record in &records {
+            if let Err(e) = storage.append(id, record) {
+                error!("could not log the creation of game {}: {}", id, e);
+                break;
+            }
+        }
+        self.rooms.insert(id, Room::spawn(&self.executor, id, game, storage,
+                                          self.ratings.clone(), self.abandonment,
+                                          self.admins.clone()));
+        id
+    }
+
+    /// Get the game that new clients join: the newest unfinished game that is not
+    /// a correspondence game or created for a pairing, or a newly created one if
+    /// there is none.
+    pub fn default_game(&mut self) -> u32 {
+        for (id, room) in self.rooms.iter().rev() {
+            if !room.is_over() && !room.is_correspondence() && !room.is_paired() {
+                return *id
+            }
+        }
+        self.create()
+    }
+
+    /// Get a handle on the game with the given id.
+    pub fn get(&self, id: u32) -> Option<Room> {
+        self.rooms.get(&id).cloned()
+    }
+
+    /// Get a handle on every game, ordered by id, so that the games can be
+    /// reached without holding the lobby.
+    pub fn rooms(&self) -> Vec<(u32, Room)> {
+        self.rooms.iter().map(|(id, room)| (*id, room.clone())).collect()
+    }
+
+    /// Post a seek, replacing any seek the same client already has open. If it is
+    /// compatible with an open seek, the oldest such seek is closed and the pair is
+    /// returned as the seeks playing White and Black. Otherwise the seek is left
+    /// open.
+    pub fn post(&mut self, mut seek: Seek) -> Option<(Seek, Seek)> {
+        self.cancel(&seek.addr);
+        seek.id = self.next_seek;
+        self.next_seek += 1;
+        match self.seeks.iter().position(|open| open.compatible(&seek)) {
+            Some(index) => {
+                let open = self.seeks.remove(index);
+                Some(open.colors(seek))
+            },
+            None => {
+                self.seeks.push(seek);
+                None
+            },
+        }
+    }
+
+    /// Close the seek the client at `addr` has open. Returns whether it had one.
+    pub fn cancel(&mut self, addr: &str) -> bool {
+        let before = self.seeks.len();
+        self.seeks.retain(|seek| seek.addr != addr);
+        self.seeks.len() != before
+    }
+
+    /// Describe every open seek, oldest first.
+    pub fn seeks(&self) -> Vec<SeekInfo> {
+        self.seeks.iter().map(|seek| seek.info()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratings::Ratings;
+    use room::Abandonment;
+    use std::sync::Arc;
+    use storage::tests::scratch_dir;
+
+    /// Helper function to open the lobby stored in the given directory, with no
+    /// abandonment policy.
+    fn open(dir: &str) -> Lobby {
+        let abandonment = Abandonment{grace: None, first_move: None, forfeit: false};
+        Lobby::open(dir, 60000, 0, Arc::new(Ratings::open(dir)), abandonment,
+                    Arc::new(Vec::new()))
+    }
+
+    #[test]
+    fn puts_new_clients_in_games_not_created_for_a_pairing() {
+        let dir = scratch_dir("puts_new_clients_in_games_not_created_for_a_pairing");
+        let mut lobby = open(&dir);
+        let open_game = lobby.default_game();
+        assert_eq!(lobby.default_game(), open_game);
+        let seek = lobby.create_game(60000, 0, true);
+        let tournament = lobby.create_reserved(60000, 0, true, false, "alice", "bob");
+        assert_eq!(lobby.default_game(), open_game);
+
+        let mut lobby = open(&dir);
+        assert_eq!(lobby.default_game(), open_game);
+        assert!(lobby.get(seek).unwrap().is_paired());
+        assert!(lobby.get(tournament).unwrap().is_paired());
+        assert!(!lobby.get(open_game).unwrap().is_paired());
+    }
+}
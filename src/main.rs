@@ -1,15 +1,71 @@
-extern crate hyper;
+extern crate env_logger;
+extern crate getopts;
 extern crate iron;
+#[macro_use]
+extern crate log;
+extern crate mio;
 extern crate staticfile;
 extern crate mount;
+extern crate openssl;
+extern crate rand;
 extern crate rustc_serialize;
-extern crate websocket;
+extern crate toml;
 
+mod api;
 mod page;
 mod chess_server;
+mod accounts;
+mod arena;
+mod bans;
 mod board;
+mod clock;
+mod commands;
+mod conditional;
+mod config;
+mod front;
+mod game;
+mod lobby;
+mod protocol;
+mod ratings;
+mod replay;
+mod room;
+mod seeks;
+mod storage;
+mod tasks;
+mod tournaments;
+mod transport;
+mod ws;
+
+use std::env;
+use std::sync::Arc;
+
+use accounts::Accounts;
+use arena::Arenas;
+use bans::Bans;
+use config::Config;
+use ratings::Ratings;
+use tournaments::Tournaments;
 
 fn main() {
-    chess_server::start();
-    page::main();
+    let args: Vec<String> = env::args().collect();
+    let config = Config::from_args(&args);
+
+    let mut logger = env_logger::LogBuilder::new();
+    logger.parse(&config.log_level);
+    logger.init().unwrap();
+
+    // `chess replay games/<id>.log` replays a game's event log instead of serving.
+    if let Some(ref path) = config.replay {
+        replay::main(path);
+        return;
+    }
+    let accounts = Arc::new(Accounts::open(&config.store_dir));
+    let ratings = Arc::new(Ratings::open(&config.store_dir));
+    let tournaments = Arc::new(Tournaments::open(&config.store_dir));
+    let arenas = Arc::new(Arenas::open(&config.store_dir));
+    let bans = Arc::new(Bans::open(&config.store_dir));
+    let (lobby, relay) = chess_server::start(&config, accounts.clone(), ratings.clone());
+    tournaments::direct(tournaments.clone(), lobby.clone(), relay.clone());
+    arena::direct(arenas.clone(), lobby.clone(), relay.clone());
+    page::main(&config, lobby, accounts, ratings, tournaments, arenas, bans, relay);
 }
@@ -8,15 +8,52 @@
 // point your browser to http://127.0.0.1:3000/doc/
 
 use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
 
 use iron::Iron;
 use staticfile::Static;
 use mount::Mount;
 
-/// Serves the html page.
-pub fn main() {
+use super::accounts::Accounts;
+use super::api::Api;
+use super::arena::Arenas;
+use super::bans::Bans;
+use super::chess_server::Relay;
+use super::config::Config;
+use super::front;
+use super::lobby::Lobby;
+use super::ratings::Ratings;
+use super::tournaments::Tournaments;
+
+/// Address the page server listens on. Only the front listener connects to it.
+const PRIVATE_ADDR: &'static str = "127.0.0.1:0";
+
+/// Serves the html page, and the JSON API under /api/, on a private port, then
+/// serves everything on the configured address through the front listener.
+pub fn main(config: &Config, lobby: Arc<Mutex<Lobby>>, accounts: Arc<Accounts>,
+            ratings: Arc<Ratings>, tournaments: Arc<Tournaments>, arenas: Arc<Arenas>,
+            bans: Arc<Bans>, relay: Relay) {
     let mut mount = Mount::new();
-    mount.mount("/", Static::new(Path::new("html/")));
-    Iron::new(mount).http("localhost:1980").unwrap();
-    println!("Doc server running on http://localhost:1980/");
-}
\ No newline at end of file
+    mount.mount("/", Static::new(Path::new(&config.static_root)));
+    mount.mount("/api/", Api::new(lobby, accounts.clone(), ratings, tournaments, arenas,
+                                  bans.clone(), config.admins.clone(), relay.clone()));
+    let listening = Iron::new(mount).http(PRIVATE_ADDR).unwrap();
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (&Some(ref cert), &Some(ref key)) => match front::tls_context(cert, key) {
+            Ok(context) => Some(context),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            },
+        },
+        _ => None,
+    };
+    let line_addr = config.line_addr.as_ref().map(|line_addr| line_addr.as_str());
+    if let Some(line_addr) = line_addr {
+        info!("Accepting line protocol clients on {}", line_addr);
+    }
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Chess server running on {}://{}/", scheme, config.addr);
+    front::serve(&config.addr, line_addr, listening.socket, relay, accounts, bans, tls);
+}
@@ -0,0 +1,698 @@
+//! A module for the wire format of the messages exchanged with clients. Two
+//! versions of the protocol are spoken, chosen per client when they connect:
+//!
+//! * Version 1 is rustc_serialize's encoding of `Action`, e.g.
+//! `{"variant":"Move","fields":["6","4","4","4"]}` from clients. The JavaScript
+//! client still speaks it.
+//! * Version 2 uses tagged messages with named, typed fields and squares in
+//! algebraic notation, e.g. `{"type":"move","from":"e2","to":"e4"}`. WebSocket
+//! clients ask for it by offering the `chess.v2` subprotocol, and may offer
+//! `chess.v1` to ask for version 1 by name.
+//!
+//! Messages from clients in version 2:
+//!
+//! * `{"type":"select","square":"e2"}`
+//! * `{"type":"move","from":"e2","to":"e4"}`
+//! * `{"type":"say","text":"hi"}`
+//! * `{"type":"take_seat","color":"white"}` and `{"type":"leave_seat"}`
+//! * `{"type":"resume","token":"..."}`
+//! * `{"type":"new_game"}` and `{"type":"join","game":3}`
+//! * `{"type":"nick","nick":"alice"}` to change nickname.
+//! * `{"type":"leaderboard","category":"blitz"}` to get the best rated players in
+//! a category of time control: bullet, blitz, rapid, classical or correspondence.
+//! * `{"type":"seek","time_control":"5+3","rated":true,"color":"white","min_rating":1400,
+//! "max_rating":1800}` to look for an opponent, where the time control is given in
+//! minutes plus seconds of increment, and every field but the time control may be
+//! left out, for a casual game with either color against anyone.
+//! * `{"type":"unseek"}` to stop looking for an opponent.
+//! * `{"type":"berserk"}` to halve the player's clock in an arena game.
+//! * `{"type":"seal","from":"e2","to":"e4"}` to adjourn the game with a sealed move,
+//! and `{"type":"unseal"}` to resume it.
+//! * `{"type":"spectators","chat":false,"delay_moves":2,"delay_secs":30}` for the
+//! creator of the game to turn spectator chat off or on, and to hold board updates
+//! back from spectators, where the delays may be left out for none.
+//! * `{"type":"pong"}` in answer to a ping.
+//!
+//! Messages to clients in version 2, in which positions are given as FEN and
+//! colors and seats in lowercase:
+//!
+//! * `{"type":"welcome","version":2}` when the client connects.
+//! * `{"type":"state","game","fen","turn","seat","history","clocks","check","checkmate","outcome"}`
+//! * `{"type":"board","fen","check","checkmate","last","clocks"}` after every move.
+//! * `{"type":"moves","squares":["e3","e4"]}` in answer to a select.
+//! * `{"type":"seat","game","seat","token"}` when the client's seat changes.
+//! * `{"type":"over","outcome":{"winner","reason"}}` when the game ends.
+//! * `{"type":"message","user","text"}`, `{"type":"connect","user"}` and
+//! `{"type":"disconnect","user"}` for the chat, where users are nicknames.
+//! * `{"type":"nick","nick"}` with the client's nickname, when it is given or changed.
+//! * `{"type":"presence","white","black","spectators"}` with the nicknames of the
+//! connected players, or null, and of the spectators, whenever they change.
+//! * `{"type":"standings","category","standings"}` in answer to a leaderboard
+//! request, where each standing is `{"user","rating","deviation","games","provisional"}`.
+//! * `{"type":"seeks","seeks"}` with the open seeks, whenever they change, where each
+//! seek is `{"id","user","rating","time_control","rated","color","min_rating","max_rating"}`.
+//! * `{"type":"arena","arena","name","remaining_ms","standings"}` to the players of an
+//! arena whenever its standings change, where each standing is
+//! `{"rank","user","score","games","fire","paused"}`.
+//! * `{"type":"spectators","chat","delay_moves","delay_secs"}` with what spectators may
+//! do and see, when the client enters the game and whenever it changes.
+//! * `{"type":"adjourned","game","sealed_by"}` when the game is adjourned, after which
+//! the clocks are stopped until the next board, and when a client enters an
+//! adjourned game.
+//! * `{"type":"error","error"}` for a message that could not be understood.
+//! * `{"type":"ping"}` as a heartbeat, over transports without their own pings.
+
+use rustc_serialize::json::{self, Json};
+use std::collections::BTreeMap;
+
+use super::board::{Cell, Color};
+use super::chess_server::Action;
+use super::arena::ArenaStanding;
+use super::clock::ClockState;
+use super::config;
+use super::game::{Move, Outcome, Spectating};
+use super::ratings::Standing;
+use super::seeks::{SeekInfo, Terms};
+
+/// The WebSocket subprotocol that clients offer to speak version 1, which they must
+/// offer when they offer any other subprotocol, since browsers give up on a
+/// connection that offers subprotocols unless one of them is chosen.
+pub const SUBPROTOCOL_V1: &'static str = "chess.v1";
+/// The WebSocket subprotocol that clients offer to speak version 2.
+pub const SUBPROTOCOL_V2: &'static str = "chess.v2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Represents a version of the protocol.
+pub enum Version {
+    V1,
+    V2,
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a version 1 message from a client.
+struct Payload {
+    variant: String,
+    fields: Vec<String>
+}
+
+/// An `Action` encoded in every version, so that it is only encoded once when it
+/// is sent to many clients.
+pub struct Encoded {
+    v1: String,
+    v2: String,
+}
+
+impl Encoded {
+    /// Encode an `Action` in every version.
+    pub fn new(action: &Action) -> Encoded {
+        Encoded {
+            v1: encode(Version::V1, action),
+            v2: encode(Version::V2, action),
+        }
+    }
+
+    /// Get the encoding for the given version.
+    pub fn get(&self, version: Version) -> &str {
+        match version {
+            Version::V1 => &self.v1,
+            Version::V2 => &self.v2,
+        }
+    }
+}
+
+/// Get the greeting sent to a client when they connect.
+pub fn welcome(version: Version) -> String {
+    match version {
+        Version::V1 => String::from("Welcome!"),
+        Version::V2 => object(vec![("type", string("welcome")), ("version", Json::U64(2))])
+            .to_string(),
+    }
+}
+
+/// Get the message telling a client that their message could not be understood,
+/// or None if the version has no such message.
+pub fn error(version: Version, error: &str) -> Option<String> {
+    match version {
+        Version::V1 => None,
+        Version::V2 => Some(object(vec![("type", string("error")), ("error", string(error))])
+                            .to_string()),
+    }
+}
+
+/// Get the heartbeat sent to version 2 clients over transports without their own
+/// pings.
+pub fn ping() -> String {
+    object(vec![("type", string("ping"))]).to_string()
+}
+
+/// Encode an `Action` to be sent to a client.
+pub fn encode(version: Version, action: &Action) -> String {
+    match version {
+        Version::V1 => json::encode(action).unwrap(),
+        Version::V2 => encode_v2(action).to_string(),
+    }
+}
+
+/// Decode a message from a client into an `Action` tagged with their address.
+/// Returns None for a message that only keeps the connection alive.
+pub fn decode(version: Version, addr: &str, text: &str) -> Result<Option<Action>, String> {
+    match version {
+        Version::V1 => decode_v1(addr, text).map(Some),
+        Version::V2 => decode_v2(addr, text),
+    }
+}
+
+/// Helper function to build a JSON object.
+fn object(pairs: Vec<(&str, Json)>) -> Json {
+    let mut object = BTreeMap::new();
+    for (key, value) in pairs {
+        object.insert(String::from(key), value);
+    }
+    Json::Object(object)
+}
+
+/// Helper function to build a JSON string.
+fn string(value: &str) -> Json {
+    Json::String(String::from(value))
+}
+
+/// Helper function to get the version 2 name of a color.
+fn color_name(color: &Color) -> Json {
+    string(match *color {
+        Color::White => "white",
+        Color::Black => "black",
+    })
+}
+
+/// Helper function to build a version 2 move.
+fn move_json(from: &Cell, to: &Cell) -> Json {
+    object(vec![("from", Json::String(from.notation())), ("to", Json::String(to.notation()))])
+}
+
+/// Helper function to build version 2 clocks.
+fn clocks_json(clocks: &ClockState) -> Json {
+    object(vec![
+        ("white_ms", Json::U64(clocks.white_ms)),
+        ("black_ms", Json::U64(clocks.black_ms)),
+        ("running", clocks.running.as_ref().map_or(Json::Null, color_name)),
+    ])
+}
+
+/// Helper function to build a version 2 outcome.
+fn outcome_json(outcome: &Outcome) -> Json {
+    object(vec![
+        ("winner", outcome.winner.as_ref().map_or(Json::Null, color_name)),
+        ("reason", string(&outcome.reason)),
+    ])
+}
+
+/// Helper function to build a version 2 move history.
+fn history_json(history: &[Move]) -> Json {
+    Json::Array(history.iter().map(|m| move_json(&m.from, &m.to)).collect())
+}
+
+/// Helper function to build a version 2 standing.
+fn standing_json(standing: &Standing) -> Json {
+    object(vec![
+        ("user", string(&standing.user)),
+        ("rating", Json::U64(standing.rating as u64)),
+        ("deviation", Json::U64(standing.deviation as u64)),
+        ("games", Json::U64(standing.games as u64)),
+        ("provisional", Json::Boolean(standing.provisional)),
+    ])
+}
+
+/// Helper function to build a version 2 arena standing.
+fn arena_standing_json(standing: &ArenaStanding) -> Json {
+    object(vec![
+        ("rank", Json::U64(standing.rank as u64)),
+        ("user", string(&standing.user)),
+        ("score", Json::U64(standing.score as u64)),
+        ("games", Json::U64(standing.games as u64)),
+        ("fire", Json::Boolean(standing.fire)),
+        ("paused", Json::Boolean(standing.paused)),
+    ])
+}
+
+/// Helper function to write a time control in minutes plus seconds of increment.
+fn format_time_control(terms: &Terms) -> String {
+    format!("{}+{}", terms.initial_ms / (60 * 1000), terms.increment_ms / 1000)
+}
+
+/// Helper function to build a version 2 rating bound.
+fn bound_json(bound: Option<u32>) -> Json {
+    bound.map_or(Json::Null, |rating| Json::U64(rating as u64))
+}
+
+/// Helper function to build the version 2 fields of the terms of a seek.
+fn terms_fields(terms: &Terms) -> Vec<(&'static str, Json)> {
+    vec![
+        ("time_control", Json::String(format_time_control(terms))),
+        ("rated", Json::Boolean(terms.rated)),
+        ("color", terms.color.as_ref().map_or(Json::Null, color_name)),
+        ("min_rating", bound_json(terms.min_rating)),
+        ("max_rating", bound_json(terms.max_rating)),
+    ]
+}
+
+/// Helper function to build a version 2 open seek.
+fn seek_json(seek: &SeekInfo) -> Json {
+    let mut fields = vec![
+        ("id", Json::U64(seek.id as u64)),
+        ("user", string(&seek.nick)),
+        ("rating", Json::U64(seek.rating as u64)),
+    ];
+    fields.extend(terms_fields(&seek.terms));
+    object(fields)
+}
+
+/// Helper function to encode an `Action` in version 2. The address that tags the
+/// actions of clients is left out.
+fn encode_v2(action: &Action) -> Json {
+    match *action {
+        Action::Connect{ref user} => {
+            object(vec![("type", string("connect")), ("user", string(user))])
+        },
+        Action::Disconnect{ref user} => {
+            object(vec![("type", string("disconnect")), ("user", string(user))])
+        },
+        Action::Select{ref cell, ..} => {
+            object(vec![("type", string("select")), ("square", Json::String(cell.notation()))])
+        },
+        Action::Board{ref board, check, checkmate, ref last, ref clocks} => object(vec![
+            ("type", string("board")),
+            ("fen", Json::String(board.fen())),
+            ("check", Json::Boolean(check)),
+            ("checkmate", Json::Boolean(checkmate)),
+            ("last", last.as_ref().map_or(Json::Null, |m| move_json(&m.from, &m.to))),
+            ("clocks", clocks_json(clocks)),
+        ]),
+        Action::Msg{ref user, ref text} => object(vec![
+            ("type", string("message")),
+            ("user", string(user)),
+            ("text", string(text)),
+        ]),
+        Action::Moves{ref cells} => object(vec![
+            ("type", string("moves")),
+            ("squares", Json::Array(cells.iter().map(|c| Json::String(c.notation())).collect())),
+        ]),
+        Action::Move{ref from, ref to, ..} => {
+            let mut json = move_json(from, to);
+            if let Json::Object(ref mut object) = json {
+                object.insert(String::from("type"), string("move"));
+            }
+            json
+        },
+        Action::State{ref board, ref turn, ref seat, ref history, ref clocks, check, checkmate,
+                      game, ref outcome} => object(vec![
+            ("type", string("state")),
+            ("game", Json::U64(game as u64)),
+            ("fen", Json::String(board.fen())),
+            ("turn", color_name(turn)),
+            ("seat", Json::String(seat.to_lowercase())),
+            ("history", history_json(history)),
+            ("clocks", clocks_json(clocks)),
+            ("check", Json::Boolean(check)),
+            ("checkmate", Json::Boolean(checkmate)),
+            ("outcome", outcome.as_ref().map_or(Json::Null, outcome_json)),
+        ]),
+        Action::TakeSeat{ref color, ..} => {
+            object(vec![("type", string("take_seat")), ("color", color_name(color))])
+        },
+        Action::LeaveSeat{..} => object(vec![("type", string("leave_seat"))]),
+        Action::Seat{game, ref seat, ref token} => object(vec![
+            ("type", string("seat")),
+            ("game", Json::U64(game as u64)),
+            ("seat", Json::String(seat.to_lowercase())),
+            ("token", string(token)),
+        ]),
+        Action::Say{ref text, ..} => object(vec![("type", string("say")), ("text", string(text))]),
+        Action::Resume{ref token, ..} => {
+            object(vec![("type", string("resume")), ("token", string(token))])
+        },
+        Action::NewGame{..} => object(vec![("type", string("new_game"))]),
+        Action::Join{game, ..} => {
+            object(vec![("type", string("join")), ("game", Json::U64(game as u64))])
+        },
+        Action::Over{ref outcome} => {
+            object(vec![("type", string("over")), ("outcome", outcome_json(outcome))])
+        },
+        Action::Nick{ref nick, ..} => {
+            object(vec![("type", string("nick")), ("nick", string(nick))])
+        },
+        Action::Presence{ref white, ref black, ref spectators} => object(vec![
+            ("type", string("presence")),
+            ("white", white.as_ref().map_or(Json::Null, |nick| string(nick))),
+            ("black", black.as_ref().map_or(Json::Null, |nick| string(nick))),
+            ("spectators", Json::Array(spectators.iter().map(|nick| string(nick)).collect())),
+        ]),
+        Action::Leaderboard{ref category, ..} => {
+            object(vec![("type", string("leaderboard")), ("category", string(category))])
+        },
+        Action::Standings{ref category, ref standings} => object(vec![
+            ("type", string("standings")),
+            ("category", string(category)),
+            ("standings", Json::Array(standings.iter().map(standing_json).collect())),
+        ]),
+        Action::Seek{ref terms, ..} => {
+            let mut fields = vec![("type", string("seek"))];
+            fields.extend(terms_fields(terms));
+            object(fields)
+        },
+        Action::Unseek{..} => object(vec![("type", string("unseek"))]),
+        Action::Seeks{ref seeks} => object(vec![
+            ("type", string("seeks")),
+            ("seeks", Json::Array(seeks.iter().map(seek_json).collect())),
+        ]),
+        Action::Berserk{..} => object(vec![("type", string("berserk"))]),
+        Action::Seal{ref from, ref to, ..} => {
+            let mut json = move_json(from, to);
+            if let Json::Object(ref mut object) = json {
+                object.insert(String::from("type"), string("seal"));
+            }
+            json
+        },
+        Action::Unseal{..} => object(vec![("type", string("unseal"))]),
+        Action::Spectators{ref spectating, ..} => object(vec![
+            ("type", string("spectators")),
+            ("chat", Json::Boolean(spectating.chat)),
+            ("delay_moves", Json::U64(spectating.delay_moves as u64)),
+            ("delay_secs", Json::U64(spectating.delay_secs as u64)),
+        ]),
+        Action::Adjourned{game, ref sealed_by} => object(vec![
+            ("type", string("adjourned")),
+            ("game", Json::U64(game as u64)),
+            ("sealed_by", color_name(sealed_by)),
+        ]),
+        Action::Arena{arena, ref name, remaining_ms, ref standings} => object(vec![
+            ("type", string("arena")),
+            ("arena", Json::U64(arena as u64)),
+            ("name", string(name)),
+            ("remaining_ms", Json::U64(remaining_ms)),
+            ("standings", Json::Array(standings.iter().map(arena_standing_json).collect())),
+        ]),
+    }
+}
+
+/// Helper function to get a string field of a version 2 message.
+fn field<'a>(message: &'a Json, key: &str) -> Result<&'a str, String> {
+    message.find(key).and_then(|value| value.as_string())
+        .ok_or(format!("expected a string {:?}", key))
+}
+
+/// Helper function to get a square field of a version 2 message.
+fn square(message: &Json, key: &str) -> Result<Cell, String> {
+    let notation = try!(field(message, key));
+    Cell::from_notation(notation).ok_or(format!("invalid square {:?}", notation))
+}
+
+/// Helper function to parse a time control of minutes plus seconds of increment.
+fn parse_time_control(text: &str) -> Result<(u64, u64), String> {
+    config::parse_time_control(text).ok_or(format!("invalid time control {:?}", text))
+}
+
+/// Helper function to get an optional rating bound field of a version 2 message.
+fn bound(message: &Json, key: &str) -> Result<Option<u32>, String> {
+    match message.find(key) {
+        None | Some(&Json::Null) => Ok(None),
+        Some(value) => value.as_u64().map(|rating| Some(rating as u32))
+            .ok_or(format!("expected a number {:?}", key)),
+    }
+}
+
+/// Helper function to get an optional delay field of a version 2 message.
+fn delay(message: &Json, key: &str) -> Result<u32, String> {
+    match message.find(key) {
+        None | Some(&Json::Null) => Ok(0),
+        Some(value) => value.as_u64().map(|delay| delay as u32)
+            .ok_or(format!("expected a number {:?}", key)),
+    }
+}
+
+/// Helper function to decode the terms of a version 2 seek.
+fn terms_v2(message: &Json) -> Result<Terms, String> {
+    let time_control = try!(field(message, "time_control"));
+    let (initial_ms, increment_ms) = try!(parse_time_control(time_control));
+    let rated = match message.find("rated") {
+        None | Some(&Json::Null) => false,
+        Some(value) => try!(value.as_boolean().ok_or(String::from("expected a boolean \"rated\""))),
+    };
+    let color = match message.find("color") {
+        None | Some(&Json::Null) => None,
+        Some(_) => match try!(field(message, "color")) {
+            "white" => Some(Color::White),
+            "black" => Some(Color::Black),
+            color => return Err(format!("invalid color {:?}", color)),
+        },
+    };
+    Ok(Terms {
+        initial_ms: initial_ms,
+        increment_ms: increment_ms,
+        rated: rated,
+        color: color,
+        min_rating: try!(bound(message, "min_rating")),
+        max_rating: try!(bound(message, "max_rating")),
+    })
+}
+
+/// Helper function to decode a version 2 message.
+fn decode_v2(addr: &str, text: &str) -> Result<Option<Action>, String> {
+    let message = try!(Json::from_str(text).map_err(|e| e.to_string()));
+    if !message.is_object() {
+        return Err(String::from("expected an object"))
+    }
+    let addr = String::from(addr);
+    let action = match try!(field(&message, "type")) {
+        "select" => Action::Select{addr: addr, cell: try!(square(&message, "square"))},
+        "move" => Action::Move{
+            addr: addr,
+            from: try!(square(&message, "from")),
+            to: try!(square(&message, "to")),
+        },
+        "say" => Action::Say{addr: addr, text: String::from(try!(field(&message, "text")))},
+        "take_seat" => {
+            let color = match try!(field(&message, "color")) {
+                "white" => Color::White,
+                "black" => Color::Black,
+                color => return Err(format!("invalid color {:?}", color)),
+            };
+            Action::TakeSeat{addr: addr, color: color}
+        },
+        "leave_seat" => Action::LeaveSeat{addr: addr},
+        "resume" => Action::Resume{addr: addr, token: String::from(try!(field(&message, "token")))},
+        "new_game" => Action::NewGame{addr: addr},
+        "join" => {
+            let game = try!(message.find("game").and_then(|game| game.as_u64())
+                            .ok_or(String::from("expected a number \"game\"")));
+            Action::Join{addr: addr, game: game as u32}
+        },
+        "nick" => Action::Nick{addr: addr, nick: String::from(try!(field(&message, "nick")))},
+        "leaderboard" => Action::Leaderboard{
+            addr: addr,
+            category: String::from(try!(field(&message, "category"))),
+        },
+        "seek" => Action::Seek{addr: addr, terms: try!(terms_v2(&message))},
+        "unseek" => Action::Unseek{addr: addr},
+        "berserk" => Action::Berserk{addr: addr},
+        "seal" => Action::Seal{
+            addr: addr,
+            from: try!(square(&message, "from")),
+            to: try!(square(&message, "to")),
+        },
+        "unseal" => Action::Unseal{addr: addr},
+        "spectators" => Action::Spectators{addr: addr, spectating: Spectating {
+            chat: try!(message.find("chat").and_then(|chat| chat.as_boolean())
+                       .ok_or(String::from("expected a boolean \"chat\""))),
+            delay_moves: try!(delay(&message, "delay_moves")),
+            delay_secs: try!(delay(&message, "delay_secs")),
+        }},
+        "pong" => return Ok(None),
+        kind => return Err(format!("unknown message type {:?}", kind)),
+    };
+    Ok(Some(action))
+}
+
+/// Helper function to decode a version 1 message. The JavaScript sends every
+/// field as a string, and chat messages as a `Msg` with the user first. Seeks are
+/// sent as the time control, "true" if rated, the color or an empty string, and
+/// the lowest and highest rating accepted, or empty strings. What spectators may
+/// do and see is sent as "true" if they may chat, and the delays in moves and
+/// seconds.
+fn decode_v1(addr: &str, text: &str) -> Result<Action, String> {
+    let payload: Payload = try!(json::decode(text).map_err(|e| e.to_string()));
+    let number = |i: usize| payload.fields.get(i).and_then(|field| field.parse::<i32>().ok())
+        .ok_or(format!("expected a number in field {}", i));
+    let text = |i: usize| payload.fields.get(i).cloned()
+        .ok_or(format!("expected a string in field {}", i));
+    let addr = String::from(addr);
+    match payload.variant.as_ref() {
+        "Select" => Ok(Action::Select{
+            addr: addr,
+            cell: Cell::new(try!(number(0)), try!(number(1))),
+        }),
+        "Move" => Ok(Action::Move{
+            addr: addr,
+            from: Cell::new(try!(number(0)), try!(number(1))),
+            to: Cell::new(try!(number(2)), try!(number(3))),
+        }),
+        "Resume" => Ok(Action::Resume{addr: addr, token: try!(text(0))}),
+        "NewGame" => Ok(Action::NewGame{addr: addr}),
+        "Join" => Ok(Action::Join{addr: addr, game: try!(number(0)) as u32}),
+        "TakeSeat" => {
+            let color = match try!(text(0)).as_ref() {
+                "White" => Color::White,
+                "Black" => Color::Black,
+                color => return Err(format!("invalid color {:?}", color)),
+            };
+            Ok(Action::TakeSeat{addr: addr, color: color})
+        },
+        "LeaveSeat" => Ok(Action::LeaveSeat{addr: addr}),
+        "Msg" => Ok(Action::Say{addr: addr, text: try!(text(1))}),
+        "Nick" => Ok(Action::Nick{addr: addr, nick: try!(text(0))}),
+        "Leaderboard" => Ok(Action::Leaderboard{addr: addr, category: try!(text(0))}),
+        "Seek" => {
+            let (initial_ms, increment_ms) = try!(parse_time_control(&try!(text(0))));
+            let color = match try!(text(2)).as_ref() {
+                "White" => Some(Color::White),
+                "Black" => Some(Color::Black),
+                "" => None,
+                color => return Err(format!("invalid color {:?}", color)),
+            };
+            let bound = |i: usize| match payload.fields.get(i).map(|field| field.trim()) {
+                None | Some("") => Ok(None),
+                Some(field) => field.parse::<u32>().map(Some)
+                    .map_err(|_| format!("expected a number in field {}", i)),
+            };
+            Ok(Action::Seek{addr: addr, terms: Terms {
+                initial_ms: initial_ms,
+                increment_ms: increment_ms,
+                rated: try!(text(1)) == "true",
+                color: color,
+                min_rating: try!(bound(3)),
+                max_rating: try!(bound(4)),
+            }})
+        },
+        "Unseek" => Ok(Action::Unseek{addr: addr}),
+        "Berserk" => Ok(Action::Berserk{addr: addr}),
+        "Seal" => Ok(Action::Seal{
+            addr: addr,
+            from: Cell::new(try!(number(0)), try!(number(1))),
+            to: Cell::new(try!(number(2)), try!(number(3))),
+        }),
+        "Unseal" => Ok(Action::Unseal{addr: addr}),
+        "Spectators" => Ok(Action::Spectators{addr: addr, spectating: Spectating {
+            chat: try!(text(0)) == "true",
+            delay_moves: try!(try!(text(1)).parse::<u32>()
+                              .map_err(|_| String::from("expected a delay in field 1"))),
+            delay_secs: try!(try!(text(2)).parse::<u32>()
+                             .map_err(|_| String::from("expected a delay in field 2"))),
+        }}),
+        variant => Err(format!("unknown action {:?}", variant)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::{Cell, Color};
+    use chess_server::Action;
+    use game::Spectating;
+    use seeks::Terms;
+
+    /// Helper function to get a square from its algebraic notation.
+    fn cell(notation: &str) -> Cell {
+        Cell::from_notation(notation).unwrap()
+    }
+
+    /// Helper function to check that an action from a client decodes to itself
+    /// once encoded in version 2.
+    fn round_trip(action: Action) {
+        let text = encode(Version::V2, &action);
+        let decoded = decode(Version::V2, "1.2.3.4:5", &text).unwrap().unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", action), "{}", text);
+    }
+
+    #[test]
+    fn round_trips_client_messages_in_version_2() {
+        let addr = || String::from("1.2.3.4:5");
+        round_trip(Action::Select{addr: addr(), cell: cell("e2")});
+        round_trip(Action::Move{addr: addr(), from: cell("e2"), to: cell("e4")});
+        round_trip(Action::Say{addr: addr(), text: String::from("hi \"there\"")});
+        round_trip(Action::TakeSeat{addr: addr(), color: Color::Black});
+        round_trip(Action::LeaveSeat{addr: addr()});
+        round_trip(Action::Resume{addr: addr(), token: String::from("abc")});
+        round_trip(Action::NewGame{addr: addr()});
+        round_trip(Action::Join{addr: addr(), game: 3});
+        round_trip(Action::Nick{addr: addr(), nick: String::from("alice")});
+        round_trip(Action::Leaderboard{addr: addr(), category: String::from("blitz")});
+        round_trip(Action::Seek{addr: addr(), terms: Terms {
+            initial_ms: 5 * 60 * 1000,
+            increment_ms: 3000,
+            rated: true,
+            color: Some(Color::White),
+            min_rating: Some(1400),
+            max_rating: Some(1800),
+        }});
+        round_trip(Action::Seek{addr: addr(), terms: Terms {
+            initial_ms: 60 * 1000,
+            increment_ms: 0,
+            rated: false,
+            color: None,
+            min_rating: None,
+            max_rating: None,
+        }});
+        round_trip(Action::Unseek{addr: addr()});
+        round_trip(Action::Berserk{addr: addr()});
+        round_trip(Action::Seal{addr: addr(), from: cell("g8"), to: cell("f6")});
+        round_trip(Action::Unseal{addr: addr()});
+        round_trip(Action::Spectators{addr: addr(), spectating: Spectating {
+            chat: false,
+            delay_moves: 2,
+            delay_secs: 30,
+        }});
+    }
+
+    #[test]
+    fn decodes_version_2_defaults_and_pongs() {
+        let seek = decode(Version::V2, "a", r#"{"type":"seek","time_control":"3"}"#);
+        match seek {
+            Ok(Some(Action::Seek{terms, ..})) => {
+                assert_eq!((terms.initial_ms, terms.increment_ms), (180000, 0));
+                assert!(!terms.rated && terms.color.is_none());
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(decode(Version::V2, "a", r#"{"type":"pong"}"#).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_bad_version_2_messages() {
+        for text in &[r#"[1]"#, r#"{"type":"fly"}"#, r#"{"type":"move","from":"e2","to":"e9"}"#,
+                      r#"{"type":"take_seat","color":"red"}"#, r#"{"type":"join"}"#, "{"] {
+            assert!(decode(Version::V2, "a", text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn decodes_what_the_javascript_sends() {
+        let text = r#"{"variant":"Move","fields":["6","4","4","4"]}"#;
+        match decode(Version::V1, "a", text) {
+            Ok(Some(Action::Move{from, to, ..})) => {
+                assert_eq!((from, to), (cell("e2"), cell("e4")));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        let text = r#"{"variant":"Msg","fields":["alice","hi"]}"#;
+        match decode(Version::V1, "a", text) {
+            Ok(Some(Action::Say{text, ..})) => assert_eq!(text, "hi"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encodes_version_1_as_the_javascript_reads_it() {
+        let text = encode(Version::V1, &Action::Msg{user: String::from("a"),
+                                                    text: String::from("b")});
+        assert_eq!(text, r#"{"variant":"Msg","fields":["a","b"]}"#);
+    }
+}
@@ -0,0 +1,358 @@
+//! A module for the ratings of players with accounts, using the Glicko-2 system
+//! (http://www.glicko.net/glicko/glicko2.pdf). Every game between two accounts
+//! that is not aborted is rated as soon as it ends, as a rating period of its own.
+//! A player's rating deviation grows again while they do not play, by one rating
+//! period per day, so the ratings of inactive players become less certain.
+//!
+//! Ratings are kept separately for each category of time control, and are stored
+//! in `ratings.json` in the store directory.
+
+use rustc_serialize::json;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+
+use super::board::Color;
+use super::storage;
+
+/// The name of the file ratings are stored in, in the store directory.
+const FILE_NAME: &'static str = "ratings.json";
+/// The rating, deviation and volatility of a new player.
+const INITIAL_RATING: f64 = 1500.0;
+const INITIAL_DEVIATION: f64 = 350.0;
+const INITIAL_VOLATILITY: f64 = 0.06;
+/// The system constant, which limits how quickly the volatility changes.
+const TAU: f64 = 0.5;
+/// The tolerance the new volatility is found to.
+const EPSILON: f64 = 0.000001;
+/// The factor between the Glicko and Glicko-2 scales.
+const SCALE: f64 = 173.7178;
+/// How long a rating period is, in milliseconds.
+const PERIOD_MS: u64 = 24 * 60 * 60 * 1000;
+/// Ratings with a deviation at least this large are provisional.
+const PROVISIONAL_DEVIATION: f64 = 110.0;
+
+/// The categories of time control, from fastest to slowest. Correspondence games
+/// have their own category, whatever their time per move.
+pub const CATEGORIES: [&'static str; 5] =
+    ["bullet", "blitz", "rapid", "classical", "correspondence"];
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a player's rating in one category, as of `updated_ms`, in
+/// milliseconds since the epoch.
+struct Rating {
+    rating: f64,
+    deviation: f64,
+    volatility: f64,
+    games: u32,
+    updated_ms: u64,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// A player's current rating in one category, to be shown to clients.
+pub struct Standing {
+    pub user: String,
+    pub rating: u32,
+    pub deviation: u32,
+    pub games: u32,
+    pub provisional: bool,
+}
+
+/// The ratings of every player in every category, keyed by username and then by
+/// category.
+pub struct Ratings {
+    path: PathBuf,
+    ratings: Mutex<BTreeMap<String, BTreeMap<String, Rating>>>,
+}
+
+/// Get the category of a time control, by how long a game of 40 moves is expected
+/// to last: bullet under 3 minutes, blitz under 8, rapid under 25, and classical
+/// otherwise.
+pub fn category(initial_ms: u64, increment_ms: u64) -> &'static str {
+    let expected_ms = initial_ms + 40 * increment_ms;
+    if expected_ms < 3 * 60 * 1000 {
+        "bullet"
+    } else if expected_ms < 8 * 60 * 1000 {
+        "blitz"
+    } else if expected_ms < 25 * 60 * 1000 {
+        "rapid"
+    } else {
+        "classical"
+    }
+}
+
+/// Helper function to reduce the impact of a game by the opponent's deviation.
+fn g(phi: f64) -> f64 {
+    1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt()
+}
+
+/// Helper function to get the expected score against an opponent.
+fn expected(mu: f64, mu_j: f64, phi_j: f64) -> f64 {
+    1.0 / (1.0 + (-g(phi_j) * (mu - mu_j)).exp())
+}
+
+/// Helper function to find the new volatility, with the Illinois algorithm.
+fn volatility(sigma: f64, phi: f64, v: f64, delta: f64) -> f64 {
+    let a = (sigma * sigma).ln();
+    let f = |x: f64| {
+        let ex = x.exp();
+        let d = phi * phi + v + ex;
+        ex * (delta * delta - phi * phi - v - ex) / (2.0 * d * d) - (x - a) / (TAU * TAU)
+    };
+    let mut big_a = a;
+    let mut big_b = if delta * delta > phi * phi + v {
+        (delta * delta - phi * phi - v).ln()
+    } else {
+        let mut k = 1.0;
+        while f(a - k * TAU) < 0.0 {
+            k += 1.0;
+        }
+        a - k * TAU
+    };
+    let mut f_a = f(big_a);
+    let mut f_b = f(big_b);
+    while (big_b - big_a).abs() > EPSILON {
+        let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+        let f_c = f(big_c);
+        if f_c * f_b <= 0.0 {
+            big_a = big_b;
+            f_a = f_b;
+        } else {
+            f_a /= 2.0;
+        }
+        big_b = big_c;
+        f_b = f_c;
+    }
+    (big_a / 2.0).exp()
+}
+
+impl Rating {
+    /// Helper function to get the rating of a new player.
+    fn new(now_ms: u64) -> Rating {
+        Rating {
+            rating: INITIAL_RATING,
+            deviation: INITIAL_DEVIATION,
+            volatility: INITIAL_VOLATILITY,
+            games: 0,
+            updated_ms: now_ms,
+        }
+    }
+
+    /// Helper function to grow the deviation by the rating periods that have
+    /// passed without a game, never past the deviation of a new player.
+    fn decayed(&self, now_ms: u64) -> Rating {
+        let periods = now_ms.saturating_sub(self.updated_ms) as f64 / PERIOD_MS as f64;
+        let phi = self.deviation / SCALE;
+        let phi = (phi * phi + periods * self.volatility * self.volatility).sqrt();
+        Rating {
+            deviation: (phi * SCALE).min(INITIAL_DEVIATION),
+            updated_ms: now_ms,
+            ..self.clone()
+        }
+    }
+
+    /// Helper function to rate a single game against an opponent, with the given
+    /// score: 1 for a win, 0.5 for a draw, and 0 for a loss.
+    fn update(&self, opponent: &Rating, score: f64) -> Rating {
+        self.rate_period(&[(opponent, score)])
+    }
+
+    /// Helper function to rate the games of a rating period, each against an
+    /// opponent with the given score.
+    fn rate_period(&self, games: &[(&Rating, f64)]) -> Rating {
+        let mu = (self.rating - INITIAL_RATING) / SCALE;
+        let phi = self.deviation / SCALE;
+        let mut information = 0.0;
+        let mut improvement = 0.0;
+        for &(opponent, score) in games {
+            let mu_j = (opponent.rating - INITIAL_RATING) / SCALE;
+            let phi_j = opponent.deviation / SCALE;
+            let e = expected(mu, mu_j, phi_j);
+            information += g(phi_j) * g(phi_j) * e * (1.0 - e);
+            improvement += g(phi_j) * (score - e);
+        }
+
+        let v = 1.0 / information;
+        let delta = v * improvement;
+        let sigma = volatility(self.volatility, phi, v, delta);
+        let phi_star = (phi * phi + sigma * sigma).sqrt();
+        let phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let mu = mu + phi * phi * improvement;
+        Rating {
+            rating: mu * SCALE + INITIAL_RATING,
+            deviation: phi * SCALE,
+            volatility: sigma,
+            games: self.games + games.len() as u32,
+            updated_ms: self.updated_ms,
+        }
+    }
+
+    /// Helper function to describe the rating to clients.
+    fn standing(&self, user: &str) -> Standing {
+        Standing {
+            user: String::from(user),
+            rating: self.rating.round().max(0.0) as u32,
+            deviation: self.deviation.round() as u32,
+            games: self.games,
+            provisional: self.deviation >= PROVISIONAL_DEVIATION,
+        }
+    }
+}
+
+impl Ratings {
+    /// Open the ratings stored in the given directory.
+    pub fn open(dir: &str) -> Ratings {
+        let path = PathBuf::from(dir).join(FILE_NAME);
+        let mut contents = String::new();
+        let ratings = match File::open(&path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => match json::decode(&contents) {
+                Ok(ratings) => ratings,
+                Err(e) => {
+                    error!("could not read ratings from {}: {}", path.display(), e);
+                    process::exit(1);
+                },
+            },
+            Err(_) => BTreeMap::new(),
+        };
+        Ratings {
+            path: path,
+            ratings: Mutex::new(ratings),
+        }
+    }
+
+    /// Rate a game in the given category between two accounts, won by `winner`,
+    /// or drawn if there is none.
+    pub fn record(&self, category: &str, white: &str, black: &str, winner: Option<&Color>) {
+        let now = storage::now_ms();
+        let mut ratings = self.ratings.lock().unwrap();
+        let (white_rating, black_rating) = {
+            let current = |user: &str| ratings.get(user).and_then(|r| r.get(category))
+                .map_or(Rating::new(now), |rating| rating.decayed(now));
+            (current(white), current(black))
+        };
+        let white_score = match winner {
+            Some(&Color::White) => 1.0,
+            Some(&Color::Black) => 0.0,
+            None => 0.5,
+        };
+        let new_white = white_rating.update(&black_rating, white_score);
+        let new_black = black_rating.update(&white_rating, 1.0 - white_score);
+        info!("{} game rated: {} {:.0} -> {:.0}, {} {:.0} -> {:.0}", category,
+              white, white_rating.rating, new_white.rating,
+              black, black_rating.rating, new_black.rating);
+        ratings.entry(String::from(white)).or_insert(BTreeMap::new())
+            .insert(String::from(category), new_white);
+        ratings.entry(String::from(black)).or_insert(BTreeMap::new())
+            .insert(String::from(category), new_black);
+        let contents = json::encode(&*ratings).unwrap();
+        if let Err(e) = storage::replace(&self.path, &contents) {
+            error!("could not save ratings to {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Get the current rating of a player in every category they have played in.
+    pub fn of(&self, user: &str) -> BTreeMap<String, Standing> {
+        let now = storage::now_ms();
+        let ratings = self.ratings.lock().unwrap();
+        ratings.get(user).map_or(BTreeMap::new(), |ratings| {
+            ratings.iter()
+                .map(|(category, rating)| (category.clone(), rating.decayed(now).standing(user)))
+                .collect()
+        })
+    }
+
+    /// Get the current rating of a player in a category, or the initial rating for
+    /// guests and players who have not played in it.
+    pub fn rating(&self, user: Option<&str>, category: &str) -> u32 {
+        let ratings = self.ratings.lock().unwrap();
+        user.and_then(|user| ratings.get(user)).and_then(|ratings| ratings.get(category))
+            .map_or(INITIAL_RATING, |rating| rating.rating).round() as u32
+    }
+
+    /// Get the best players in a category, with the highest ratings first, and
+    /// provisional ratings after every established one.
+    pub fn leaderboard(&self, category: &str, limit: usize) -> Vec<Standing> {
+        let now = storage::now_ms();
+        let ratings = self.ratings.lock().unwrap();
+        let mut standings: Vec<(f64, Standing)> = ratings.iter()
+            .filter_map(|(user, ratings)| ratings.get(category).map(|rating| {
+                let rating = rating.decayed(now);
+                (rating.rating, rating.standing(user))
+            }))
+            .collect();
+        standings.sort_by(|&(a, ref x), &(b, ref y)| {
+            match x.provisional.cmp(&y.provisional) {
+                Ordering::Equal => b.partial_cmp(&a).unwrap_or(Ordering::Equal),
+                order => order,
+            }
+        });
+        standings.into_iter().take(limit).map(|(_, standing)| standing).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::Color;
+    use std::fs;
+    use storage::tests::scratch_dir;
+
+    /// Helper function to get a rating with the given rating and deviation, and
+    /// the volatility of a new player.
+    fn rated(rating: f64, deviation: f64) -> Rating {
+        Rating {
+            rating: rating,
+            deviation: deviation,
+            volatility: INITIAL_VOLATILITY,
+            games: 0,
+            updated_ms: 0,
+        }
+    }
+
+    #[test]
+    fn matches_glickmans_example() {
+        let player = rated(1500.0, 200.0);
+        let (a, b, c) = (rated(1400.0, 30.0), rated(1550.0, 100.0), rated(1700.0, 300.0));
+        let after = player.rate_period(&[(&a, 1.0), (&b, 0.0), (&c, 0.0)]);
+        assert!((after.rating - 1464.06).abs() < 0.05, "{}", after.rating);
+        assert!((after.deviation - 151.52).abs() < 0.05, "{}", after.deviation);
+        assert!((after.volatility - 0.05999).abs() < 0.00001, "{}", after.volatility);
+        assert_eq!(after.games, 3);
+    }
+
+    #[test]
+    fn grows_the_deviation_while_idle() {
+        let rating = rated(1800.0, 50.0);
+        assert_eq!(rating.decayed(0).deviation, 50.0);
+        let phi = 50.0 / SCALE;
+        let expected = (phi * phi + 10.0 * INITIAL_VOLATILITY * INITIAL_VOLATILITY).sqrt() * SCALE;
+        let decayed = rating.decayed(10 * PERIOD_MS);
+        assert!((decayed.deviation - expected).abs() < 0.001, "{}", decayed.deviation);
+        assert_eq!(decayed.rating, 1800.0);
+        assert_eq!(decayed.updated_ms, 10 * PERIOD_MS);
+        assert_eq!(rating.decayed(100000 * PERIOD_MS).deviation, INITIAL_DEVIATION);
+    }
+
+    #[test]
+    fn rates_games_and_keeps_them() {
+        let dir = scratch_dir("rates_games_and_keeps_them");
+        fs::create_dir_all(&dir).unwrap();
+        let ratings = Ratings::open(&dir);
+        ratings.record("blitz", "alice", "bob", Some(&Color::White));
+        assert!(ratings.rating(Some("alice"), "blitz") > 1500);
+        assert!(ratings.rating(Some("bob"), "blitz") < 1500);
+        assert_eq!(ratings.rating(None, "blitz"), 1500);
+        let reopened = Ratings::open(&dir);
+        assert_eq!(reopened.rating(Some("alice"), "blitz"),
+                   ratings.rating(Some("alice"), "blitz"));
+        let leaders = reopened.leaderboard("blitz", 10);
+        assert_eq!(leaders.iter().map(|s| s.user.as_str()).collect::<Vec<_>>(), ["alice", "bob"]);
+    }
+}
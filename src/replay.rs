@@ -0,0 +1,74 @@
+//! A module for replaying the event log of a single game. Rebuilds the game one
+//! event at a time with the same code the server uses on startup, and prints each
+//! event together with the board and the state derived from it, so that a bug
+//! report can be reproduced exactly from the log of the game it came from.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use super::game::{Game, Player};
+use super::lobby::Lobby;
+use super::storage::{Record, Storage};
+
+/// Helper function to describe who holds a seat, without printing their token.
+fn describe(player: &Option<Player>) -> String {
+    match *player {
+        Some(ref player) => {
+            let seat = if player.addr.is_empty() {
+                String::from("reserved")
+            } else {
+                player.addr.clone()
+            };
+            match player.user {
+                Some(ref user) => format!("{} ({})", user, seat),
+                None => seat,
+            }
+        },
+        None => String::from("open"),
+    }
+}
+
+/// Helper function to describe a record, without printing the token of a seat.
+fn redacted(record: &Record) -> String {
+    match *record {
+        Record::Seated{game, ref color, ..} => {
+            format!("Seated {{ game: {}, color: {:?} }}", game, color)
+        },
+        ref record => format!("{:?}", record),
+    }
+}
+
+/// Helper function to print the board and the derived state of a game.
+fn print_game(game: &Game) {
+    println!("{}", game.board);
+    println!("turn: {:?}, check: {}, checkmate: {}, moves: {}",
+             game.board.color(), game.check, game.checkmate, game.history.len());
+    println!("white: {}, black: {}", describe(&game.white), describe(&game.black));
+    println!("clocks: {:?}", game.clock.state());
+    if let Some(ref outcome) = game.outcome {
+        println!("outcome: {:?}", outcome);
+    }
+}
+
+/// Replays the event log at the given path, printing every step.
+pub fn main(path: &str) {
+    let events = Storage::load(Path::new(path));
+    let start = events.first().map_or(0, |event| event.at);
+    let mut games = BTreeMap::new();
+    for (step, event) in events.into_iter().enumerate() {
+        println!("#{} +{}ms {}", step, event.at - start, redacted(&event.record));
+        // Only print the game again if the event changed its state.
+        let changed = match event.record {
+            Record::Joined{..} | Record::Left{..} | Record::Resumed{..} |
+            Record::Said{..} => false,
+            _ => true,
+        };
+        let id = Lobby::replay(&mut games, event.record);
+        if changed {
+            if let Some(game) = games.get(&id) {
+                print_game(game);
+            }
+        }
+        println!("");
+    }
+}
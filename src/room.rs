@@ -0,0 +1,1243 @@
+//! A module for the actor that owns a single game. Every game runs as a task on
+//! the games' `Executor`, and is only reached through its bounded mailbox of
+//! `Command`s, so a busy game never holds up another one. Updates reach the
+//! clients in the game through their bounded `Outbox`es, so a slow client never
+//! holds up the game either.
+//!
+//! The actor also enforces the `Abandonment` policy, so that a game whose player
+//! has left does not hang forever: a seat that stays reserved without a connected
+//! client for the grace period, or a first move that is not played in time, ends
+//! the game. Correspondence games are exempt from the policy, since their players
+//! are not expected to stay connected. In every game, the actor ends the game as
+//! soon as the side to move runs out of time, without waiting for them to move.
+//!
+//! A game may be adjourned by the side to move sealing their next move, which is
+//! kept from everyone until a player resumes the game with both players present.
+//! The clocks stay stopped, and the abandonment policy is suspended, in between.
+//!
+//! Players and spectators chat on separate channels: what the players say reaches
+//! everyone in the game, but what spectators say only reaches other spectators,
+//! and not at all if the creator of the game has turned spectator chat off. The
+//! creator may also hold board updates back from spectators for some moves or
+//! seconds, so that they cannot pass the live position on to an outside helper.
+//!
+//! Chat messages that start with a slash are commands, which the actor answers
+//! itself, so that a game can be played entirely through the chat. The creator of
+//! the game and the admins of the server may mute a client, who can then no longer
+//! chat in the game, or kick them, which disconnects them and keeps them out of the
+//! game. Both last as long as the game is kept in memory: for an account, across
+//! reconnects, but for a guest, who is known only by their connection, until they
+//! reconnect. Keeping a guest out for good is what an IP ban is for.
+//!
+//! In correspondence games, players may register conditional moves, which the
+//! actor plays for them as soon as their opponent's move is applied.
+//!
+//! Once a game between two accounts ends, other than by being aborted, the actor
+//! rates it in `Ratings`.
+
+use rand::{self, Rng};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use super::accounts;
+use super::board::{Cell, Color};
+use super::chess_server::{Action, Outbox};
+use super::clock::ClockState;
+use super::commands::{self, Slash};
+use super::conditional;
+use super::game::{Game, Move, Player};
+use super::protocol::Encoded;
+use super::ratings::{self, Ratings};
+use super::storage::{Record, Storage};
+use super::tasks::{self, Executor, Mailbox};
+
+/// How many commands may be waiting for a game before senders wait, or are turned
+/// away if they cannot wait.
+const QUEUE_LEN: usize = 256;
+/// The nickname the answers to chat commands are sent under.
+const SERVER_NICK: &'static str = "server";
+/// What a client kicked from a game is told.
+const KICKED: &'static str = "you were kicked from this game";
+/// What the clients in a game are told when a change to the game could not be saved.
+const UNSAVED: &'static str =
+    "this game could not be saved, and may be lost if the server stops";
+
+/// Represents a request to the actor of a game. Requests that need an answer
+/// carry the channel to send it back on.
+pub enum Command {
+    /// A client enters the game as a spectator. A client logged in to an account
+    /// that a seat is reserved for takes that seat. Otherwise, if the client has
+    /// just connected, they take the first open seat. If the client has just
+    /// connected, the rest of the game is told about them.
+    Enter { addr: String, nick: String, user: Option<String>, outbox: Outbox, connected: bool },
+    /// A reconnecting client enters the game, if their token holds a seat in it.
+    Resume { addr: String, nick: String, user: Option<String>, token: String, outbox: Outbox,
+             reply: mpsc::Sender<bool> },
+    /// A client in the game has changed their nickname.
+    Rename { addr: String, nick: String },
+    /// A client moves to another game. Their seat is released if `release` is set,
+    /// and stays reserved for them otherwise.
+    Leave { addr: String, release: bool },
+    /// A client has disconnected. Their seat stays reserved for them.
+    Disconnect { addr: String },
+    /// An `Action` sent by a client in the game.
+    Client(Action),
+    /// Get a copy of the game.
+    Inspect { reply: mpsc::Sender<Game> },
+    /// Get a copy of the game as spectators see it.
+    Spectate { reply: mpsc::Sender<Game> },
+    /// Play a move for the player whose seat token is given.
+    Play { token: String, from: Cell, to: Cell, reply: mpsc::Sender<Result<(), &'static str>> },
+    /// Replace the conditional moves of the player whose seat token is given.
+    Conditional { token: String, lines: Vec<Vec<Move>>,
+                  reply: mpsc::Sender<Result<(), &'static str>> },
+    /// Abort the game if no move has been played in it yet.
+    AbortUnstarted,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Represents when a game is given up on.
+/// `grace` is how long a seated player may stay disconnected, and `first_move` is
+/// how long each player has to play their first move once both seats are taken;
+/// None means no limit. A game abandoned before both players have moved is always
+/// aborted. After that, it is awarded to the opponent if `forfeit` is set, and
+/// aborted otherwise.
+pub struct Abandonment {
+    pub grace: Option<Duration>,
+    pub first_move: Option<Duration>,
+    pub forfeit: bool,
+}
+
+#[derive(Clone)]
+/// A handle on the actor of a game.
+pub struct Room {
+    mailbox: Mailbox<Actor>,
+    over: Arc<AtomicBool>,
+    correspondence: bool,
+    paired: bool,
+}
+
+/// A client in a game, known to the other clients only by their nickname, and
+/// logged in to `user` if any. `flipped` is set once they have turned the board
+/// around to Black's side with `/flip`.
+struct Member {
+    outbox: Outbox,
+    nick: String,
+    user: Option<String>,
+    flipped: bool,
+}
+
+/// A board update held back from spectators: when it happened, how many moves
+/// had been played, and the clocks.
+struct Frame {
+    at: Instant,
+    moves: usize,
+    clocks: ClockState,
+}
+
+/// The state owned by the actor of a game: the game itself, its log, the
+/// outboxes of the clients in it, the ratings it is rated in, the timers of the
+/// abandonment policy, and the board updates held back from spectators.
+/// `white_away` and `black_away` are when each seat was last left without a
+/// client, and `waiting` is how many moves had been played when the wait for a
+/// first move started, and when it started. `shown` is how many moves spectators
+/// have been shown, and `shown_clocks` the clocks they were last sent, if delayed.
+/// `muted` and `kicked` hold the identities of the clients that have been muted
+/// and kicked by a moderator.
+struct Actor {
+    id: u32,
+    game: Game,
+    storage: Storage,
+    members: HashMap<String, Member>,
+    over: Arc<AtomicBool>,
+    ratings: Arc<Ratings>,
+    abandonment: Abandonment,
+    white_away: Option<Instant>,
+    black_away: Option<Instant>,
+    waiting: Option<(usize, Instant)>,
+    frames: VecDeque<Frame>,
+    shown: usize,
+    shown_clocks: Option<ClockState>,
+    admins: Arc<Vec<String>>,
+    muted: HashSet<String>,
+    kicked: HashSet<String>,
+}
+
+/// Generate a new player token.
+pub fn new_token() -> String {
+    rand::thread_rng().gen_ascii_chars().take(24).collect()
+}
+
+/// Helper function to get when a seat was left without a client, starting now
+/// if it has just been left, or None if it is open or has a client.
+fn away_since(player: &Option<Player>, since: Option<Instant>, now: Instant) -> Option<Instant> {
+    match *player {
+        Some(ref player) if player.addr.is_empty() => Some(since.unwrap_or(now)),
+        _ => None,
+    }
+}
+
+/// Helper function to build an `Action::Board` with the state of a game after its
+/// last move, and the given clocks.
+fn board_update(game: &Game, clocks: ClockState) -> Action {
+    Action::Board {
+        board: game.board.clone(),
+        check: game.check,
+        checkmate: game.checkmate,
+        last: game.history.last().cloned(),
+        clocks: clocks,
+    }
+}
+
+/// Helper function to get what identifies a client to moderators: their account,
+/// ignoring case, or their connection address, with its port, for guests. Guests
+/// behind the same address, such as a school or a carrier's NAT, are told apart.
+fn identity(addr: &str, user: Option<&String>) -> String {
+    match user {
+        Some(user) => user.to_lowercase(),
+        None => String::from(addr),
+    }
+}
+
+/// Helper function to get when a timer started at `since` runs out.
+fn expiry(since: Option<Instant>, limit: Option<Duration>) -> Option<Instant> {
+    match (since, limit) {
+        (Some(since), Some(limit)) => Some(since + limit),
+        _ => None,
+    }
+}
+
+impl Room {
+    /// Spawn the actor of a game on `executor`, which appends to the game's log in
+    /// `storage`, rates the game in `ratings`, ends the game according to
+    /// `abandonment` and lets `admins` moderate it.
+    pub fn spawn(executor: &Executor, id: u32, game: Game, storage: Storage,
+                 ratings: Arc<Ratings>, abandonment: Abandonment, admins: Arc<Vec<String>>)
+                 -> Room {
+        let over = Arc::new(AtomicBool::new(game.outcome.is_some()));
+        let correspondence = game.clock.is_per_move();
+        let paired = game.paired;
+        // Updates held back when the server stopped are held back again.
+        let shown = if game.outcome.is_none() {
+            game.history.len().saturating_sub(game.spectating.delay_moves as usize)
+        } else {
+            game.history.len()
+        };
+        let mut actor = Actor {
+            id: id,
+            game: game,
+            storage: storage,
+            members: HashMap::new(),
+            over: over.clone(),
+            ratings: ratings,
+            abandonment: abandonment,
+            white_away: None,
+            black_away: None,
+            waiting: None,
+            frames: VecDeque::new(),
+            shown: shown,
+            shown_clocks: None,
+            admins: admins,
+            muted: HashSet::new(),
+            kicked: HashSet::new(),
+        };
+        // A conditional reply may have been left unplayed when the server stopped.
+        actor.play_conditional();
+        Room {
+            mailbox: executor.spawn(actor, QUEUE_LEN),
+            over: over,
+            correspondence: correspondence,
+            paired: paired,
+        }
+    }
+
+    /// Send a command to the game, waiting if its queue is full.
+    pub fn send(&self, command: Command) {
+        self.mailbox.send(command);
+    }
+
+    /// Send a command to the game without waiting. Returns the command if the
+    /// game's queue is full.
+    pub fn try_send(&self, command: Command) -> Result<(), Command> {
+        self.mailbox.try_send(command)
+    }
+
+    /// Send a command to the game without waiting, even if its queue is full, for
+    /// the commands that keep track of who is in the game, which must never be
+    /// lost.
+    pub fn push(&self, command: Command) {
+        self.mailbox.push(command);
+    }
+
+    /// Check if the game is over, without waiting for the game.
+    pub fn is_over(&self) -> bool {
+        self.over.load(Ordering::SeqCst)
+    }
+
+    /// Check if the game is a correspondence game.
+    pub fn is_correspondence(&self) -> bool {
+        self.correspondence
+    }
+
+    /// Check if the game was created for a pairing.
+    pub fn is_paired(&self) -> bool {
+        self.paired
+    }
+
+    /// Try to resume a client's seat in the game. Returns whether their token
+    /// holds a seat in it, in which case they have entered the game.
+    pub fn resume(&self, addr: &str, nick: &str, user: Option<&str>, token: &str,
+                  outbox: Outbox) -> bool {
+        let (reply, answer) = mpsc::channel();
+        self.send(Command::Resume{addr: String::from(addr), nick: String::from(nick),
+                                  user: user.map(String::from), token: String::from(token),
+                                  outbox: outbox, reply: reply});
+        answer.recv().unwrap_or(false)
+    }
+
+    /// Get a copy of the game.
+    pub fn inspect(&self) -> Option<Game> {
+        let (reply, answer) = mpsc::channel();
+        self.send(Command::Inspect{reply: reply});
+        answer.recv().ok()
+    }
+
+    /// Get a copy of the game as spectators see it, which lags behind the game if
+    /// board updates are held back from spectators.
+    pub fn spectate(&self) -> Option<Game> {
+        let (reply, answer) = mpsc::channel();
+        self.send(Command::Spectate{reply: reply});
+        answer.recv().ok()
+    }
+
+    /// Play a move for the player whose seat token is given, after checking that
+    /// it is their turn and that the move is legal.
+    pub fn play(&self, token: &str, from: Cell, to: Cell) -> Result<(), &'static str> {
+        let (reply, answer) = mpsc::channel();
+        self.send(Command::Play{token: String::from(token), from: from, to: to, reply: reply});
+        answer.recv().unwrap_or(Err("no such game"))
+    }
+
+    /// Abort the game if no move has been played in it yet, without waiting for the
+    /// game.
+    pub fn abort_unstarted(&self) {
+        self.send(Command::AbortUnstarted);
+    }
+
+    /// Replace the conditional moves of the player whose seat token is given with
+    /// the given lines, after checking that they are legal.
+    pub fn set_conditional(&self, token: &str, lines: Vec<Vec<Move>>)
+                           -> Result<(), &'static str> {
+        let (reply, answer) = mpsc::channel();
+        self.send(Command::Conditional{token: String::from(token), lines: lines, reply: reply});
+        answer.recv().unwrap_or(Err("no such game"))
+    }
+}
+
+/// The actor is ticked after every batch of commands, and at its deadline: the
+/// game is ended if it has been abandoned or the side to move has run out of
+/// time, board updates that have been held back long enough are sent to
+/// spectators, and whether it is over is published.
+impl tasks::Actor for Actor {
+    type Message = Command;
+
+    fn handle(&mut self, command: Command) {
+        Actor::handle(self, command);
+    }
+
+    fn tick(&mut self) -> Option<Instant> {
+        self.check_abandoned();
+        self.check_timeout();
+        self.release_frames();
+        self.over.store(self.game.outcome.is_some(), Ordering::SeqCst);
+        self.deadline()
+    }
+}
+
+impl Actor {
+    /// Handle a single command.
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Enter{addr, nick, user, outbox, connected} => {
+                if self.kicked.contains(&identity(&addr, user.as_ref())) {
+                    outbox.send(&Action::Msg{user: String::from(SERVER_NICK),
+                                             text: String::from(KICKED)});
+                    return;
+                }
+                let reserved = user.as_ref().and_then(|user| self.game.reserved_for(user));
+                let token = if let Some((color, token)) = reserved {
+                    self.game.resume(&addr, &token);
+                    self.record(Record::Resumed{game: self.id, color: color, addr: addr.clone()});
+                    Some(token)
+                } else if connected {
+                    self.take_seat(&addr, user.as_ref().map(|u| u.as_str()), &Color::White)
+                        .or_else(|| {
+                            self.take_seat(&addr, user.as_ref().map(|u| u.as_str()), &Color::Black)
+                        })
+                } else {
+                    None
+                };
+                // Bring the new client up to date before anyone else hears of them.
+                self.enter(&addr, nick.clone(), user, outbox);
+                if let Some(token) = token {
+                    let seat = String::from(self.game.seat(&addr));
+                    self.relay_to(&addr, &Action::Seat{game: self.id, seat: seat, token: token});
+                }
+                if connected {
+                    self.relay_all(&Action::Connect{user: nick});
+                }
+                self.relay_presence();
+            },
+            Command::Resume{addr, nick, user, token, outbox, reply} => {
+                let color = self.game.color_of_token(&token);
+                let resumed = self.game.outcome.is_none() && color.is_some() &&
+                    !self.kicked.contains(&identity(&addr, user.as_ref()));
+                if resumed {
+                    if self.game.color_of(&addr) != color {
+                        // Give up the seat handed out on connect.
+                        self.leave_seat(&addr);
+                    }
+                    let color = self.game.resume(&addr, &token).unwrap();
+                    self.record(Record::Resumed{game: self.id, color: color, addr: addr.clone()});
+                    self.enter(&addr, nick, user, outbox);
+                    let seat = String::from(self.game.seat(&addr));
+                    self.relay_to(&addr, &Action::Seat{game: self.id, seat: seat, token: token});
+                    self.relay_presence();
+                }
+                let _ = reply.send(resumed);
+            },
+            Command::Rename{addr, nick} => {
+                if let Some(member) = self.members.get_mut(&addr) {
+                    member.nick = nick;
+                }
+                self.relay_presence();
+            },
+            Command::Leave{addr, release} => {
+                self.members.remove(&addr);
+                if release {
+                    self.leave_seat(&addr);
+                } else {
+                    self.game.disconnect(&addr);
+                }
+                self.record(Record::Left{game: self.id, addr: addr});
+                self.relay_presence();
+            },
+            Command::Disconnect{addr} => {
+                let member = self.members.remove(&addr);
+                self.game.disconnect(&addr);
+                self.record(Record::Left{game: self.id, addr: addr});
+                if let Some(member) = member {
+                    self.relay_all(&Action::Disconnect{user: member.nick});
+                }
+                self.relay_presence();
+            },
+            Command::Client(action) => self.client(action),
+            Command::Inspect{reply} => {
+                let _ = reply.send(self.game.clone());
+            },
+            Command::Spectate{reply} => {
+                let _ = reply.send(self.spectated().0);
+            },
+            Command::Play{token, from, to, reply} => {
+                let result = self.play_token(&token, from, to);
+                let _ = reply.send(result);
+            },
+            Command::Conditional{token, lines, reply} => {
+                let result = self.set_conditional(&token, &lines);
+                let _ = reply.send(result);
+            },
+            Command::AbortUnstarted => {
+                if self.game.outcome.is_none() && self.game.history.is_empty() {
+                    self.end(None, "aborted");
+                }
+            },
+        }
+    }
+
+    /// Update the timers of the abandonment policy, and end the game if one of
+    /// them has run out.
+    fn check_abandoned(&mut self) {
+        if self.game.outcome.is_some() || self.game.clock.is_per_move() ||
+                self.game.sealed.is_some() {
+            self.white_away = None;
+            self.black_away = None;
+            self.waiting = None;
+            return;
+        }
+        let now = Instant::now();
+        self.white_away = away_since(&self.game.white, self.white_away, now);
+        self.black_away = away_since(&self.game.black, self.black_away, now);
+        let moves = self.game.history.len();
+        self.waiting = match (&self.game.white, &self.game.black, self.waiting) {
+            (&Some(_), &Some(_), Some((started, since))) if moves < 2 && started == moves => {
+                Some((started, since))
+            },
+            (&Some(_), &Some(_), _) if moves < 2 => Some((moves, now)),
+            _ => None,
+        };
+
+        let grace = self.abandonment.grace;
+        let white_gone = expiry(self.white_away, grace).map_or(false, |at| at <= now);
+        let black_gone = expiry(self.black_away, grace).map_or(false, |at| at <= now);
+        let winner = match (white_gone, black_gone) {
+            (true, false) => Some(Color::Black),
+            (false, true) => Some(Color::White),
+            _ => None,
+        };
+        if winner.is_some() && self.abandonment.forfeit && moves >= 2 {
+            self.end(winner, "abandonment");
+        } else if white_gone || black_gone {
+            self.end(None, "aborted");
+        } else if expiry(self.waiting.map(|(_, since)| since), self.abandonment.first_move)
+                .map_or(false, |at| at <= now) {
+            self.end(None, "aborted");
+        }
+    }
+
+    /// End the game if the side to move has run out of time, so that a player who
+    /// stays connected without moving still loses on time, in live and
+    /// correspondence games alike.
+    fn check_timeout(&mut self) {
+        if self.game.outcome.is_some() || self.game.clock.expires().is_none() {
+            return;
+        }
+        let color = self.game.board.color();
+        if self.game.clock.remaining(&color) == 0 {
+            self.end(Some(color.other()), "timeout");
+        }
+    }
+
+    /// Helper function to get when the next timer of the abandonment policy, or
+    /// the running clock, runs out, if any is running, or when the oldest board
+    /// update held back from spectators may be sent.
+    fn deadline(&self) -> Option<Instant> {
+        let grace = self.abandonment.grace;
+        let waiting = self.waiting.map(|(_, since)| since);
+        let flag = if self.game.outcome.is_none() { self.game.clock.expires() } else { None };
+        let delay_moves = self.game.spectating.delay_moves as usize;
+        let delay = Duration::from_secs(self.game.spectating.delay_secs as u64);
+        let frame = self.frames.front()
+            .and_then(|frame| if self.game.history.len() >= frame.moves + delay_moves {
+                Some(frame.at + delay)
+            } else {
+                None
+            });
+        vec![expiry(self.white_away, grace), expiry(self.black_away, grace),
+             expiry(waiting, self.abandonment.first_move), flag, frame]
+            .into_iter().filter_map(|at| at).min()
+    }
+
+    /// End the game without a move, storing the outcome and telling every client
+    /// in the game.
+    fn end(&mut self, winner: Option<Color>, reason: &str) {
+        info!("game {} {}", self.id, reason);
+        self.game.finish(winner, reason);
+        let outcome = self.game.outcome.clone().unwrap();
+        self.record(Record::Finished{game: self.id, outcome: outcome.clone()});
+        self.rate();
+        self.flush_frames();
+        self.relay_all(&Action::Over{outcome: outcome});
+    }
+
+    /// Rate the game once it is over, if it is a rated game that was played between
+    /// two different accounts and not aborted.
+    fn rate(&self) {
+        if !self.game.rated {
+            return;
+        }
+        let winner = match self.game.outcome {
+            Some(ref outcome) if outcome.reason != "aborted" => outcome.winner.as_ref(),
+            _ => return,
+        };
+        let (white, black) = match (self.game.user(&Color::White), self.game.user(&Color::Black)) {
+            (Some(white), Some(black)) if white != black => (white, black),
+            _ => return,
+        };
+        let category = if self.game.clock.is_per_move() {
+            "correspondence"
+        } else {
+            let (initial_ms, increment_ms) = self.game.clock.time_control();
+            ratings::category(initial_ms, increment_ms)
+        };
+        self.ratings.record(category, white, black, winner);
+    }
+
+    /// Handle an `Action` sent by a client in the game.
+    ///
+    /// * If it receives an `Action::TakeSeat` or `Action::LeaveSeat`, it will update the
+    /// seats of the game and then send an `Action::Seat` with the resulting seat to the
+    /// requesting client only, followed by an `Action::Presence` to all clients in the
+    /// game.
+    /// * If it receives an `Action::Select`, it will calculate the potential_moves
+    /// that can be made by that cell, and then send an `Action::Moves` to the
+    /// sender only.
+    /// * If it receives an `Action::Move` that is legal, it will adjust the board state,
+    /// check for check and checkmate, switch turns, and then send an `Action::Board` with
+    /// all the state to all clients in the game, followed by an `Action::Over` if the move
+    /// ended the game.
+    /// * If it receives an `Action::Say`, it will send an `Action::Msg` labeled with the
+    /// sender's nickname to all clients in the game.
+    /// * If it receives an `Action::Berserk` from a player who may go berserk, it will
+    /// halve their clock, announce it with an `Action::Msg`, and send an `Action::Board`
+    /// with the new clocks to all clients in the game.
+    /// * If it receives an `Action::Say` from a spectator, it will only send the
+    /// `Action::Msg` to the other spectators, if spectator chat is on.
+    /// * If it receives an `Action::Say` with a chat command, it will carry out the
+    /// command, and answer the sender only with an `Action::Msg`, except that muting
+    /// and kicking a client is announced to all clients in the game.
+    /// * If it receives an `Action::Say` from a muted client, it will only tell them
+    /// that they are muted.
+    /// * If it receives an `Action::Spectators` from the creator of the game, it will
+    /// change what spectators may do and see, and send it back to all clients in the
+    /// game.
+    /// * If it receives an `Action::Seal` with a legal move from the side to move, it will
+    /// keep the move hidden, stop the clocks, and send an `Action::Adjourned` to all
+    /// clients in the game.
+    /// * If it receives an `Action::Unseal` from a player of an adjourned game while both
+    /// players are connected, it will reveal the sealed move with an `Action::Msg`, and
+    /// then play it.
+    fn client(&mut self, action: Action) {
+        match action {
+            Action::TakeSeat{addr, color} => {
+                let user = self.members.get(&addr).and_then(|member| member.user.clone());
+                let token = self.take_seat(&addr, user.as_ref().map(|u| u.as_str()), &color)
+                    .unwrap_or(String::new());
+                let seat = String::from(self.game.seat(&addr));
+                self.relay_to(&addr, &Action::Seat{game: self.id, seat: seat, token: token});
+                if self.game.color_of(&addr).is_some() && self.is_delayed() {
+                    // A spectator who became a player is shown the live game.
+                    let snapshot = self.snapshot(&addr);
+                    self.relay_to(&addr, &snapshot);
+                }
+                self.relay_presence();
+            },
+            Action::LeaveSeat{addr} => {
+                self.leave_seat(&addr);
+                self.relay_to(&addr, &Action::Seat{game: self.id, seat: String::from("Spectator"),
+                                                   token: String::new()});
+                self.relay_presence();
+            },
+            Action::Select{addr, cell} => {
+                if !self.game.is_turn(&addr) {
+                    return;
+                }
+                let moves = Action::Moves{cells: self.game.board.legal_moves(&cell)};
+                self.relay_to(&addr, &moves);
+            },
+            Action::Move{addr, from, to} => {
+                if !self.game.is_turn(&addr) || !self.game.board.is_legal(&from, &to) {
+                    return;
+                }
+                self.play(from, to);
+            },
+            Action::Say{addr, text} => {
+                let (user, sender) = match self.members.get(&addr) {
+                    Some(member) => (member.nick.clone(), identity(&addr, member.user.as_ref())),
+                    None => return,
+                };
+                if let Some(command) = commands::parse(&text) {
+                    self.command(&addr, command);
+                    return;
+                }
+                if self.muted.contains(&sender) {
+                    return self.tell(&addr, "you are muted in this game");
+                }
+                let spectator = self.game.color_of(&addr).is_none();
+                if spectator && !self.game.spectating.chat {
+                    if let Some(member) = self.members.get(&addr) {
+                        member.outbox.send_error("spectator chat is turned off in this game");
+                    }
+                    return;
+                }
+                self.record(Record::Said{game: self.id, user: user.clone(), text: text.clone()});
+                let message = Action::Msg{user: user, text: text};
+                if spectator {
+                    self.relay_group(&message, true);
+                } else {
+                    self.relay_all(&message);
+                }
+            },
+            Action::Spectators{addr, spectating} => {
+                let user = self.members.get(&addr).and_then(|member| member.user.clone());
+                if user.is_none() || user != self.game.creator {
+                    return;
+                }
+                self.record(Record::Spectating{game: self.id, spectating: spectating.clone()});
+                self.game.spectating = spectating.clone();
+                if !self.is_delayed() {
+                    self.flush_frames();
+                }
+                self.relay_all(&Action::Spectators{addr: String::new(), spectating: spectating});
+            },
+            Action::Berserk{addr} => {
+                let color = match self.game.color_of(&addr) {
+                    Some(color) => color,
+                    None => return,
+                };
+                if !self.game.go_berserk(&color) {
+                    return;
+                }
+                self.record(Record::Berserked{game: self.id, color: color});
+                if let Some(nick) = self.members.get(&addr).map(|member| member.nick.clone()) {
+                    self.relay_all(&Action::Msg{user: nick, text: String::from("went berserk!")});
+                }
+                self.relay_board();
+            },
+            Action::Seal{addr, from, to} => {
+                if !self.game.is_turn(&addr) || !self.game.board.is_legal(&from, &to) {
+                    return;
+                }
+                let color = self.game.board.color();
+                self.game.seal(from.clone(), to.clone());
+                let white_ms = self.game.clock.remaining(&Color::White);
+                let black_ms = self.game.clock.remaining(&Color::Black);
+                self.record(Record::Sealed{game: self.id, from: from, to: to, white_ms: white_ms,
+                                           black_ms: black_ms});
+                info!("game {} adjourned", self.id);
+                self.relay_all(&Action::Adjourned{game: self.id, sealed_by: color});
+            },
+            Action::Unseal{addr} => {
+                let present = |player: &Option<Player>| {
+                    player.as_ref().map_or(false, |player| !player.addr.is_empty())
+                };
+                if self.game.color_of(&addr).is_none() || !present(&self.game.white) ||
+                        !present(&self.game.black) {
+                    return;
+                }
+                let sealed = match self.game.unseal() {
+                    Some(sealed) => sealed,
+                    None => return,
+                };
+                self.record(Record::Unsealed{game: self.id});
+                let san = self.game.board.san(&sealed.from, &sealed.to);
+                if let Some(nick) = self.members.get(&addr).map(|member| member.nick.clone()) {
+                    let message = Action::Msg{user: nick,
+                                              text: format!("opened the sealed move: {}", san)};
+                    // Spectators see the move once its board update reaches them.
+                    let delayed = self.is_delayed();
+                    self.relay_group(&message, false);
+                    if !delayed {
+                        self.relay_group(&message, true);
+                    }
+                }
+                self.play(sealed.from, sealed.to);
+            },
+            _ => {},
+        }
+    }
+
+    /// Carry out a chat command sent by a client in the game, or tell them why it
+    /// could not be understood.
+    fn command(&mut self, addr: &str, command: Result<Slash, String>) {
+        let command = match command {
+            Ok(command) => command,
+            Err(error) => return self.tell(addr, &error),
+        };
+        let color = self.game.color_of(addr);
+        let playing = color.is_some() && self.game.outcome.is_none();
+        match command {
+            Slash::Move(text) => {
+                if !playing {
+                    return self.tell(addr, "you are not playing in this game");
+                } else if self.game.sealed.is_some() {
+                    return self.tell(addr, "the game is adjourned");
+                } else if !self.game.is_turn(addr) {
+                    return self.tell(addr, "it is not your turn");
+                }
+                match commands::find_move(&self.game.board, &text) {
+                    Ok((from, to)) => self.play(from, to),
+                    Err(e) => self.tell(addr, &e),
+                }
+            },
+            Slash::Resign | Slash::Draw if !playing => {
+                self.tell(addr, "you are not playing in this game");
+            },
+            Slash::Resign => {
+                let winner = color.unwrap().other();
+                self.end(Some(winner), "resignation");
+            },
+            Slash::Draw => {
+                let color = color.unwrap();
+                if self.game.draw_offer == Some(color.other()) {
+                    self.end(None, "agreement");
+                    return;
+                }
+                self.game.draw_offer = Some(color);
+                if let Some(nick) = self.members.get(addr).map(|member| member.nick.clone()) {
+                    self.relay_all(&Action::Msg{user: nick, text: String::from(
+                        "offers a draw, type /draw to accept")});
+                }
+            },
+            Slash::Flip => {
+                let flipped = match self.members.get_mut(addr) {
+                    Some(member) => {
+                        member.flipped = !member.flipped;
+                        member.flipped
+                    },
+                    None => return,
+                };
+                let diagram = self.view(addr).board.diagram(flipped);
+                self.tell(addr, &diagram);
+            },
+            Slash::Fen => {
+                let fen = self.view(addr).fen();
+                self.tell(addr, &fen);
+            },
+            Slash::Pgn => {
+                let pgn = self.view(addr).pgn(self.id);
+                self.tell(addr, &pgn);
+            },
+            Slash::Who => {
+                let turn = self.view(addr).board.color();
+                let who = match self.presence() {
+                    Action::Presence{white, black, spectators} => {
+                        let spectators = if spectators.is_empty() {
+                            String::from("-")
+                        } else {
+                            spectators.join(", ")
+                        };
+                        format!("White: {}, Black: {}, Spectators: {}. {:?} to move.",
+                                white.unwrap_or(String::from("-")),
+                                black.unwrap_or(String::from("-")), spectators, turn)
+                    },
+                    _ => return,
+                };
+                self.tell(addr, &who);
+            },
+            Slash::Help => self.tell(addr, commands::HELP),
+            Slash::Mute(_) | Slash::Unmute(_) | Slash::Kick(_) if !self.may_moderate(addr) => {
+                self.tell(addr, "only the creator of the game may do that");
+            },
+            Slash::Mute(nick) => {
+                if let Some((_, identity)) = self.find_member(addr, &nick) {
+                    self.muted.insert(identity);
+                    self.relay_all(&Action::Msg{user: String::from(SERVER_NICK),
+                                                text: format!("{} was muted", nick)});
+                }
+            },
+            Slash::Unmute(nick) => {
+                if let Some((_, identity)) = self.find_member(addr, &nick) {
+                    self.muted.remove(&identity);
+                    self.relay_all(&Action::Msg{user: String::from(SERVER_NICK),
+                                                text: format!("{} was unmuted", nick)});
+                }
+            },
+            Slash::Kick(nick) => {
+                let (kicked, identity) = match self.find_member(addr, &nick) {
+                    Some(found) => found,
+                    None => return,
+                };
+                info!("{} was kicked from game {}", kicked, self.id);
+                self.kicked.insert(identity);
+                self.tell(&kicked, KICKED);
+                if let Some(member) = self.members.get(&kicked) {
+                    // The client is cleaned up like any other disconnect.
+                    member.outbox.close();
+                }
+                self.relay_all(&Action::Msg{user: String::from(SERVER_NICK),
+                                            text: format!("{} was kicked", nick)});
+            },
+        }
+    }
+
+    /// Helper function to check if a client may moderate the game: they must be
+    /// logged in to the account that created it, or to an admin.
+    fn may_moderate(&self, addr: &str) -> bool {
+        match self.members.get(addr).and_then(|member| member.user.as_ref()) {
+            Some(user) => {
+                self.game.creator.as_ref() == Some(user) || accounts::is_admin(&self.admins, user)
+            },
+            None => false,
+        }
+    }
+
+    /// Helper function to find the client in the game a moderator means by a
+    /// nickname, ignoring case. Returns their address and identity, or tells the
+    /// moderator why there is no one to moderate.
+    fn find_member(&self, addr: &str, nick: &str) -> Option<(String, String)> {
+        let lower = nick.to_lowercase();
+        let found = self.members.iter()
+            .find(|&(_, member)| member.nick.to_lowercase() == lower)
+            .map(|(other, member)| (other.clone(), identity(other, member.user.as_ref())));
+        match found {
+            Some((ref other, _)) if other == addr => {
+                self.tell(addr, "you cannot moderate yourself");
+                None
+            },
+            Some(found) => Some(found),
+            None => {
+                self.tell(addr, &format!("no one called {} is in this game", nick));
+                None
+            },
+        }
+    }
+
+    /// Helper function to get the game as the given client sees it.
+    fn view(&self, addr: &str) -> Game {
+        if self.game.color_of(addr).is_none() {
+            self.spectated().0
+        } else {
+            self.game.clone()
+        }
+    }
+
+    /// Helper function to append a record to the game's log. If it cannot be
+    /// written, everyone in the game is told that the game may be lost if the
+    /// server stops, and the game goes on.
+    fn record(&mut self, record: Record) {
+        if let Err(e) = self.storage.append(self.id, &record) {
+            error!("could not log {:?} for game {}: {}", record, self.id, e);
+            self.relay_all(&Action::Msg{user: String::from(SERVER_NICK),
+                                        text: String::from(UNSAVED)});
+        }
+    }
+
+    /// Helper function to answer a single client in the game.
+    fn tell(&self, addr: &str, text: &str) {
+        self.relay_to(addr, &Action::Msg{user: String::from(SERVER_NICK),
+                                         text: String::from(text)});
+    }
+
+    /// Helper function to send an `Action` to a single client in the game.
+    fn relay_to(&self, addr: &str, action: &Action) {
+        if let Some(member) = self.members.get(addr) {
+            member.outbox.send(action);
+        }
+    }
+
+    /// Helper function to send an `Action` to every spectator in the game if
+    /// `spectators` is set, or to every player otherwise.
+    fn relay_group(&self, action: &Action, spectators: bool) {
+        let encoded = Encoded::new(action);
+        for (addr, member) in &self.members {
+            if self.game.color_of(addr).is_none() == spectators {
+                member.outbox.send_encoded(&encoded);
+            }
+        }
+    }
+
+    /// Helper function to send an `Action` to every client in the game.
+    fn relay_all(&self, action: &Action) {
+        let encoded = Encoded::new(action);
+        for member in self.members.values() {
+            member.outbox.send_encoded(&encoded);
+        }
+    }
+
+    /// Helper function to build an `Action::Presence` listing the nicknames of the
+    /// connected players and of the spectators in the game.
+    fn presence(&self) -> Action {
+        let nick = |color: Color| self.members.iter()
+            .find(|&(addr, _)| self.game.color_of(addr) == Some(color.clone()))
+            .map(|(_, member)| member.nick.clone());
+        let mut spectators: Vec<String> = self.members.iter()
+            .filter(|&(addr, _)| self.game.color_of(addr).is_none())
+            .map(|(_, member)| member.nick.clone())
+            .collect();
+        spectators.sort();
+        Action::Presence {
+            white: nick(Color::White),
+            black: nick(Color::Black),
+            spectators: spectators,
+        }
+    }
+
+    /// Helper function to send an `Action::Presence` to every client in the game.
+    fn relay_presence(&self) {
+        let presence = self.presence();
+        self.relay_all(&presence);
+    }
+
+    /// Helper function to build an `Action::State` snapshot of the game, as seen
+    /// from the seat held by the given client.
+    fn snapshot(&self, addr: &str) -> Action {
+        let (game, clocks) = if self.game.color_of(addr).is_none() {
+            self.spectated()
+        } else {
+            (self.game.clone(), self.game.clock.state())
+        };
+        Action::State {
+            board: game.board.clone(),
+            turn: game.board.color(),
+            seat: String::from(self.game.seat(addr)),
+            history: game.history,
+            clocks: clocks,
+            check: game.check,
+            checkmate: game.checkmate,
+            game: self.id,
+            outcome: game.outcome,
+        }
+    }
+
+    /// Helper function to check if board updates are held back from spectators.
+    fn is_delayed(&self) -> bool {
+        self.game.spectating.is_delayed() && self.game.outcome.is_none()
+    }
+
+    /// Helper function to get the game as spectators see it, and the clocks they
+    /// were last sent.
+    fn spectated(&self) -> (Game, ClockState) {
+        if self.is_delayed() && self.shown < self.game.history.len() {
+            let clocks = self.shown_clocks.clone().unwrap_or_else(|| self.game.clock.state());
+            (self.game.rewound(self.shown), clocks)
+        } else {
+            (self.game.clone(), self.game.clock.state())
+        }
+    }
+
+    /// Helper function to send spectators the board updates that have been held
+    /// back long enough.
+    fn release_frames(&mut self) {
+        let delay_moves = self.game.spectating.delay_moves as usize;
+        let delay = Duration::from_secs(self.game.spectating.delay_secs as u64);
+        let now = Instant::now();
+        while let Some(frame) = self.frames.pop_front() {
+            if self.game.history.len() < frame.moves + delay_moves || frame.at + delay > now {
+                self.frames.push_front(frame);
+                break;
+            }
+            let update = board_update(&self.game.rewound(frame.moves), frame.clocks.clone());
+            self.relay_group(&update, true);
+            self.shown = frame.moves;
+            self.shown_clocks = Some(frame.clocks);
+        }
+    }
+
+    /// Helper function to bring spectators up to date with the game, e.g. once it
+    /// is over, dropping the board updates held back from them.
+    fn flush_frames(&mut self) {
+        self.frames.clear();
+        self.shown_clocks = None;
+        if self.shown < self.game.history.len() {
+            self.shown = self.game.history.len();
+            let update = board_update(&self.game, self.game.clock.state());
+            self.relay_group(&update, true);
+        }
+    }
+
+    /// Helper function to add a client to the game and send them a snapshot of it.
+    fn enter(&mut self, addr: &str, nick: String, user: Option<String>, outbox: Outbox) {
+        let member = Member{outbox: outbox, nick: nick, user: user, flipped: false};
+        if self.members.insert(String::from(addr), member).is_none() {
+            self.record(Record::Joined{game: self.id, addr: String::from(addr)});
+        }
+        let snapshot = self.snapshot(addr);
+        self.relay_to(addr, &snapshot);
+        let spectating = self.game.spectating.clone();
+        self.relay_to(addr, &Action::Spectators{addr: String::new(), spectating: spectating});
+        if self.game.sealed.is_some() {
+            let sealed_by = self.game.board.color();
+            self.relay_to(addr, &Action::Adjourned{game: self.id, sealed_by: sealed_by});
+        }
+    }
+
+    /// Helper function to send an `Action::Board` with the state after the last move
+    /// to every client in the game, followed by an `Action::Over` if the game ended.
+    /// While board updates are held back from spectators, spectators are sent the
+    /// update later instead.
+    fn relay_board(&mut self) {
+        let update = board_update(&self.game, self.game.clock.state());
+        if self.is_delayed() {
+            self.relay_group(&update, false);
+            self.frames.push_back(Frame{at: Instant::now(), moves: self.game.history.len(),
+                                        clocks: self.game.clock.state()});
+            self.release_frames();
+        } else {
+            self.frames.clear();
+            self.shown = self.game.history.len();
+            self.shown_clocks = None;
+            self.relay_all(&update);
+        }
+        if let Some(ref outcome) = self.game.outcome {
+            self.relay_all(&Action::Over{outcome: outcome.clone()});
+        }
+    }
+
+    /// Seat a client, logged in to `user` if any, as the given color. Returns the
+    /// token that identifies them as that player, or None if the seat could not be
+    /// taken.
+    fn take_seat(&mut self, addr: &str, user: Option<&str>, color: &Color) -> Option<String> {
+        if self.game.outcome.is_some() {
+            return None
+        }
+        let previous = self.game.color_of(addr);
+        if previous.as_ref() == Some(color) {
+            return None
+        }
+        let token = new_token();
+        if !self.game.take_seat(addr, user, color, &token) {
+            return None
+        }
+        if let Some(previous) = previous {
+            self.record(Record::Unseated{game: self.id, color: previous});
+        }
+        self.record(Record::Seated{game: self.id, color: color.clone(), token: token.clone()});
+        if let Some(user) = user {
+            self.record(Record::Claimed{game: self.id, color: color.clone(),
+                                        user: String::from(user)});
+        }
+        Some(token)
+    }
+
+    /// Release the seat held by a client, if any.
+    fn leave_seat(&mut self, addr: &str) {
+        if let Some(color) = self.game.leave_seat(addr) {
+            self.record(Record::Unseated{game: self.id, color: color});
+        }
+    }
+
+    /// Play a move for the player identified by `token`, after checking that it
+    /// is their turn and that the move is legal.
+    fn play_token(&mut self, token: &str, from: Cell, to: Cell) -> Result<(), &'static str> {
+        if self.game.outcome.is_some() {
+            return Err("the game is over")
+        }
+        if self.game.sealed.is_some() {
+            return Err("the game is adjourned")
+        }
+        match self.game.color_of_token(token) {
+            Some(ref color) if *color == self.game.board.color() => {},
+            Some(_) => return Err("it is not your turn"),
+            None => return Err("you are not playing in this game"),
+        }
+        if !self.game.board.is_legal(&from, &to) {
+            return Err("illegal move")
+        }
+        self.play(from, to);
+        Ok(())
+    }
+
+    /// Replace the conditional moves of the player identified by `token`, who must
+    /// be waiting for their opponent's move in a correspondence game.
+    fn set_conditional(&mut self, token: &str, lines: &[Vec<Move>]) -> Result<(), &'static str> {
+        if self.game.outcome.is_some() {
+            return Err("the game is over")
+        }
+        if !self.game.clock.is_per_move() {
+            return Err("conditional moves are only for correspondence games")
+        }
+        let color = match self.game.color_of_token(token) {
+            Some(ref color) if *color == self.game.board.color() => {
+                return Err("conditional moves are only set on your opponent's turn")
+            },
+            Some(color) => color,
+            None => return Err("you are not playing in this game"),
+        };
+        let tree = try!(conditional::build(&self.game.board, lines));
+        self.record(Record::Conditional{game: self.id, color: color.clone(), tree: tree.clone()});
+        *self.game.conditional_mut(&color) = tree;
+        Ok(())
+    }
+
+    /// Play the conditional reply to the last move, if any. A reply that is no
+    /// longer legal discards the rest of the player's conditional moves.
+    fn play_conditional(&mut self) {
+        let reply = match self.game.reply.take() {
+            Some(reply) => reply,
+            None => return,
+        };
+        if self.game.outcome.is_none() && self.game.board.is_legal(&reply.from, &reply.to) {
+            self.play(reply.from, reply.to);
+        } else {
+            let color = self.game.board.color();
+            self.game.conditional_mut(&color).clear();
+            self.record(Record::Conditional{game: self.id, color: color, tree: Vec::new()});
+        }
+    }
+
+    /// Play a move, storing the move and the outcome if the game ended, and then
+    /// update every client in the game. The opponent's conditional reply is then
+    /// played, if there is one.
+    fn play(&mut self, from: Cell, to: Cell) {
+        let color = self.game.board.color();
+        let moves = self.game.history.len();
+        self.game.play(from.clone(), to.clone());
+        if self.game.history.len() > moves {
+            let clock_ms = self.game.clock.remaining(&color);
+            self.record(Record::Moved{game: self.id, from: from, to: to, clock_ms: clock_ms});
+        }
+        if let Some(outcome) = self.game.outcome.clone() {
+            self.record(Record::Finished{game: self.id, outcome: outcome});
+            self.rate();
+        }
+        self.relay_board();
+        self.play_conditional();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use board::Cell;
+    use clock::Clock;
+    use game::Game;
+    use ratings::Ratings;
+    use storage::Storage;
+    use storage::tests::scratch_dir;
+    use std::thread;
+
+    /// Helper function to spawn a game between two reserved seats, with the given
+    /// clock and no abandonment policy.
+    fn spawn(name: &str, clock: Clock) -> Room {
+        let dir = scratch_dir(name);
+        let mut game = Game::new(clock);
+        game.reserve(&Color::White, "white");
+        game.reserve(&Color::Black, "black");
+        let abandonment = Abandonment{grace: None, first_move: None, forfeit: false};
+        Room::spawn(&Executor::new(1), 1, game, Storage::open(&dir),
+                    Arc::new(Ratings::open(&dir)), abandonment, Arc::new(Vec::new()))
+    }
+
+    /// Helper function to play a coordinate move such as "e2e4".
+    fn play(room: &Room, token: &str, coordinate: &str) -> Result<(), &'static str> {
+        room.play(token, Cell::from_notation(&coordinate[..2]).unwrap(),
+                  Cell::from_notation(&coordinate[2..]).unwrap())
+    }
+
+    #[test]
+    fn flags_a_live_game_without_a_move() {
+        let room = spawn("flags_a_live_game_without_a_move", Clock::new(100, 0));
+        play(&room, "white", "e2e4").unwrap();
+        thread::sleep(Duration::from_millis(400));
+        assert!(room.is_over());
+        let outcome = room.inspect().unwrap().outcome.unwrap();
+        assert_eq!(outcome.winner, Some(Color::White));
+        assert_eq!(outcome.reason, "timeout");
+    }
+
+    #[test]
+    fn flags_a_correspondence_game_without_a_move() {
+        let room = spawn("flags_a_correspondence_game_without_a_move", Clock::per_move(100));
+        play(&room, "white", "e2e4").unwrap();
+        thread::sleep(Duration::from_millis(400));
+        let outcome = room.inspect().unwrap().outcome.unwrap();
+        assert_eq!(outcome.winner, Some(Color::White));
+    }
+
+    #[test]
+    fn aborts_only_unstarted_games() {
+        let unstarted = spawn("aborts_only_unstarted_games_1", Clock::new(60000, 0));
+        unstarted.abort_unstarted();
+        let outcome = unstarted.inspect().unwrap().outcome.unwrap();
+        assert_eq!((outcome.winner, outcome.reason.as_str()), (None, "aborted"));
+        let started = spawn("aborts_only_unstarted_games_2", Clock::new(60000, 0));
+        play(&started, "white", "e2e4").unwrap();
+        started.abort_unstarted();
+        assert!(started.inspect().unwrap().outcome.is_none());
+    }
+
+    #[test]
+    fn checks_turns_and_legality() {
+        let room = spawn("checks_turns_and_legality", Clock::new(60000, 0));
+        assert_eq!(play(&room, "black", "e7e5"), Err("it is not your turn"));
+        assert_eq!(play(&room, "nobody", "e2e4"), Err("you are not playing in this game"));
+        assert_eq!(play(&room, "white", "e2e5"), Err("illegal move"));
+        play(&room, "white", "e2e4").unwrap();
+        play(&room, "black", "e7e5").unwrap();
+        assert_eq!(room.inspect().unwrap().history.len(), 2);
+        assert!(!room.is_over());
+    }
+}
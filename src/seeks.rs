@@ -0,0 +1,106 @@
+//! A module for matchmaking. A player posts a seek with the terms of the game they
+//! want: its time control, whether it is rated, the color they want to play, and
+//! the range of ratings they accept for their opponent. Each new seek is paired
+//! with the oldest open seek it is compatible with, and is left open for others to
+//! be paired with if there is none.
+
+use rand::{self, Rng};
+
+use super::board::Color;
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents the terms of a game a player is looking for. `color` is None if
+/// the player does not mind which color they play, and the ratings bound the
+/// opponent's rating in the category of the time control.
+pub struct Terms {
+    pub initial_ms: u64,
+    pub increment_ms: u64,
+    pub rated: bool,
+    pub color: Option<Color>,
+    pub min_rating: Option<u32>,
+    pub max_rating: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+/// Represents an open seek, posted by the client at `addr`, who goes by `nick` and
+/// is logged in to `user` if any. Guests count as having the initial rating.
+pub struct Seek {
+    pub id: u32,
+    pub addr: String,
+    pub nick: String,
+    pub user: Option<String>,
+    pub rating: u32,
+    pub terms: Terms,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// An open seek as listed to clients, without the address of the player.
+pub struct SeekInfo {
+    pub id: u32,
+    pub nick: String,
+    pub rating: u32,
+    pub terms: Terms,
+}
+
+/// Check that the terms of a seek make sense, and that a client logged in to
+/// `user`, if any, may post it.
+pub fn check(terms: &Terms, user: Option<&str>) -> Result<(), &'static str> {
+    if terms.initial_ms == 0 && terms.increment_ms == 0 {
+        return Err("the time control must give the players some time")
+    }
+    if terms.rated && user.is_none() {
+        return Err("only players logged in to an account can play rated games")
+    }
+    match (terms.min_rating, terms.max_rating) {
+        (Some(min), Some(max)) if min > max => Err("the rating range is empty"),
+        _ => Ok(()),
+    }
+}
+
+/// Helper function to check if a rating is in the range that the terms accept.
+fn accepts(terms: &Terms, rating: u32) -> bool {
+    terms.min_rating.map_or(true, |min| rating >= min) &&
+        terms.max_rating.map_or(true, |max| rating <= max)
+}
+
+impl Seek {
+    /// Check if two seeks can be paired: they are from different players, agree
+    /// on the time control and on whether the game is rated, do not want the same
+    /// color, and accept each other's rating.
+    pub fn compatible(&self, other: &Seek) -> bool {
+        let same_player = self.addr == other.addr ||
+            (self.user.is_some() && self.user == other.user);
+        let clashes = match (&self.terms.color, &other.terms.color) {
+            (&Some(ref a), &Some(ref b)) => a == b,
+            _ => false,
+        };
+        !same_player && !clashes &&
+            self.terms.initial_ms == other.terms.initial_ms &&
+            self.terms.increment_ms == other.terms.increment_ms &&
+            self.terms.rated == other.terms.rated &&
+            accepts(&self.terms, other.rating) && accepts(&other.terms, self.rating)
+    }
+
+    /// Decide the colors of two compatible seeks. Returns the seek that plays
+    /// White and the seek that plays Black, picked at random if neither minds.
+    pub fn colors(self, other: Seek) -> (Seek, Seek) {
+        let white = match (&self.terms.color, &other.terms.color) {
+            (&Some(Color::White), _) | (_, &Some(Color::Black)) => true,
+            (&Some(Color::Black), _) | (_, &Some(Color::White)) => false,
+            (&None, &None) => rand::thread_rng().gen(),
+        };
+        if white { (self, other) } else { (other, self) }
+    }
+
+    /// Describe the seek to clients.
+    pub fn info(&self) -> SeekInfo {
+        SeekInfo {
+            id: self.id,
+            nick: self.nick.clone(),
+            rating: self.rating,
+            terms: self.terms.clone(),
+        }
+    }
+}
@@ -0,0 +1,229 @@
+//! A module for persisting games to disk. Every accepted change to a game is
+//! appended as one line of JSON to that game's event log, stamped with the time
+//! it happened, so that a game can be rebuilt step by step by replaying its log,
+//! both when the server starts again and when reproducing a bug report.
+
+use rustc_serialize::json;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::board::{Cell, Color};
+use super::conditional::Branch;
+use super::game::{Outcome, Spectating};
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a single change to a game, as stored in the log.
+///
+/// DO NOT MODIFY existing variants: old logs must still decode.
+pub enum Record {
+    Created { game: u32, initial_ms: u64, increment_ms: u64 },
+    Seated { game: u32, color: Color, token: String },
+    Unseated { game: u32, color: Color },
+    Moved { game: u32, from: Cell, to: Cell, clock_ms: u64 },
+    Finished { game: u32, outcome: Outcome },
+    Joined { game: u32, addr: String },
+    Left { game: u32, addr: String },
+    Resumed { game: u32, color: Color, addr: String },
+    Said { game: u32, user: String, text: String },
+    Claimed { game: u32, color: Color, user: String },
+    Casual { game: u32 },
+    BerserkAllowed { game: u32 },
+    Berserked { game: u32, color: Color },
+    Correspondence { game: u32 },
+    Conditional { game: u32, color: Color, tree: Vec<Branch> },
+    Sealed { game: u32, from: Cell, to: Cell, white_ms: u64, black_ms: u64 },
+    Unsealed { game: u32 },
+    CreatedBy { game: u32, user: String },
+    Spectating { game: u32, spectating: Spectating },
+    Paired { game: u32 },
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// A `Record` along with the time it was logged, in milliseconds since the epoch.
+pub struct Event {
+    pub at: u64,
+    pub record: Record,
+}
+
+/// A directory holding one append-only log of `Event`s per game.
+pub struct Storage {
+    dir: PathBuf,
+    files: HashMap<u32, File>,
+}
+
+/// Replace the contents of a file, such as the accounts or the ratings, only once
+/// the new contents have been written in full, so that a crash never leaves the
+/// file half written.
+pub fn replace(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(file.write_all(contents.as_bytes()));
+        try!(file.sync_all());
+    }
+    fs::rename(&tmp, path)
+}
+
+/// Helper function to get the current time in milliseconds since the epoch.
+pub fn now_ms() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1000 + (now.subsec_nanos() / 1_000_000) as u64
+}
+
+impl Storage {
+    /// Open the log directory at the given path, creating it if it does not exist.
+    pub fn open(dir: &str) -> Storage {
+        fs::create_dir_all(dir).unwrap();
+        Storage {
+            dir: PathBuf::from(dir),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Read the events of every game in the log directory, ordered by game id.
+    pub fn load_all(&self) -> Vec<Vec<Event>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map_or(false, |ext| ext == "log") {
+                if let Some(id) = path.file_stem().and_then(|s| s.to_str())
+                        .and_then(|s| s.parse::<u32>().ok()) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort();
+        ids.iter().map(|id| Storage::load(&self.path(*id))).collect()
+    }
+
+    /// Read every event in the log at the given path. A missing log holds no
+    /// events, and lines that cannot be decoded (e.g. a line cut short by a
+    /// crash) are skipped.
+    pub fn load(path: &Path) -> Vec<Event> {
+        let mut events = Vec::new();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return events,
+        };
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            match json::decode(line.as_str()) {
+                Ok(event) => events.push(event),
+                Err(e) => warn!("skipping bad event {:?} in {}: {}", line, path.display(), e),
+            }
+        }
+        events
+    }
+
+    /// Helper function to get the path of a game's log.
+    fn path(&self, game: u32) -> PathBuf {
+        self.dir.join(format!("{}.log", game))
+    }
+
+    /// Helper function to open a game's log for appending. A line cut short by a
+    /// crash is cut off first, so that the next record starts on a line of its own
+    /// instead of being lost along with it.
+    fn open_log(path: &Path) -> io::Result<File> {
+        if let Ok(mut file) = OpenOptions::new().read(true).write(true).open(path) {
+            let mut contents = Vec::new();
+            try!(file.read_to_end(&mut contents));
+            if contents.last().map_or(false, |&byte| byte != b'\n') {
+                let end = contents.iter().rposition(|&byte| byte == b'\n').map_or(0, |i| i + 1);
+                warn!("cutting off a line cut short at the end of {}", path.display());
+                try!(file.set_len(end as u64));
+            }
+        }
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Append a record to the log of the given game, stamped with the current
+    /// time, and wait until it is on disk. If the log cannot be written, it is
+    /// opened again for the next record.
+    pub fn append(&mut self, game: u32, record: &Record) -> io::Result<()> {
+        if !self.files.contains_key(&game) {
+            let file = try!(Storage::open_log(&self.path(game)));
+            self.files.insert(game, file);
+        }
+        let event = Event{at: now_ms(), record: record.clone()};
+        let line = json::encode(&event).unwrap();
+        let result = {
+            let file = self.files.get_mut(&game).unwrap();
+            writeln!(file, "{}", line).and_then(|_| file.sync_data())
+        };
+        if result.is_err() {
+            self.files.remove(&game);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// Helper function to get an empty directory for a test to store things in,
+    /// named after the test so that tests running at once do not share one.
+    pub fn scratch_dir(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("chess-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        String::from(dir.to_str().unwrap())
+    }
+
+    #[test]
+    fn replays_appended_records_in_order() {
+        let dir = scratch_dir("replays_appended_records_in_order");
+        let mut storage = Storage::open(&dir);
+        storage.append(2, &Record::Casual{game: 2}).unwrap();
+        storage.append(1, &Record::Correspondence{game: 1}).unwrap();
+        storage.append(1, &Record::Unsealed{game: 1}).unwrap();
+        let games = storage.load_all();
+        assert_eq!(games.len(), 2);
+        match (&games[0][0].record, &games[0][1].record, &games[1][0].record) {
+            (&Record::Correspondence{game: 1}, &Record::Unsealed{game: 1},
+             &Record::Casual{game: 2}) => {},
+            other => panic!("unexpected records {:?}", other),
+        }
+    }
+
+    #[test]
+    fn skips_lines_cut_short() {
+        let dir = scratch_dir("skips_lines_cut_short");
+        let mut storage = Storage::open(&dir);
+        storage.append(1, &Record::Casual{game: 1}).unwrap();
+        {
+            let mut file = OpenOptions::new().append(true).open(storage.path(1)).unwrap();
+            write!(file, "{{\"at\":1,\"rec").unwrap();
+        }
+        assert_eq!(Storage::load(&storage.path(1)).len(), 1);
+        assert!(Storage::load(&storage.path(9)).is_empty());
+
+        // Once the server starts again, the next record is not lost with the line.
+        let mut storage = Storage::open(&dir);
+        storage.append(1, &Record::Unsealed{game: 1}).unwrap();
+        let events = Storage::load(&storage.path(1));
+        match (&events[0].record, &events[1].record) {
+            (&Record::Casual{game: 1}, &Record::Unsealed{game: 1}) if events.len() == 2 => {},
+            other => panic!("unexpected records {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fails_to_append_without_a_directory() {
+        let dir = scratch_dir("fails_to_append_without_a_directory");
+        let mut storage = Storage::open(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(storage.append(1, &Record::Casual{game: 1}).is_err());
+        fs::create_dir_all(&dir).unwrap();
+        assert!(storage.append(1, &Record::Casual{game: 1}).is_ok());
+    }
+}
@@ -0,0 +1,449 @@
+//! A module for running actors, such as the games, as tasks on a small pool of
+//! worker threads instead of a thread each. An actor owns its state and handles
+//! the messages sent to it one at a time, so it needs no locks of its own. A task
+//! only takes a worker while it has messages waiting, or once the deadline it
+//! asked for has passed, so thousands of idle games cost no threads at all.
+//!
+//! Each task has a bounded mailbox: senders wait while it is full, or are turned
+//! away if they cannot wait, so a flood of messages slows its senders down rather
+//! than growing without limit. A task is
+//! never run by two workers at once, and hands its worker back after a batch of
+//! messages, so one busy task cannot starve the others.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::sync::atomic::{self, AtomicBool};
+use std::thread;
+use std::time::Instant;
+
+/// How many messages a task handles before handing its worker to another task.
+const BATCH_LEN: usize = 64;
+
+/// Something run as a task: it handles the messages sent to it one at a time.
+pub trait Actor: Send + 'static {
+    type Message: Send + 'static;
+
+    /// Handle a single message.
+    fn handle(&mut self, message: Self::Message);
+
+    /// Do whatever is due now, once the messages that were waiting have been
+    /// handled, or a deadline has passed. Returns when to be run again if no
+    /// message comes first, if ever.
+    fn tick(&mut self) -> Option<Instant>;
+}
+
+#[derive(Clone)]
+/// A handle on the pool of worker threads that tasks run on.
+pub struct Executor {
+    shared: Arc<Shared>,
+}
+
+/// The state shared by the workers, behind one lock: the tasks ready to run, in
+/// the order they became ready, and the deadlines tasks are waiting for.
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    ready: VecDeque<Arc<Run>>,
+    timers: BinaryHeap<Timer>,
+}
+
+/// A deadline a task is waiting for. Timers do not keep a task alive, and are
+/// ordered so that the earliest is at the top of the heap.
+struct Timer {
+    at: Instant,
+    task: Weak<Run>,
+}
+
+impl PartialEq for Timer {
+    fn eq(&self, other: &Timer) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Timer {}
+
+impl PartialOrd for Timer {
+    fn partial_cmp(&self, other: &Timer) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timer {
+    fn cmp(&self, other: &Timer) -> Ordering {
+        other.at.cmp(&self.at)
+    }
+}
+
+/// A task as the workers see it, whatever its actor.
+trait Run: Send + Sync {
+    /// Handle a batch of messages and tick the actor. Returns the actor's next
+    /// deadline, and whether the task should be run again straight away.
+    fn run(&self) -> (Option<Instant>, bool);
+
+    /// Ask for the task to be run at the given time. Returns whether it is earlier
+    /// than the deadline it was waiting for, and so needs a new timer.
+    fn wait_until(&self, at: Instant) -> bool;
+
+    /// Take the run of a timer that has gone off at the given time. Returns whether
+    /// the task should be put on the ready queue.
+    fn wake_at(&self, at: Instant) -> bool;
+}
+
+/// The mailbox and state of a task. `scheduled` is set while the task is on the
+/// ready queue or running, so that it is never run twice at once, `timer` is the
+/// deadline it is waiting for, and `dead` is set once its actor has panicked.
+struct Task<A: Actor> {
+    mailbox: Mutex<VecDeque<A::Message>>,
+    not_full: Condvar,
+    capacity: usize,
+    actor: Mutex<A>,
+    scheduled: AtomicBool,
+    timer: Mutex<Option<Instant>>,
+    dead: AtomicBool,
+}
+
+/// A handle on a task, to send it messages.
+pub struct Mailbox<A: Actor> {
+    task: Arc<Task<A>>,
+    executor: Executor,
+}
+
+impl<A: Actor> Clone for Mailbox<A> {
+    fn clone(&self) -> Mailbox<A> {
+        Mailbox{task: self.task.clone(), executor: self.executor.clone()}
+    }
+}
+
+impl Executor {
+    /// Start a pool of the given number of worker threads.
+    pub fn new(threads: usize) -> Executor {
+        let executor = Executor {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State{ready: VecDeque::new(), timers: BinaryHeap::new()}),
+                changed: Condvar::new(),
+            }),
+        };
+        for _ in 0..threads {
+            let executor = executor.clone();
+            thread::spawn(move || executor.work());
+        }
+        executor
+    }
+
+    /// Start running an actor as a task, with room for `capacity` messages in its
+    /// mailbox. The actor is ticked straight away. The task ends once every
+    /// `Mailbox` on it is gone.
+    pub fn spawn<A: Actor>(&self, actor: A, capacity: usize) -> Mailbox<A> {
+        let task = Arc::new(Task {
+            mailbox: Mutex::new(VecDeque::new()),
+            not_full: Condvar::new(),
+            capacity: capacity,
+            actor: Mutex::new(actor),
+            scheduled: AtomicBool::new(true),
+            timer: Mutex::new(None),
+            dead: AtomicBool::new(false),
+        });
+        self.ready(task.clone());
+        Mailbox{task: task, executor: self.clone()}
+    }
+
+    /// Helper function to put a task on the ready queue, for the next free worker.
+    fn ready(&self, task: Arc<Run>) {
+        self.shared.state.lock().unwrap().ready.push_back(task);
+        self.shared.changed.notify_one();
+    }
+
+    /// Helper function run by each worker: take the next ready task, waiting for
+    /// one or for the earliest timer to go off, and run it.
+    fn work(&self) {
+        loop {
+            let task;
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                loop {
+                    let now = Instant::now();
+                    while state.timers.peek().map_or(false, |timer| timer.at <= now) {
+                        let timer = state.timers.pop().unwrap();
+                        if let Some(task) = timer.task.upgrade() {
+                            if task.wake_at(timer.at) {
+                                state.ready.push_back(task);
+                            }
+                        }
+                    }
+                    if let Some(ready) = state.ready.pop_front() {
+                        task = ready;
+                        break;
+                    }
+                    state = match state.timers.peek().map(|timer| timer.at) {
+                        Some(at) => self.shared.changed.wait_timeout(state, at - now).unwrap().0,
+                        None => self.shared.changed.wait(state).unwrap(),
+                    };
+                }
+            }
+            let (deadline, again) = task.run();
+            if let Some(at) = deadline {
+                if task.wait_until(at) {
+                    let timer = Timer{at: at, task: Arc::downgrade(&task)};
+                    self.shared.state.lock().unwrap().timers.push(timer);
+                    // A sleeping worker may have to wake up earlier than it planned.
+                    self.shared.changed.notify_one();
+                }
+            }
+            if again {
+                self.ready(task);
+            }
+        }
+    }
+}
+
+impl<A: Actor> Run for Task<A> {
+    fn run(&self) -> (Option<Instant>, bool) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut actor = self.actor.lock().unwrap();
+            for _ in 0..BATCH_LEN {
+                let message = match self.mailbox.lock().unwrap().pop_front() {
+                    Some(message) => message,
+                    None => break,
+                };
+                self.not_full.notify_one();
+                actor.handle(message);
+            }
+            actor.tick()
+        }));
+        let deadline = match result {
+            Ok(deadline) => deadline,
+            Err(_) => {
+                error!("a task panicked, and was stopped");
+                self.dead.store(true, atomic::Ordering::SeqCst);
+                // Senders waiting for room would otherwise wait forever.
+                self.mailbox.lock().unwrap().clear();
+                self.not_full.notify_all();
+                return (None, false)
+            },
+        };
+        self.scheduled.store(false, atomic::Ordering::SeqCst);
+        // A message sent while the batch ran found the task scheduled, and left it
+        // to be run again here.
+        let again = !self.mailbox.lock().unwrap().is_empty() &&
+            !self.scheduled.swap(true, atomic::Ordering::SeqCst);
+        (deadline, again)
+    }
+
+    fn wait_until(&self, at: Instant) -> bool {
+        let mut timer = self.timer.lock().unwrap();
+        if timer.map_or(true, |current| at < current) {
+            *timer = Some(at);
+            return true
+        }
+        false
+    }
+
+    fn wake_at(&self, at: Instant) -> bool {
+        let mut timer = self.timer.lock().unwrap();
+        // A timer for a later deadline is left over from before an earlier one.
+        if *timer != Some(at) {
+            return false
+        }
+        *timer = None;
+        !self.dead.load(atomic::Ordering::SeqCst) &&
+            !self.scheduled.swap(true, atomic::Ordering::SeqCst)
+    }
+}
+
+impl<A: Actor> Mailbox<A> {
+    /// Send a message to the task, waiting while its mailbox is full. Messages to a
+    /// task that has panicked are dropped.
+    pub fn send(&self, message: A::Message) {
+        let task = &self.task;
+        {
+            let mut mailbox = task.mailbox.lock().unwrap();
+            while mailbox.len() >= task.capacity && !task.dead.load(atomic::Ordering::SeqCst) {
+                mailbox = task.not_full.wait(mailbox).unwrap();
+            }
+            if task.dead.load(atomic::Ordering::SeqCst) {
+                return;
+            }
+            mailbox.push_back(message);
+        }
+        self.schedule();
+    }
+
+    /// Send a message to the task without waiting. Returns the message if the
+    /// mailbox is full. Messages to a task that has panicked are dropped.
+    pub fn try_send(&self, message: A::Message) -> Result<(), A::Message> {
+        let task = &self.task;
+        {
+            let mut mailbox = task.mailbox.lock().unwrap();
+            if task.dead.load(atomic::Ordering::SeqCst) {
+                return Ok(())
+            }
+            if mailbox.len() >= task.capacity {
+                return Err(message)
+            }
+            mailbox.push_back(message);
+        }
+        self.schedule();
+        Ok(())
+    }
+
+    /// Send a message to the task without waiting, even if its mailbox is full,
+    /// for the few messages that must never be lost. Messages to a task that has
+    /// panicked are dropped.
+    pub fn push(&self, message: A::Message) {
+        let task = &self.task;
+        {
+            let mut mailbox = task.mailbox.lock().unwrap();
+            if task.dead.load(atomic::Ordering::SeqCst) {
+                return;
+            }
+            mailbox.push_back(message);
+        }
+        self.schedule();
+    }
+
+    /// Helper function to hand the task to a worker once a message has been put in
+    /// its mailbox, unless it is already waiting for one.
+    fn schedule(&self) {
+        if !self.task.scheduled.swap(true, atomic::Ordering::SeqCst) {
+            self.executor.ready(self.task.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// An actor that adds up the numbers sent to it, and reports the total on its
+    /// deadline, which is set by sending it zero.
+    struct Adder {
+        total: u32,
+        deadline: Option<Instant>,
+        report: mpsc::Sender<u32>,
+    }
+
+    impl Actor for Adder {
+        type Message = u32;
+
+        fn handle(&mut self, n: u32) {
+            if n == 0 {
+                self.deadline = Some(Instant::now() + Duration::from_millis(100));
+            }
+            self.total += n;
+        }
+
+        fn tick(&mut self) -> Option<Instant> {
+            if self.deadline.map_or(false, |at| at <= Instant::now()) {
+                self.deadline = None;
+                self.report.send(self.total).unwrap();
+            }
+            self.deadline
+        }
+    }
+
+    #[test]
+    fn runs_many_tasks_on_few_threads_in_order() {
+        let executor = Executor::new(2);
+        let (report, reports) = mpsc::channel();
+        let tasks: Vec<Mailbox<Adder>> = (0..100).map(|_| {
+            executor.spawn(Adder{total: 0, deadline: None, report: report.clone()}, 4)
+        }).collect();
+        for n in 1..11 {
+            for task in &tasks {
+                task.send(n);
+            }
+        }
+        for task in &tasks {
+            task.send(0);
+        }
+        for _ in 0..100 {
+            assert_eq!(reports.recv_timeout(Duration::from_secs(5)), Ok(55));
+        }
+    }
+
+    #[test]
+    fn runs_a_task_at_its_deadline() {
+        let executor = Executor::new(1);
+        let (report, reports) = mpsc::channel();
+        let task = executor.spawn(Adder{total: 0, deadline: None, report: report}, 4);
+        let sent = Instant::now();
+        task.send(7);
+        task.send(0);
+        assert_eq!(reports.recv_timeout(Duration::from_secs(5)), Ok(7));
+        assert!(sent.elapsed() >= Duration::from_millis(100));
+    }
+
+    /// An actor that reports each message it starts handling, and then waits for
+    /// the gate to open before it finishes.
+    struct Gated {
+        started: mpsc::Sender<u32>,
+        gate: mpsc::Receiver<()>,
+    }
+
+    impl Actor for Gated {
+        type Message = u32;
+
+        fn handle(&mut self, n: u32) {
+            self.started.send(n).unwrap();
+            let _ = self.gate.recv();
+        }
+
+        fn tick(&mut self) -> Option<Instant> {
+            None
+        }
+    }
+
+    #[test]
+    fn turns_messages_away_from_a_full_mailbox_unless_pushed() {
+        let executor = Executor::new(1);
+        let (started, starts) = mpsc::channel();
+        let (open, gate) = mpsc::channel();
+        let task = executor.spawn(Gated{started: started, gate: gate}, 2);
+        task.send(1);
+        assert_eq!(starts.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert_eq!(task.try_send(2), Ok(()));
+        assert_eq!(task.try_send(3), Ok(()));
+        assert_eq!(task.try_send(4), Err(4));
+        task.push(5);
+        drop(open);
+        for n in vec![2, 3, 5] {
+            assert_eq!(starts.recv_timeout(Duration::from_secs(5)), Ok(n));
+        }
+    }
+
+    /// An actor that panics on any message.
+    struct Faulty;
+
+    impl Actor for Faulty {
+        type Message = ();
+
+        fn handle(&mut self, _: ()) {
+            panic!("faulty");
+        }
+
+        fn tick(&mut self) -> Option<Instant> {
+            None
+        }
+    }
+
+    #[test]
+    fn stops_a_task_that_panics_without_stopping_the_others() {
+        let executor = Executor::new(1);
+        let faulty = executor.spawn(Faulty, 1);
+        for _ in 0..10 {
+            faulty.send(());
+        }
+        let (report, reports) = mpsc::channel();
+        let task = executor.spawn(Adder{total: 0, deadline: None, report: report}, 4);
+        task.send(3);
+        task.send(0);
+        assert_eq!(reports.recv_timeout(Duration::from_secs(5)), Ok(3));
+    }
+}
@@ -0,0 +1,855 @@
+//! A module for tournaments between players with accounts. An organizer creates a
+//! tournament, players register for it, and once it starts each round is paired
+//! and its games are created in the lobby, with the seats reserved for the paired
+//! players, who take them by joining the game while logged in. The director
+//! thread collects the results of finished games, and pairs the next round once
+//! every result of the current one is in. The players of each new game are summoned
+//! into it, if they are connected. Games that are aborted, such as when a player
+//! never shows up, are left for the organizer to decide.
+//!
+//! Two systems are supported:
+//!
+//! * A round-robin, paired with Berger tables, where everyone plays everyone once.
+//! With an odd number of players, whoever would play the extra player sits out the
+//! round, for no points.
+//! * A Swiss, paired with a simplified Dutch system, for a set number of rounds.
+//! Players are ranked by score and then by seed, the top half of each score group
+//! plays the bottom half without rematches, and a player who cannot be paired in
+//! their group floats down to the next one, within a bounded number of attempts,
+//! after which the tournament is finished early. Colors are given to even out each
+//! player's whites and blacks. With an odd number of players, the lowest ranked
+//! player who has not had a bye gets one, worth a win.
+//!
+//! Standings are ordered by score, then by Buchholz, the sum of the opponents'
+//! scores, and then by Sonneborn-Berger, the sum of the scores of the opponents
+//! beaten plus half those of the opponents drawn. Tournaments are stored in
+//! `tournaments.json` in the store directory.
+
+use rustc_serialize::json;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::board::Color;
+use super::chess_server::Relay;
+use super::lobby::Lobby;
+use super::ratings::{self, Ratings};
+use super::storage;
+
+/// The name of the file tournaments are stored in, in the store directory.
+const FILE_NAME: &'static str = "tournaments.json";
+/// How often the director thread collects the results of games.
+const DIRECTOR_SECS: u64 = 5;
+/// The longest name of a tournament that is accepted.
+const NAME_LEN: usize = 60;
+/// How many opponents are tried for the players of a Swiss round before giving up,
+/// since trying every way to pair them takes exponential time.
+const PAIRING_ATTEMPTS: usize = 100000;
+
+/// The pairing systems, as named when creating a tournament.
+pub const SYSTEMS: [&'static str; 2] = ["round_robin", "swiss"];
+/// The results a game can have, as the points of White and then of Black.
+pub const RESULTS: [&'static str; 3] = ["1-0", "0-1", "1/2-1/2"];
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a registered player, with their rating when the tournament started.
+/// Players are listed by seed once it has.
+pub struct Entrant {
+    pub user: String,
+    pub rating: u32,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a board of a round, or a bye for `white` if `black` is None. Byes
+/// have no game, and never have a result.
+pub struct Pairing {
+    pub white: String,
+    pub black: Option<String>,
+    pub game: Option<u32>,
+    pub result: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcDecodable, RustcEncodable)]
+/// Represents a tournament. `rounds` is how many rounds are played, which for a
+/// round-robin is only known once it starts, and `pairings` holds the boards of
+/// every round paired so far.
+pub struct Tournament {
+    pub id: u32,
+    pub name: String,
+    pub organizer: String,
+    pub system: String,
+    pub rounds: u32,
+    pub initial_ms: u64,
+    pub increment_ms: u64,
+    pub rated: bool,
+    pub players: Vec<Entrant>,
+    pub pairings: Vec<Vec<Pairing>>,
+    pub finished: bool,
+}
+
+#[derive(Debug, Clone)]
+#[derive(RustcEncodable)]
+/// A player's place in the standings of a tournament.
+pub struct Placing {
+    pub rank: usize,
+    pub user: String,
+    pub rating: u32,
+    pub score: f64,
+    pub buchholz: f64,
+    pub sonneborn_berger: f64,
+}
+
+/// Represents one round of a player's tournament: who they played, with which
+/// color, and how many points they scored, if the result is in. A bye has no
+/// opponent.
+struct Round {
+    opponent: Option<String>,
+    color: Color,
+    points: Option<f64>,
+}
+
+/// Every tournament, in the order they were created.
+pub struct Tournaments {
+    path: PathBuf,
+    tournaments: Mutex<Vec<Tournament>>,
+}
+
+/// Helper function to get the points scored by the given color for a result.
+fn points(result: &str, color: &Color) -> f64 {
+    match (result, color) {
+        ("1-0", &Color::White) | ("0-1", &Color::Black) => 1.0,
+        ("1/2-1/2", _) => 0.5,
+        _ => 0.0,
+    }
+}
+
+/// Helper function to pair players in a round-robin by Berger tables. Players are
+/// numbered from 1 to `n`, which must be even, and the pairs are returned as White
+/// and Black, with the board of player `n` first.
+fn berger(n: usize, round: usize) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    for k in 1..n {
+        let mut opponent = (round + 1 + 2 * (n - 1) - k) % (n - 1);
+        if opponent == 0 {
+            opponent = n - 1;
+        }
+        if opponent == k {
+            // Player n plays White in even rounds.
+            pairs.insert(0, if round % 2 == 0 { (n, k) } else { (k, n) });
+        } else if k < opponent {
+            // The lower number plays White when the sum is odd.
+            pairs.push(if (k + opponent) % 2 == 1 { (k, opponent) } else { (opponent, k) });
+        }
+    }
+    pairs
+}
+
+/// Helper function to pair players in rank order without rematches, the Dutch way:
+/// the highest ranked unpaired player plays the player half way down their score
+/// group, or the nearest one after that who they have not played, or failing that
+/// the highest ranked player below their group. Backtracks until everyone is
+/// paired, and returns None if they cannot be, or if `attempts` opponents have been
+/// tried without pairing everyone.
+fn pair_ranked(ranked: &[usize], scores: &[f64], met: &HashSet<(usize, usize)>,
+               attempts: &mut usize) -> Option<Vec<(usize, usize)>> {
+    let (top, rest) = match ranked.split_first() {
+        Some(split) => split,
+        None => return Some(Vec::new()),
+    };
+    let group = rest.iter().take_while(|&&p| scores[p] == scores[*top]).count();
+    let mut order: Vec<usize> = Vec::new();
+    if group > 0 {
+        let start = (group + 1) / 2 - 1;
+        order.extend(start..group);
+        order.extend((0..start).rev());
+    }
+    order.extend(group..rest.len());
+    for i in order {
+        let other = rest[i];
+        if met.contains(&(*top, other)) {
+            continue;
+        }
+        if *attempts == 0 {
+            return None
+        }
+        *attempts -= 1;
+        let remaining: Vec<usize> = rest.iter().enumerate()
+            .filter(|&(j, _)| j != i)
+            .map(|(_, &p)| p)
+            .collect();
+        if let Some(mut pairs) = pair_ranked(&remaining, scores, met, attempts) {
+            pairs.insert(0, (*top, other));
+            return Some(pairs)
+        }
+    }
+    None
+}
+
+impl Tournament {
+    /// Helper function to get a player's rounds so far.
+    fn rounds_of(&self, user: &str) -> Vec<Round> {
+        let mut rounds = Vec::new();
+        for round in &self.pairings {
+            for pairing in round {
+                let color = if pairing.white == user {
+                    Color::White
+                } else if pairing.black.as_ref().map(|b| b.as_str()) == Some(user) {
+                    Color::Black
+                } else {
+                    continue
+                };
+                let opponent = match color {
+                    Color::White => pairing.black.clone(),
+                    Color::Black => Some(pairing.white.clone()),
+                };
+                let points = match pairing.result {
+                    Some(ref result) => Some(points(result, &color)),
+                    None if opponent.is_none() => Some(self.bye_points()),
+                    None => None,
+                };
+                rounds.push(Round{opponent: opponent, color: color, points: points});
+            }
+        }
+        rounds
+    }
+
+    /// Helper function to get the points a bye is worth.
+    fn bye_points(&self) -> f64 {
+        if self.system == "swiss" { 1.0 } else { 0.0 }
+    }
+
+    /// Helper function to get every player's score so far, by seed.
+    fn scores(&self) -> Vec<f64> {
+        self.players.iter()
+            .map(|player| {
+                self.rounds_of(&player.user).iter().filter_map(|r| r.points).fold(0.0, |a, b| a + b)
+            })
+            .collect()
+    }
+
+    /// Get the standings, ordered by score, then Buchholz, then Sonneborn-Berger,
+    /// and then by seed.
+    pub fn standings(&self) -> Vec<Placing> {
+        let scores = self.scores();
+        let score_of: HashMap<&str, f64> = self.players.iter()
+            .map(|player| player.user.as_str())
+            .zip(scores.iter().cloned())
+            .collect();
+        let mut placings: Vec<(usize, Placing)> = self.players.iter().enumerate()
+            .map(|(seed, player)| {
+                let mut buchholz = 0.0;
+                let mut sonneborn_berger = 0.0;
+                for round in self.rounds_of(&player.user) {
+                    let opponent = round.opponent.as_ref()
+                        .and_then(|opponent| score_of.get(opponent.as_str()));
+                    if let (Some(opponent), Some(points)) = (opponent, round.points) {
+                        buchholz += *opponent;
+                        sonneborn_berger += *opponent * points;
+                    }
+                }
+                (seed, Placing {
+                    rank: 0,
+                    user: player.user.clone(),
+                    rating: player.rating,
+                    score: scores[seed],
+                    buchholz: buchholz,
+                    sonneborn_berger: sonneborn_berger,
+                })
+            })
+            .collect();
+        placings.sort_by(|&(a, ref x), &(b, ref y)| {
+            let keys = [(y.score, x.score), (y.buchholz, x.buchholz),
+                        (y.sonneborn_berger, x.sonneborn_berger)];
+            for &(high, low) in keys.iter() {
+                match high.partial_cmp(&low) {
+                    Some(Ordering::Equal) | None => {},
+                    Some(order) => return order,
+                }
+            }
+            a.cmp(&b)
+        });
+        placings.into_iter().enumerate()
+            .map(|(i, (_, placing))| Placing{rank: i + 1, ..placing})
+            .collect()
+    }
+
+    /// Write the crosstable, listing each player's rounds by their opponent's rank,
+    /// their color and their points, such as `4w1` for a win with White against the
+    /// player ranked 4th.
+    pub fn crosstable(&self) -> String {
+        let standings = self.standings();
+        let rank_of: HashMap<&str, usize> = standings.iter()
+            .map(|placing| (placing.user.as_str(), placing.rank))
+            .collect();
+        let mut table = format!("{} ({}, {}+{}, {} rounds)\n\n", self.name, self.system,
+                                self.initial_ms / (60 * 1000), self.increment_ms / 1000,
+                                self.rounds);
+        table.push_str(&format!("{:>3}  {:<20} {:>4}", "#", "Player", "Rtg"));
+        for round in 0..self.pairings.len() {
+            table.push_str(&format!(" {:>5}", format!("Rd{}", round + 1)));
+        }
+        table.push_str(&format!(" {:>5} {:>5} {:>6}\n", "Pts", "Buch", "SB"));
+        for placing in &standings {
+            table.push_str(&format!("{:>3}  {:<20} {:>4}", placing.rank, placing.user,
+                                    placing.rating));
+            for round in self.rounds_of(&placing.user) {
+                let cell = match round.opponent {
+                    None => String::from("bye"),
+                    Some(ref opponent) => {
+                        let color = match round.color {
+                            Color::White => "w",
+                            Color::Black => "b",
+                        };
+                        let points = match round.points {
+                            Some(points) if points == 1.0 => "1",
+                            Some(points) if points == 0.5 => "=",
+                            Some(_) => "0",
+                            None => "*",
+                        };
+                        format!("{}{}{}", rank_of[opponent.as_str()], color, points)
+                    },
+                };
+                table.push_str(&format!(" {:>5}", cell));
+            }
+            table.push_str(&format!(" {:>5.1} {:>5.1} {:>6.2}\n", placing.score,
+                                    placing.buchholz, placing.sonneborn_berger));
+        }
+        table
+    }
+
+    /// Helper function to get a player's color preference from the colors they
+    /// have played: positive for White and negative for Black, stronger the more
+    /// their colors are out of balance, or 0 if they have not played.
+    fn preference(&self, user: &str) -> i32 {
+        let mut balance = 0;
+        let mut last = None;
+        for round in self.rounds_of(user) {
+            if round.opponent.is_none() {
+                continue;
+            }
+            balance += match round.color {
+                Color::White => 1,
+                Color::Black => -1,
+            };
+            last = Some(round.color);
+        }
+        match last {
+            _ if balance != 0 => -2 * balance,
+            Some(Color::White) => -1,
+            Some(Color::Black) => 1,
+            None => 0,
+        }
+    }
+
+    /// Helper function to pair the next Swiss round. Returns the boards as seeds
+    /// of White and Black, and the seed of the player with a bye, if any.
+    fn pair_swiss(&self) -> Option<(Vec<(usize, usize)>, Option<usize>)> {
+        let scores = self.scores();
+        let mut ranked: Vec<usize> = (0..self.players.len()).collect();
+        ranked.sort_by(|&a, &b| {
+            match scores[b].partial_cmp(&scores[a]) {
+                Some(Ordering::Equal) | None => a.cmp(&b),
+                Some(order) => order,
+            }
+        });
+        let mut bye = None;
+        if ranked.len() % 2 == 1 {
+            let had_bye = |p: usize| {
+                self.rounds_of(&self.players[p].user).iter().any(|r| r.opponent.is_none())
+            };
+            let index = ranked.iter().rposition(|&p| !had_bye(p)).unwrap_or(ranked.len() - 1);
+            bye = Some(ranked.remove(index));
+        }
+        let seed: HashMap<&str, usize> = self.players.iter().enumerate()
+            .map(|(i, player)| (player.user.as_str(), i))
+            .collect();
+        let mut met = HashSet::new();
+        for pairing in self.pairings.iter().flat_map(|round| round.iter()) {
+            if let Some(ref black) = pairing.black {
+                let (white, black) = (seed[pairing.white.as_str()], seed[black.as_str()]);
+                met.insert((white, black));
+                met.insert((black, white));
+            }
+        }
+        let mut attempts = PAIRING_ATTEMPTS;
+        let pairs = match pair_ranked(&ranked, &scores, &met, &mut attempts) {
+            Some(pairs) => pairs,
+            None => return None,
+        };
+        let boards: Vec<(usize, usize)> = pairs.into_iter().enumerate().map(|(board, (high, low))| {
+            let high_pref = self.preference(&self.players[high].user);
+            let low_pref = self.preference(&self.players[low].user);
+            let high_white = if high_pref == 0 && low_pref == 0 {
+                board % 2 == 0
+            } else if high_pref.signum() != low_pref.signum() {
+                high_pref > 0 || low_pref < 0
+            } else if high_pref.abs() >= low_pref.abs() {
+                high_pref > 0
+            } else {
+                low_pref < 0
+            };
+            if high_white { (high, low) } else { (low, high) }
+        }).collect();
+        Some((boards, bye))
+    }
+
+    /// Helper function to pair the next round and create its games, with the seats
+    /// reserved for the players.
+    fn pair_round(&mut self, lobby: &Mutex<Lobby>) -> Result<(), &'static str> {
+        let n = self.players.len();
+        let (boards, bye) = if self.system == "swiss" {
+            try!(self.pair_swiss().ok_or("the players cannot be paired without rematches"))
+        } else {
+            // An odd number of players is evened out by an extra player, who stands
+            // for a bye.
+            let even = n + n % 2;
+            let mut boards = Vec::new();
+            let mut bye = None;
+            for (white, black) in berger(even, self.pairings.len() + 1) {
+                if black > n {
+                    bye = Some(white - 1);
+                } else if white > n {
+                    bye = Some(black - 1);
+                } else {
+                    boards.push((white - 1, black - 1));
+                }
+            }
+            (boards, bye)
+        };
+        let mut round = Vec::new();
+        {
+            let mut lobby = lobby.lock().unwrap();
+            for (white, black) in boards {
+                let (white, black) = (&self.players[white].user, &self.players[black].user);
+                let game = lobby.create_reserved(self.initial_ms, self.increment_ms, self.rated,
+                                                 false, white, black);
+                round.push(Pairing{white: white.clone(), black: Some(black.clone()),
+                                   game: Some(game), result: None});
+            }
+        }
+        if let Some(bye) = bye {
+            round.push(Pairing{white: self.players[bye].user.clone(), black: None, game: None,
+                               result: None});
+        }
+        info!("paired round {} of tournament {}", self.pairings.len() + 1, self.id);
+        self.pairings.push(round);
+        Ok(())
+    }
+
+    /// Helper function to get the players of every game of the current round, along
+    /// with the game, to summon them into it.
+    fn summons(&self) -> Vec<(String, u32)> {
+        let mut summons = Vec::new();
+        for pairing in self.pairings.last().into_iter().flat_map(|round| round.iter()) {
+            if let (Some(black), Some(game)) = (pairing.black.as_ref(), pairing.game) {
+                summons.push((pairing.white.clone(), game));
+                summons.push((black.clone(), game));
+            }
+        }
+        summons
+    }
+
+    /// Helper function to check if every game of the current round has a result.
+    fn round_done(&self) -> bool {
+        self.pairings.last().map_or(false, |round| {
+            round.iter().all(|pairing| pairing.black.is_none() || pairing.result.is_some())
+        })
+    }
+
+    /// Helper function to pair the next round once the current one is done, or to
+    /// finish the tournament after the last round. Returns whether anything changed.
+    fn advance(&mut self, lobby: &Mutex<Lobby>) -> bool {
+        if self.finished || !self.round_done() {
+            return false
+        }
+        if self.pairings.len() as u32 >= self.rounds {
+            info!("tournament {} finished", self.id);
+            self.finished = true;
+            return true
+        }
+        if let Err(error) = self.pair_round(lobby) {
+            // Finish early rather than leave the tournament stuck.
+            warn!("could not pair tournament {}: {}", self.id, error);
+            self.rounds = self.pairings.len() as u32;
+            self.finished = true;
+        }
+        true
+    }
+}
+
+/// Spawn the director thread, which collects the results of the games of every
+/// tournament in progress, advances the tournaments whose rounds are done, and
+/// summons the players of each new game into it through the relay.
+pub fn direct(tournaments: Arc<Tournaments>, lobby: Arc<Mutex<Lobby>>, relay: Relay) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(DIRECTOR_SECS));
+            tournaments.collect(&lobby, &relay);
+        }
+    });
+}
+
+/// Helper function to summon players into their games.
+fn summon(relay: &Relay, summons: Vec<(String, u32)>) {
+    for (user, game) in summons {
+        relay.summon(&user, game);
+    }
+}
+
+impl Tournaments {
+    /// Open the tournaments stored in the given directory.
+    pub fn open(dir: &str) -> Tournaments {
+        let path = PathBuf::from(dir).join(FILE_NAME);
+        let mut contents = String::new();
+        let read = File::open(&path).and_then(|mut f| f.read_to_string(&mut contents));
+        let tournaments = match read {
+            Ok(_) => match json::decode(&contents) {
+                Ok(tournaments) => tournaments,
+                Err(e) => {
+                    error!("could not read tournaments from {}: {}", path.display(), e);
+                    process::exit(1);
+                },
+            },
+            Err(_) => Vec::new(),
+        };
+        Tournaments {
+            path: path,
+            tournaments: Mutex::new(tournaments),
+        }
+    }
+
+    /// Get every tournament.
+    pub fn list(&self) -> Vec<Tournament> {
+        self.tournaments.lock().unwrap().clone()
+    }
+
+    /// Get the tournament with the given id.
+    pub fn get(&self, id: u32) -> Option<Tournament> {
+        self.tournaments.lock().unwrap().iter().find(|t| t.id == id).cloned()
+    }
+
+    /// Create a tournament, organized by the given account, for the given number
+    /// of rounds if it is a Swiss.
+    pub fn create(&self, organizer: &str, name: &str, system: &str, initial_ms: u64,
+                  increment_ms: u64, rated: bool, rounds: Option<u32>)
+                  -> Result<Tournament, &'static str> {
+        if name.trim().is_empty() || name.chars().count() > NAME_LEN {
+            return Err("names of tournaments must be 1 to 60 characters long")
+        }
+        if !SYSTEMS.contains(&system) {
+            return Err("the system must be round_robin or swiss")
+        }
+        if initial_ms == 0 && increment_ms == 0 {
+            return Err("the time control must give the players some time")
+        }
+        let rounds = match (system, rounds) {
+            ("swiss", Some(rounds)) if rounds > 0 => rounds,
+            ("swiss", _) => return Err("a swiss needs a number of rounds"),
+            _ => 0,
+        };
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let tournament = Tournament {
+            id: tournaments.last().map_or(1, |t| t.id + 1),
+            name: String::from(name.trim()),
+            organizer: String::from(organizer),
+            system: String::from(system),
+            rounds: rounds,
+            initial_ms: initial_ms,
+            increment_ms: increment_ms,
+            rated: rated,
+            players: Vec::new(),
+            pairings: Vec::new(),
+            finished: false,
+        };
+        tournaments.push(tournament.clone());
+        self.save(&tournaments);
+        info!("{} created tournament {}", organizer, tournament.id);
+        Ok(tournament)
+    }
+
+    /// Register an account for a tournament that has not started.
+    pub fn register(&self, id: u32, user: &str) -> Result<Tournament, &'static str> {
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let tournament = {
+            let tournament = try!(tournaments.iter_mut().find(|t| t.id == id)
+                                  .ok_or("no such tournament"));
+            if !tournament.pairings.is_empty() {
+                return Err("the tournament has started")
+            }
+            if tournament.players.iter().any(|player| player.user == user) {
+                return Err("already registered")
+            }
+            tournament.players.push(Entrant{user: String::from(user), rating: 0});
+            tournament.clone()
+        };
+        self.save(&tournaments);
+        Ok(tournament)
+    }
+
+    /// Start a tournament, seeding the players by their ratings in the category of
+    /// its time control, and pair the first round, summoning its players through
+    /// the relay.
+    pub fn start(&self, id: u32, organizer: &str, ratings: &Ratings, lobby: &Mutex<Lobby>,
+                 relay: &Relay) -> Result<Tournament, &'static str> {
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let tournament = {
+            let tournament = try!(tournaments.iter_mut().find(|t| t.id == id)
+                                  .ok_or("no such tournament"));
+            if tournament.organizer != organizer {
+                return Err("only the organizer can start the tournament")
+            }
+            if !tournament.pairings.is_empty() {
+                return Err("the tournament has started")
+            }
+            let n = tournament.players.len();
+            if n < 2 {
+                return Err("a tournament needs at least two players")
+            }
+            if tournament.system == "swiss" && tournament.rounds as usize >= n + n % 2 {
+                return Err("a swiss needs more players than rounds")
+            }
+            let category = ratings::category(tournament.initial_ms, tournament.increment_ms);
+            for player in tournament.players.iter_mut() {
+                player.rating = ratings.rating(Some(&player.user), category);
+            }
+            // Stable, so players with the same rating are seeded in the order they
+            // registered.
+            tournament.players.sort_by(|a, b| b.rating.cmp(&a.rating));
+            if tournament.system == "round_robin" {
+                tournament.rounds = (n + n % 2 - 1) as u32;
+            }
+            try!(tournament.pair_round(lobby));
+            tournament.clone()
+        };
+        self.save(&tournaments);
+        drop(tournaments);
+        summon(relay, tournament.summons());
+        Ok(tournament)
+    }
+
+    /// Set the result of a board of a round, as the organizer, such as for a game
+    /// that was aborted or a forfeit. Advances the tournament if that was the last
+    /// result of the round, summoning the players of the next round through the
+    /// relay.
+    pub fn set_result(&self, id: u32, organizer: &str, round: usize, board: usize,
+                      result: &str, lobby: &Mutex<Lobby>, relay: &Relay)
+                      -> Result<Tournament, &'static str> {
+        if !RESULTS.contains(&result) {
+            return Err("the result must be 1-0, 0-1 or 1/2-1/2")
+        }
+        let mut summons = Vec::new();
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let tournament = {
+            let tournament = try!(tournaments.iter_mut().find(|t| t.id == id)
+                                  .ok_or("no such tournament"));
+            if tournament.organizer != organizer {
+                return Err("only the organizer can set results")
+            }
+            {
+                let pairing = try!(tournament.pairings.get_mut(round.wrapping_sub(1))
+                                   .and_then(|round| round.get_mut(board.wrapping_sub(1)))
+                                   .ok_or("no such board"));
+                if pairing.black.is_none() {
+                    return Err("a bye has no result")
+                }
+                pairing.result = Some(String::from(result));
+            }
+            let rounds = tournament.pairings.len();
+            tournament.advance(lobby);
+            if tournament.pairings.len() > rounds {
+                summons = tournament.summons();
+            }
+            tournament.clone()
+        };
+        self.save(&tournaments);
+        drop(tournaments);
+        summon(relay, summons);
+        Ok(tournament)
+    }
+
+    /// Collect the results of the finished games of every tournament in progress,
+    /// advance the tournaments whose rounds are done, and summon the players of each
+    /// new game through the relay.
+    pub fn collect(&self, lobby: &Mutex<Lobby>, relay: &Relay) {
+        // The games are asked for their outcome before the tournaments are locked,
+        // so that a busy game never holds up the tournaments.
+        let games: Vec<u32> = self.tournaments.lock().unwrap().iter()
+            .filter(|t| !t.finished)
+            .filter_map(|t| t.pairings.last())
+            .flat_map(|round| round.iter())
+            .filter(|p| p.result.is_none())
+            .filter_map(|p| p.game)
+            .collect();
+        let mut outcomes = HashMap::new();
+        for game in games {
+            let room = lobby.lock().unwrap().get(game);
+            if let Some(outcome) = room.and_then(|room| room.inspect()).and_then(|g| g.outcome) {
+                outcomes.insert(game, outcome);
+            }
+        }
+
+        let mut summons = Vec::new();
+        let mut tournaments = self.tournaments.lock().unwrap();
+        let mut changed = false;
+        for tournament in tournaments.iter_mut().filter(|t| !t.finished) {
+            if let Some(round) = tournament.pairings.last_mut() {
+                for pairing in round.iter_mut().filter(|p| p.result.is_none()) {
+                    let result = match pairing.game.and_then(|game| outcomes.get(&game)) {
+                        Some(outcome) if outcome.reason == "aborted" => continue,
+                        Some(outcome) => match outcome.winner {
+                            Some(Color::White) => "1-0",
+                            Some(Color::Black) => "0-1",
+                            None => "1/2-1/2",
+                        },
+                        None => continue,
+                    };
+                    pairing.result = Some(String::from(result));
+                    changed = true;
+                }
+            }
+            let rounds = tournament.pairings.len();
+            changed = tournament.advance(lobby) || changed;
+            if tournament.pairings.len() > rounds {
+                summons.extend(tournament.summons());
+            }
+        }
+        if changed {
+            self.save(&tournaments);
+        }
+        drop(tournaments);
+        summon(relay, summons);
+    }
+
+    /// Helper function to write every tournament to disk.
+    fn save(&self, tournaments: &[Tournament]) {
+        if let Err(e) = storage::replace(&self.path, &json::encode(&tournaments).unwrap()) {
+            error!("could not save tournaments to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+
+    /// Helper function to get a Swiss between the given number of players, seeded
+    /// in order, that has not started.
+    fn swiss(players: usize, rounds: u32) -> Tournament {
+        Tournament {
+            id: 1,
+            name: String::from("test"),
+            organizer: String::from("p0"),
+            system: String::from("swiss"),
+            rounds: rounds,
+            initial_ms: 60000,
+            increment_ms: 0,
+            rated: false,
+            players: (0..players).map(|i| Entrant{user: format!("p{}", i), rating: 0}).collect(),
+            pairings: Vec::new(),
+            finished: false,
+        }
+    }
+
+    /// Helper function to pair the next Swiss round without creating its games, and
+    /// have White win every game.
+    fn play_swiss_round(tournament: &mut Tournament) {
+        let (boards, bye) = tournament.pair_swiss().unwrap();
+        let mut round: Vec<Pairing> = boards.into_iter().map(|(white, black)| Pairing {
+            white: tournament.players[white].user.clone(),
+            black: Some(tournament.players[black].user.clone()),
+            game: None,
+            result: Some(String::from("1-0")),
+        }).collect();
+        if let Some(bye) = bye {
+            round.push(Pairing{white: tournament.players[bye].user.clone(), black: None,
+                               game: None, result: None});
+        }
+        tournament.pairings.push(round);
+    }
+
+    #[test]
+    fn berger_tables_pair_everyone_once_with_balanced_colors() {
+        for &n in &[2, 4, 6, 8, 10, 12] {
+            let mut met = HashSet::new();
+            let mut whites = vec![0; n + 1];
+            for round in 1..n {
+                let pairs = berger(n, round);
+                assert_eq!(pairs.len(), n / 2);
+                let mut seen = HashSet::new();
+                for &(white, black) in &pairs {
+                    assert!(seen.insert(white) && seen.insert(black), "n {} round {}", n, round);
+                    assert!(met.insert((white.min(black), white.max(black))));
+                    whites[white] += 1;
+                }
+            }
+            assert_eq!(met.len(), n * (n - 1) / 2);
+            for player in 1..n + 1 {
+                let blacks = n - 1 - whites[player];
+                assert!((whites[player] as i32 - blacks as i32).abs() <= 1,
+                        "n {} player {} has {} whites", n, player, whites[player]);
+            }
+        }
+    }
+
+    #[test]
+    fn swiss_never_pairs_a_rematch() {
+        for &(players, rounds) in &[(8, 5), (7, 5), (10, 7)] {
+            let mut tournament = swiss(players, rounds);
+            for _ in 0..rounds {
+                play_swiss_round(&mut tournament);
+            }
+            let mut met = HashSet::new();
+            let mut byes = HashMap::new();
+            for pairing in tournament.pairings.iter().flat_map(|round| round.iter()) {
+                match pairing.black {
+                    Some(ref black) => {
+                        let pair = (pairing.white.clone().min(black.clone()),
+                                    pairing.white.clone().max(black.clone()));
+                        assert!(met.insert(pair), "rematch in {:?}", tournament.pairings);
+                    },
+                    None => *byes.entry(pairing.white.clone()).or_insert(0) += 1,
+                }
+            }
+            assert!(byes.values().all(|&count| count == 1), "{:?}", byes);
+        }
+    }
+
+    #[test]
+    fn gives_up_pairing_within_the_attempts() {
+        // The lowest ranked player has met everyone, so no pairing exists, and only
+        // the bound on attempts keeps the search from taking forever.
+        let n = 40;
+        let ranked: Vec<usize> = (0..n).collect();
+        let scores = vec![0.0; n];
+        let mut met = HashSet::new();
+        for p in 0..n - 1 {
+            met.insert((p, n - 1));
+            met.insert((n - 1, p));
+        }
+        let mut attempts = PAIRING_ATTEMPTS;
+        assert!(pair_ranked(&ranked, &scores, &met, &mut attempts).is_none());
+        assert_eq!(attempts, 0);
+    }
+
+    #[test]
+    fn summons_the_players_of_the_current_round() {
+        let mut tournament = swiss(3, 1);
+        tournament.pairings.push(vec![
+            Pairing{white: String::from("p0"), black: Some(String::from("p1")), game: Some(4),
+                    result: None},
+            Pairing{white: String::from("p2"), black: None, game: None, result: None},
+        ]);
+        assert_eq!(tournament.summons(),
+                   vec![(String::from("p0"), 4), (String::from("p1"), 4)]);
+    }
+}
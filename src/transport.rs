@@ -0,0 +1,263 @@
+//! A module for the transports that carry messages between the server and its
+//! clients. A client connects over a WebSocket, or over a plain TCP connection with
+//! one message per line, and the event loop in `front` reads and writes their
+//! connection without ever waiting on it. This module answers the WebSocket
+//! handshake and splits what a client sends into messages with a `Framing`, so the
+//! event loop itself never looks inside a message.
+//!
+//! The event loop is told that messages have been queued for a client through a
+//! `Waker`, and asked to disconnect them through a `Closer`, both of which may be
+//! called from any thread.
+//!
+//! Tests drive clients in memory instead, over a pair of MPSC channels, through the
+//! blocking `Incoming` and `Outgoing` halves that `Relay::serve` serves.
+
+#[cfg(test)]
+use std::sync::mpsc;
+use std::sync::Arc;
+#[cfg(test)]
+use std::time::Duration;
+
+use super::accounts::Accounts;
+use super::bans::Bans;
+use super::protocol::{self, SUBPROTOCOL_V1, SUBPROTOCOL_V2, Version};
+use super::ws::{self, Frame, Head, MAX_FRAME_LEN};
+
+/// A handle that closes a connection from any thread, so that reading from it
+/// stops as well.
+pub type Closer = Arc<Fn() + Send + Sync>;
+
+/// A handle that tells the event loop, from any thread, that messages have been
+/// queued for a connection.
+pub type Waker = Arc<Fn() + Send + Sync>;
+
+/// How a client's messages are framed on their connection: as WebSocket frames, or
+/// one per line.
+pub enum Framing {
+    WebSocket(ws::Decoder),
+    Lines,
+}
+
+impl Framing {
+    /// Take the next message off the start of `buf`. Returns None until a whole
+    /// message has arrived, and why the client has broken the protocol otherwise.
+    /// Blank lines are skipped.
+    pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<Frame>, &'static str> {
+        match *self {
+            Framing::WebSocket(ref mut decoder) => decoder.decode(buf),
+            Framing::Lines => loop {
+                let end = match buf.iter().position(|&byte| byte == b'\n') {
+                    Some(end) => end,
+                    None if buf.len() > MAX_FRAME_LEN => return Err("line too long"),
+                    None => return Ok(None),
+                };
+                let line: Vec<u8> = buf.drain(..end + 1).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+                if !line.is_empty() {
+                    return Ok(Some(Frame::Text(String::from(line))))
+                }
+            },
+        }
+    }
+
+    /// Encode a message to the client.
+    pub fn text(&self, text: &str) -> Vec<u8> {
+        match *self {
+            Framing::WebSocket(_) => ws::text(text),
+            Framing::Lines => format!("{}\n", text).into_bytes(),
+        }
+    }
+
+    /// Encode a heartbeat that the client answers to show that they are still
+    /// there.
+    pub fn ping(&self) -> Vec<u8> {
+        match *self {
+            Framing::WebSocket(_) => ws::ping(),
+            Framing::Lines => self.text(&protocol::ping()),
+        }
+    }
+
+    /// Encode what is sent last before the connection is closed, if anything.
+    pub fn close(&self) -> Vec<u8> {
+        match *self {
+            Framing::WebSocket(_) => ws::close(),
+            Framing::Lines => Vec::new(),
+        }
+    }
+}
+
+/// The prefix of the WebSocket subprotocol that clients offer to give their session
+/// token, which keeps the token out of the URL, where it would end up in logs.
+const SESSION_PREFIX: &'static str = "session.";
+
+/// Answer the WebSocket handshake in the head of a request from the given address.
+/// Clients that offer the `chess.v2` subprotocol speak version 2 of the protocol,
+/// and all others version 1. Clients that offer a `session.<token>` subprotocol
+/// play as the account the session belongs to, and are turned away if the session
+/// is not open. Clients whose address or account is banned are turned away before
+/// the request is accepted.
+/// Returns the client's version, their account if any, and the response accepting
+/// them, or the response refusing them, after which the connection is closed.
+pub fn accept_websocket(head: &Head, addr: &str, accounts: &Accounts, bans: &Bans)
+                        -> Result<(Version, Option<String>, Vec<u8>), Vec<u8>> {
+    let key = match head.handshake_key() {
+        Some(key) => key,
+        None => {
+            debug!("refused a bad handshake from {}", addr);
+            return Err(ws::refuse("400 Bad Request"))
+        },
+    };
+
+    let offered: Vec<&str> = head.header("Sec-WebSocket-Protocol").map_or(Vec::new(), |protocols| {
+        protocols.split(',').map(|protocol| protocol.trim()).collect()
+    });
+    let token = offered.iter()
+        .find(|protocol| protocol.starts_with(SESSION_PREFIX))
+        .map(|protocol| &protocol[SESSION_PREFIX.len()..]);
+    let user = match token {
+        Some(token) => match accounts.user(token) {
+            Some(user) => Some(user),
+            None => return Err(ws::refuse("403 Forbidden")),
+        },
+        None => None,
+    };
+
+    if bans.is_banned(addr, user.as_ref().map(|user| user.as_str())) {
+        info!("turned away {}, who is banned", addr);
+        return Err(ws::refuse("403 Forbidden"))
+    }
+
+    // The session token is never echoed back: the version is chosen instead.
+    let protocol = if offered.contains(&SUBPROTOCOL_V2) {
+        Some(SUBPROTOCOL_V2)
+    } else if offered.contains(&SUBPROTOCOL_V1) {
+        Some(SUBPROTOCOL_V1)
+    } else {
+        None
+    };
+    let version = if protocol == Some(SUBPROTOCOL_V2) { Version::V2 } else { Version::V1 };
+    Ok((version, user, ws::accept(key, protocol)))
+}
+
+#[cfg(test)]
+/// The receiving half of a blocking connection to a client.
+pub trait Incoming: Send {
+    /// Wait for the next message from the client. Returns None once the client
+    /// has disconnected, or has sent nothing for the idle timeout.
+    fn recv(&mut self) -> Option<String>;
+
+    /// Treat the client as disconnected once they have sent nothing for the given
+    /// time. Does nothing by default, for transports that cannot be half-open.
+    fn set_idle_timeout(&mut self, _timeout: Duration) {}
+}
+
+#[cfg(test)]
+/// The sending half of a blocking connection to a client.
+pub trait Outgoing: Send {
+    /// Send a message to the client. Returns false once the client can no longer
+    /// be sent to.
+    fn send(&mut self, text: &str) -> bool;
+
+    /// Send a heartbeat that the client answers to show that they are still
+    /// there. Returns false once the client can no longer be sent to.
+    fn ping(&mut self) -> bool;
+
+    /// Get a handle that closes the connection.
+    fn closer(&self) -> Closer;
+}
+
+#[cfg(test)]
+/// The messages an in-memory client sends to the server are read from a channel.
+impl Incoming for mpsc::Receiver<String> {
+    fn recv(&mut self) -> Option<String> {
+        mpsc::Receiver::recv(self).ok()
+    }
+}
+
+#[cfg(test)]
+/// The messages the server sends to an in-memory client are written to a channel.
+/// Closing it does nothing: the client disconnects by dropping its own sender.
+impl Outgoing for mpsc::Sender<String> {
+    fn send(&mut self, text: &str) -> bool {
+        mpsc::Sender::send(self, String::from(text)).is_ok()
+    }
+
+    fn ping(&mut self) -> bool {
+        true
+    }
+
+    fn closer(&self) -> Closer {
+        Arc::new(|| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use accounts::Accounts;
+    use bans::Bans;
+    use storage::tests::scratch_dir;
+    use ws::Decoder;
+
+    #[test]
+    fn splits_lines_and_skips_blank_ones() {
+        let mut framing = Framing::Lines;
+        let mut buf = b"move e2 e4\r\n\n  \nsay hi\nsay".to_vec();
+        assert_eq!(framing.decode(&mut buf), Ok(Some(Frame::Text(String::from("move e2 e4")))));
+        assert_eq!(framing.decode(&mut buf), Ok(Some(Frame::Text(String::from("say hi")))));
+        assert_eq!(framing.decode(&mut buf), Ok(None));
+        assert_eq!(buf, b"say".to_vec());
+        let mut long = vec![b'a'; MAX_FRAME_LEN + 1];
+        assert_eq!(framing.decode(&mut long), Err("line too long"));
+        assert_eq!(framing.text("hi"), b"hi\n".to_vec());
+        assert!(!Framing::WebSocket(Decoder::new()).close().is_empty());
+    }
+
+    /// Helper function to build the head of a handshake for the given path, with
+    /// the given extra headers.
+    fn handshake(path: &str, extra: &str) -> Head {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+                               Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                               Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+                              path, extra);
+        Head::parse(request.as_bytes()).unwrap()
+    }
+
+    /// Helper function to get the status line of a response.
+    fn status(response: &[u8]) -> String {
+        String::from_utf8_lossy(response).lines().next().unwrap_or("").to_string()
+    }
+
+    #[test]
+    fn answers_handshakes_and_refuses_bad_ones() {
+        let dir = scratch_dir("answers_handshakes_and_refuses_bad_ones");
+        let (accounts, bans) = (Accounts::open(&dir), Bans::open(&dir));
+        accounts.register("alice", "correct horse").unwrap();
+        let (_, token) = accounts.login("alice", "correct horse").unwrap();
+        bans.ban(Some("203.0.113.7"), None).unwrap();
+        let addr = "198.51.100.1:4000";
+
+        let head = handshake("/ws", "Sec-WebSocket-Protocol: chat, chess.v2\r\n");
+        let (version, user, response) = accept_websocket(&head, addr, &accounts, &bans).unwrap();
+        assert_eq!((version, user), (Version::V2, None));
+        assert_eq!(status(&response), "HTTP/1.1 101 Switching Protocols");
+
+        let offer = format!("Sec-WebSocket-Protocol: chess.v1, session.{}\r\n", token);
+        let head = handshake("/ws", &offer);
+        let (version, user, response) = accept_websocket(&head, addr, &accounts, &bans).unwrap();
+        assert_eq!((version, user), (Version::V1, Some(String::from("alice"))));
+        let response = String::from_utf8_lossy(&response).into_owned();
+        assert!(response.contains("Sec-WebSocket-Protocol: chess.v1\r\n"), "{}", response);
+        assert!(!response.contains(&token), "{}", response);
+
+        let refusal = |head: &Head, addr: &str| {
+            status(&accept_websocket(head, addr, &accounts, &bans).err().unwrap())
+        };
+        let plain = Head::parse(b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(refusal(&plain, addr), "HTTP/1.1 400 Bad Request");
+        let unknown = handshake("/ws", "Sec-WebSocket-Protocol: chess.v1, session.nope\r\n");
+        assert_eq!(refusal(&unknown, addr), "HTTP/1.1 403 Forbidden");
+        assert_eq!(refusal(&handshake("/ws", ""), "203.0.113.7:5000"), "HTTP/1.1 403 Forbidden");
+    }
+}
@@ -0,0 +1,345 @@
+//! A module for the WebSocket protocol (RFC 6455), spoken over connections that
+//! the event loop in `front` owns, so it works on bytes rather than on sockets: it
+//! parses the head of the HTTP request a connection starts with, answers the
+//! opening handshake, and decodes and encodes frames. Only what the server needs
+//! is implemented: frames from clients must be masked, extensions are refused,
+//! and fragmented messages are joined back together.
+
+use openssl::crypto::hash::{self, Type};
+use rustc_serialize::base64::{FromBase64, STANDARD, ToBase64};
+
+/// The GUID that a client's key is hashed with to accept the handshake.
+const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// The longest message a client may send, in bytes, over all its fragments. The
+/// connection is closed if a client sends more.
+pub const MAX_FRAME_LEN: usize = 65536;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// The head of an HTTP request: its method, its path with the query string, and
+/// its headers in order. `len` is how many bytes it took, including the blank line
+/// that ends it.
+pub struct Head {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub len: usize,
+}
+
+/// Represents a message from a client, once its frames have been joined. Binary
+/// messages are read as text, like text messages.
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Ping(Vec<u8>),
+    Pong,
+    Close,
+}
+
+/// Decodes the frames a client sends, joining the fragments of a message.
+pub struct Decoder {
+    message: Vec<u8>,
+    fragmented: bool,
+}
+
+impl Head {
+    /// Parse the head at the start of `buf`. Returns None until the blank line
+    /// that ends it has arrived, or if it is not a request.
+    pub fn parse(buf: &[u8]) -> Option<Head> {
+        let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end,
+            None => return None,
+        };
+        let text = String::from_utf8_lossy(&buf[..end]);
+        let mut lines = text.split("\r\n");
+        let mut request = lines.next().unwrap_or("").split_whitespace();
+        let (method, path) = match (request.next(), request.next(), request.next()) {
+            (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/") => {
+                (String::from(method), String::from(path))
+            },
+            _ => return None,
+        };
+        let headers = lines.filter_map(|line| {
+            let mut header = line.splitn(2, ':');
+            match (header.next(), header.next()) {
+                (Some(name), Some(value)) => {
+                    Some((String::from(name.trim()), String::from(value.trim())))
+                },
+                _ => None,
+            }
+        }).collect();
+        Some(Head{method: method, path: path, headers: headers, len: end + 4})
+    }
+
+    /// Get the value of a header, ignoring the case of its name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers.iter()
+            .find(|&&(ref other, _)| other.to_lowercase() == name)
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    /// Check if a header holds the given token in its comma-separated list,
+    /// ignoring case, as in "Connection: keep-alive, Upgrade".
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name).map_or(false, |value| {
+            value.split(',').any(|other| other.trim().to_lowercase() == token.to_lowercase())
+        })
+    }
+
+    /// Get the path without its query string.
+    pub fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or("")
+    }
+
+    /// Check if the request opens a WebSocket handshake that this module can
+    /// answer. Returns the client's key if so.
+    pub fn handshake_key(&self) -> Option<&str> {
+        let key = match self.header("Sec-WebSocket-Key") {
+            Some(key) => key,
+            None => return None,
+        };
+        let valid = self.method == "GET" && self.has_token("Upgrade", "websocket") &&
+            self.has_token("Connection", "upgrade") &&
+            self.header("Sec-WebSocket-Version") == Some("13") &&
+            key.from_base64().map(|nonce| nonce.len() == 16).unwrap_or(false);
+        if valid { Some(key) } else { None }
+    }
+}
+
+/// Get the `Sec-WebSocket-Accept` value that accepts a client's key.
+pub fn accept_key(key: &str) -> String {
+    hash::hash(Type::SHA1, format!("{}{}", key, GUID).as_bytes()).to_base64(STANDARD)
+}
+
+/// Build the response that accepts a handshake, in the given subprotocol if any.
+pub fn accept(key: &str, protocol: Option<&str>) -> Vec<u8> {
+    let protocol = protocol.map_or(String::new(), |protocol| {
+        format!("Sec-WebSocket-Protocol: {}\r\n", protocol)
+    });
+    format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+            accept_key(key), protocol).into_bytes()
+}
+
+/// Build a response that refuses a request with the given status, such as
+/// "400 Bad Request", after which the connection is closed.
+pub fn refuse(status: &str) -> Vec<u8> {
+    format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status)
+        .into_bytes()
+}
+
+/// Helper function to encode a single, final frame from the server, which is
+/// never masked.
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len <= 0xFFFF {
+        frame.push(126);
+        frame.push((len >> 8) as u8);
+        frame.push(len as u8);
+    } else {
+        frame.push(127);
+        for shift in (0..8).rev() {
+            frame.push((len as u64 >> (shift * 8)) as u8);
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Encode a text message.
+pub fn text(text: &str) -> Vec<u8> {
+    frame(OP_TEXT, text.as_bytes())
+}
+
+/// Encode a ping, which the client answers with a pong.
+pub fn ping() -> Vec<u8> {
+    frame(OP_PING, &[])
+}
+
+/// Encode the answer to a client's ping.
+pub fn pong(payload: &[u8]) -> Vec<u8> {
+    frame(OP_PONG, payload)
+}
+
+/// Encode a close frame, after which nothing more is sent.
+pub fn close() -> Vec<u8> {
+    frame(OP_CLOSE, &[])
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder{message: Vec::new(), fragmented: false}
+    }
+
+    /// Take the next complete frame off the start of `buf`. Returns None until the
+    /// whole frame has arrived, or if it is a fragment that does not finish its
+    /// message yet, and why the client has broken the protocol otherwise.
+    pub fn decode(&mut self, buf: &mut Vec<u8>) -> Result<Option<Frame>, &'static str> {
+        loop {
+            let (fin, opcode, payload) = match try!(self.split(buf)) {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            let control = opcode & 0x8 != 0;
+            if control && (!fin || payload.len() > 125) {
+                return Err("control frames must be short and whole")
+            }
+            match opcode {
+                OP_CLOSE => return Ok(Some(Frame::Close)),
+                OP_PING => return Ok(Some(Frame::Ping(payload))),
+                OP_PONG => return Ok(Some(Frame::Pong)),
+                OP_TEXT | OP_BINARY if self.fragmented => {
+                    return Err("a new message started before the last one finished")
+                },
+                OP_CONTINUATION if !self.fragmented => {
+                    return Err("a continuation frame without a message")
+                },
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                    if self.message.len() + payload.len() > MAX_FRAME_LEN {
+                        return Err("message too long")
+                    }
+                    self.message.extend_from_slice(&payload);
+                    self.fragmented = !fin;
+                    if fin {
+                        let message = String::from_utf8_lossy(&self.message).into_owned();
+                        self.message.clear();
+                        return Ok(Some(Frame::Text(message)))
+                    }
+                },
+                _ => return Err("unknown opcode"),
+            }
+        }
+    }
+
+    /// Helper function to take a whole frame off the start of `buf`, unmasked.
+    /// Returns whether it is final, its opcode and its payload.
+    fn split(&self, buf: &mut Vec<u8>) -> Result<Option<(bool, u8, Vec<u8>)>, &'static str> {
+        if buf.len() < 2 {
+            return Ok(None)
+        }
+        if buf[0] & 0x70 != 0 {
+            return Err("no extensions were agreed on")
+        }
+        if buf[1] & 0x80 == 0 {
+            return Err("frames from clients must be masked")
+        }
+        let (len, start) = match buf[1] & 0x7F {
+            126 if buf.len() >= 4 => ((buf[2] as u64) << 8 | buf[3] as u64, 4),
+            127 if buf.len() >= 10 => {
+                (buf[2..10].iter().fold(0, |len, &byte| len << 8 | byte as u64), 10)
+            },
+            126 | 127 => return Ok(None),
+            len => (len as u64, 2),
+        };
+        if len > MAX_FRAME_LEN as u64 {
+            return Err("message too long")
+        }
+        let len = len as usize;
+        if buf.len() < start + 4 + len {
+            return Ok(None)
+        }
+        let mask = [buf[start], buf[start + 1], buf[start + 2], buf[start + 3]];
+        let payload = buf[start + 4..start + 4 + len].iter().enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        let (fin, opcode) = (buf[0] & 0x80 != 0, buf[0] & 0x0F);
+        buf.drain(..start + 4 + len);
+        Ok(Some((fin, opcode, payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Helper function to encode a frame as a client would, masked.
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8);
+        } else {
+            frame.push(0x80 | 126);
+            frame.push((payload.len() >> 8) as u8);
+            frame.push(payload.len() as u8);
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accepts_the_key_from_the_rfc() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn parses_a_handshake_once_it_has_arrived() {
+        let request = b"GET /ws?game=3 HTTP/1.1\r\nHost: localhost\r\n\
+                        upgrade: WebSocket\r\nConnection: keep-alive, Upgrade\r\n\
+                        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                        Sec-WebSocket-Version: 13\r\n\r\nextra";
+        assert!(Head::parse(&request[..40]).is_none());
+        let head = Head::parse(request).unwrap();
+        assert_eq!(head.len, request.len() - 5);
+        assert_eq!(head.route(), "/ws");
+        assert_eq!(head.header("UPGRADE"), Some("WebSocket"));
+        assert_eq!(head.handshake_key(), Some("dGhlIHNhbXBsZSBub25jZQ=="));
+
+        let plain = Head::parse(b"GET /ws HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(plain.handshake_key(), None);
+        assert!(Head::parse(b"not a request\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn decodes_masked_and_fragmented_messages() {
+        let mut decoder = Decoder::new();
+        let mut buf = masked(0x81, b"Hello");
+        let whole = buf.len();
+        buf.extend(masked(0x01, b"Hel"));
+        buf.extend(masked(0x89, b"?"));
+        buf.extend(masked(0x80, b"lo"));
+        let mut partial: Vec<u8> = buf[..whole - 1].to_vec();
+        assert_eq!(decoder.decode(&mut partial), Ok(None));
+
+        assert_eq!(decoder.decode(&mut buf), Ok(Some(Frame::Text(String::from("Hello")))));
+        // A ping may come in between the fragments of a message.
+        assert_eq!(decoder.decode(&mut buf), Ok(Some(Frame::Ping(b"?".to_vec()))));
+        assert_eq!(decoder.decode(&mut buf), Ok(Some(Frame::Text(String::from("Hello")))));
+        assert!(buf.is_empty());
+
+        let long = vec![b'a'; 300];
+        let mut buf = masked(0x81, &long);
+        buf.extend(masked(0x88, b""));
+        assert_eq!(decoder.decode(&mut buf),
+                   Ok(Some(Frame::Text(String::from_utf8(long).unwrap()))));
+        assert_eq!(decoder.decode(&mut buf), Ok(Some(Frame::Close)));
+    }
+
+    #[test]
+    fn refuses_frames_that_break_the_protocol() {
+        let mut unmasked = text("hi");
+        assert!(Decoder::new().decode(&mut unmasked).is_err());
+        let mut continuation = masked(0x80, b"lo");
+        assert!(Decoder::new().decode(&mut continuation).is_err());
+        let mut too_long = vec![0x81, 0x80 | 127, 0, 0, 0, 0, 0x10, 0, 0, 0];
+        assert_eq!(Decoder::new().decode(&mut too_long), Err("message too long"));
+    }
+
+    #[test]
+    fn encodes_frames_of_every_length() {
+        assert_eq!(text("hi"), vec![0x81, 2, b'h', b'i']);
+        assert_eq!(&text(&"a".repeat(300))[..4], &[0x81, 126, 1, 44]);
+        assert_eq!(&text(&"a".repeat(70000))[..10], &[0x81, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]);
+        assert_eq!(ping(), vec![0x89, 0]);
+    }
+}